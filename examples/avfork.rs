@@ -17,28 +17,30 @@ fn dummy_avfork_callback(_fd: Fd, _old_sigset: &mut sigset_t) -> c_int {
 
 fn main() {
     let mut stack = Stack::new();
+    let mut allocator = stack.reserve(0, 100).unwrap();
 
     for _ in 0..10 {
-        let allocator = stack.reserve(0, 100).unwrap();
+        // The children share the same stack, so each of them must have
+        // called execve or exited before the next one is created.
+        let result = allocator.alloc_callback_scoped(dummy_avfork_callback, |allocator, f| {
+            println!("Calling avfork");
 
-        let f = match allocator.alloc_obj(dummy_avfork_callback) {
-            Ok(f) => f,
-            Err(_) => panic!("allocation failed"),
-        };
+            let (fd, _pid) = avfork(allocator, f).unwrap();
 
-        println!("Calling avfork");
+            println!("avfork is done");
 
-        let (fd, _pid) = avfork(&allocator, f.pin()).unwrap();
+            println!("Wait for child process to exit or exec");
 
-        println!("avfork is done");
+            let mut buf = [1 as u8; 1];
+            match fd.read(&mut buf) {
+                Ok(cnt) => assert_eq!(0, cnt),
+                Err(_) => panic!("There shouldn't be any error")
+            };
+        });
 
-        println!("Wait for child process to exit or exec");
-
-        let mut buf = [1 as u8; 1];
-        match fd.read(&mut buf) {
-            Ok(cnt) => assert_eq!(0, cnt),
-            Err(_) => panic!("There shouldn't be any error")
-        };
+        if result.is_err() {
+            panic!("allocation failed");
+        }
 
         println!("Test completed");
     }
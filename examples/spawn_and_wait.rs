@@ -0,0 +1,15 @@
+use avfork::process::{Command, Stdio};
+use avfork::cstr::cstr;
+
+fn main() {
+    let mut command = Command::new(cstr!("ls"));
+    command
+        .arg(cstr!("-l"))
+        .current_dir(cstr!("/tmp"))
+        .stdin(Stdio::Null);
+
+    match command.spawn_and_wait() {
+        Ok(info) => println!("ls terminated: {:?}", info.status()),
+        Err(err) => eprintln!("Failed to run ls: {}", err),
+    }
+}
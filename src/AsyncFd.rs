@@ -0,0 +1,72 @@
+use std::io::Result;
+
+use tokio::io::unix::{AsyncFd, AsyncFdReadyGuard};
+use tokio::io::Interest;
+
+use crate::error::{SyscallError, to_syscall_error};
+use crate::syscall::{Fd, FdBox};
+
+impl FdBox {
+    /// Set `O_NONBLOCK` on the fd and wrap it in `AsyncFdBox`, e.g. the parent
+    /// end of a pipe obtained via `ChildStdout::into_fd`.
+    ///
+    /// Since `O_NONBLOCK` is set on the open file description, it also affects
+    /// the duplicates of the fd.
+    ///
+    /// Must be called within the context of a tokio runtime.
+    pub fn into_async(self, interest: Interest) -> std::result::Result<AsyncFdBox, SyscallError> {
+        self.set_nonblocking(true)?;
+        AsyncFdBox::new(self, interest).map_err(to_syscall_error)
+    }
+}
+
+/// Wrapper of tokio's `AsyncFd` for `FdBox`, which can be used to drive
+/// non-blocking fds, e.g. pipes connected to the child, asynchronously.
+pub struct AsyncFdBox(AsyncFd<FdBox>);
+impl AsyncFdBox {
+    /// * `fd` - must be non-blocking
+    ///
+    /// Must be called within the context of a tokio runtime.
+    pub fn new(fd: FdBox, interest: Interest) -> Result<AsyncFdBox> {
+        Ok(AsyncFdBox(AsyncFd::with_interest(fd, interest)?))
+    }
+
+    pub fn get_ref(&self) -> &FdBox {
+        self.0.get_ref()
+    }
+
+    pub fn into_inner(self) -> FdBox {
+        self.0.into_inner()
+    }
+
+    /// Wait for the fd to become readable.
+    pub async fn readable(&self) -> Result<AsyncFdReadyGuard<'_, FdBox>> {
+        self.0.readable().await
+    }
+
+    /// Wait for the fd to become writable.
+    pub async fn writable(&self) -> Result<AsyncFdReadyGuard<'_, FdBox>> {
+        self.0.writable().await
+    }
+
+    /// Wait for the fd to become ready for `interest`, then call `f`.
+    ///
+    /// If `f` fails with `EAGAIN`, then the readiness is cleared and it waits
+    /// for the fd to become ready again.
+    pub async fn try_io<R, F>(&self, interest: Interest, mut f: F) -> Result<R>
+        where F: FnMut(&Fd) -> std::result::Result<R, SyscallError>
+    {
+        loop {
+            let mut guard = if interest.is_readable() {
+                self.readable().await?
+            } else {
+                self.writable().await?
+            };
+
+            match guard.try_io(|inner| Ok(f(inner.get_ref())?)) {
+                Ok(result) => break result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
@@ -2,26 +2,38 @@
 ///  - Move this code into another independent crate
 use std::io::{Result, Error};
 use std::os::raw::c_int;
-use std::mem::{size_of, size_of_val, MaybeUninit};
-use std::sync::Arc;
+use std::mem::{size_of, MaybeUninit};
+#[cfg(feature = "async")]
+use std::mem::size_of_val;
+#[cfg(feature = "async")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::collections::VecDeque;
+use std::time::Duration;
 
 use libc::{signalfd, signalfd_siginfo, SFD_CLOEXEC, SFD_NONBLOCK, SIGCHLD};
 use libc::{sigset_t, SIG_BLOCK, sigemptyset, sigaddset, sigprocmask};
 
 use libc::pid_t;
 
-use tokio::io::unix::AsyncFd;
+#[cfg(feature = "async")]
 use tokio::io::Interest;
+#[cfg(feature = "async")]
 use tokio::task::JoinHandle;
+#[cfg(feature = "async")]
+use tokio::sync::watch;
 
+#[cfg(feature = "async")]
 use waitmap::WaitMap;
 
 use crate::autorestart;
-use crate::syscall::{FdBox, FromRaw};
+use crate::syscall::{Fd, FdBox, FromRaw, EpollReactor, EpollEvent, EpollEvents, Signal};
+#[cfg(feature = "async")]
+use crate::AsyncFd::AsyncFdBox;
 
 const SIGINFO_BUFSIZE: usize = 20;
 
-fn waitid(idtype: libc::idtype_t, id: libc::id_t, options: c_int)
+pub(crate) fn waitid(idtype: libc::idtype_t, id: libc::id_t, options: c_int)
     -> Result<Option<libc::siginfo_t>>
 {
     let mut siginfo = MaybeUninit::<libc::siginfo_t>::zeroed();
@@ -41,52 +53,118 @@ fn waitid(idtype: libc::idtype_t, id: libc::id_t, options: c_int)
     }
 }
 
+/// Block `SIGCHLD` and create a non-blocking signalfd for it.
+fn new_sigchld_fd() -> Result<FdBox> {
+    let mut mask = std::mem::MaybeUninit::<sigset_t>::uninit();
+    unsafe {
+        if sigemptyset(mask.as_mut_ptr()) < 0 {
+            return Err(Error::last_os_error());
+        }
+        if sigaddset(mask.as_mut_ptr(), SIGCHLD) < 0 {
+            return Err(Error::last_os_error());
+        }
+    };
+    let mask = unsafe { mask.assume_init() };
+
+    if unsafe {
+        sigprocmask(SIG_BLOCK, &mask as *const _, std::ptr::null_mut())
+    } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let fd = unsafe {
+        signalfd(-1, &mask as *const _, SFD_NONBLOCK | SFD_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(unsafe { FdBox::from_raw(fd) })
+}
+
+/// Drain the pending siginfo from the non-blocking signalfd `fd`.
+fn drain_signalfd(fd: &FdBox) -> Result<()> {
+    let mut buf = [0 as u8; size_of::<signalfd_siginfo>() * SIGINFO_BUFSIZE];
+    loop {
+        match autorestart!({ fd.read(&mut buf) }) {
+            Ok(_) => continue,
+            Err(err) if err.get_errno() == libc::EAGAIN => break Ok(()),
+            Err(err) => break Err(err.into()),
+        }
+    }
+}
+
 // Workaround for WaitMap's strange requirement in wait
+#[cfg(feature = "async")]
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 struct Pid(libc::pid_t);
+#[cfg(feature = "async")]
 impl From<&Pid> for Pid {
     fn from(pid: &Pid) -> Pid {
         *pid
     }
 }
 
+#[cfg(feature = "async")]
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    /// Insertion sequence number
+    seq: u64,
+    info: ExitInfo,
+}
+
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+struct InsertionOrder {
+    next_seq: u64,
+    /// (seq, pid) of entries in the order of insertion.
+    ///
+    /// It might contain entries that are already removed from the map by `wait`
+    /// or whose pid is reused, which are detected by comparing seq.
+    queue: VecDeque<(u64, Pid)>,
+}
+
 /// Due to the fact that epoll on signalfd would fail after fork, you cannot use
 /// SigChldFd after forked
+#[cfg(feature = "async")]
 pub struct SigChldFd {
-    inner: AsyncFd<FdBox>,
-    map: WaitMap<Pid, ExitInfo>
+    inner: AsyncFdBox,
+    map: WaitMap<Pid, Entry>,
+    max_pending: usize,
+    order: Mutex<InsertionOrder>,
+    /// Set to true by `shutdown`
+    shutdown: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
 }
+#[cfg(feature = "async")]
 impl SigChldFd {
+    /// Same as `with_max_pending(usize::MAX)`, so the number of pending entries
+    /// is unlimited.
     pub fn new() -> Result<(Arc<SigChldFd>, JoinHandle<Result<()>>)> {
-        let mut mask = std::mem::MaybeUninit::<sigset_t>::uninit();
-        unsafe {
-            if sigemptyset(mask.as_mut_ptr()) < 0 {
-                return Err(Error::last_os_error());
-            }
-            if sigaddset(mask.as_mut_ptr(), SIGCHLD) < 0 {
-                return Err(Error::last_os_error());
-            }
-        };
-        let mask = unsafe { mask.assume_init() };
+        SigChldFd::with_max_pending(usize::MAX)
+    }
 
-        if unsafe {
-            sigprocmask(SIG_BLOCK, &mask as *const _, std::ptr::null_mut())
-        } < 0 {
-            return Err(Error::last_os_error());
-        }
+    /// * `max_pending` - the maximum number of children that have terminated
+    ///   but not yet retrieved via `wait`.
+    ///   Once exceeded, the oldest entry is evicted.
+    ///
+    /// **WARNING**: Once the entry of a child is evicted, `wait` for that child
+    /// would block forever, so `max_pending` should be large enough to hold all
+    /// the children that the caller is going to `wait` for.
+    pub fn with_max_pending(max_pending: usize)
+        -> Result<(Arc<SigChldFd>, JoinHandle<Result<()>>)>
+    {
+        let fd = new_sigchld_fd()?;
 
-        let fd = unsafe {
-            signalfd(-1, &mask as *const _, SFD_NONBLOCK | SFD_CLOEXEC)
-        };
-        if fd < 0 {
-            return Err(Error::last_os_error());
-        }
-
-        let fd = unsafe { FdBox::from_raw(fd) };
+        let (shutdown, shutdown_rx) = watch::channel(false);
 
         let ret = Arc::new(SigChldFd {
-            inner: AsyncFd::with_interest(fd, Interest::READABLE)?,
-            map: WaitMap::new()
+            inner: AsyncFdBox::new(fd, Interest::READABLE)?,
+            map: WaitMap::new(),
+            max_pending,
+            order: Mutex::new(InsertionOrder::default()),
+            shutdown,
+            shutdown_rx,
         });
 
         let sigfd = ret.clone();
@@ -101,22 +179,11 @@ impl SigChldFd {
     }
 
     async fn read_bytes(&self, out: &mut [u8]) -> Result<usize> {
-        loop {
-            let mut guard = self.inner.readable().await?;
-
-            match guard.try_io(|inner| -> Result<usize> {
-                let fd = inner.get_ref();
-
-                Ok(
-                    autorestart!({
-                        fd.read(out)
-                    })?
-                )
-            }) {
-                Ok(result) => break result,
-                Err(_would_block) => continue,
-            }
-        }
+        self.inner.try_io(Interest::READABLE, |fd| {
+            autorestart!({
+                fd.read(out)
+            })
+        }).await
     }
 
     async fn read(&self) -> Result<()> {
@@ -136,8 +203,17 @@ impl SigChldFd {
             )
         };
 
+        let mut shutdown = self.shutdown_rx.clone();
+
         loop {
-            let cnt = self.read_bytes(bytes).await?;
+            if *shutdown.borrow() {
+                break Ok(());
+            }
+
+            let cnt = tokio::select! {
+                cnt = self.read_bytes(bytes) => cnt?,
+                _ = shutdown.changed() => continue,
+            };
 
             assert_eq!(cnt % size_of::<signalfd_siginfo>(), 0);
 
@@ -177,42 +253,250 @@ impl SigChldFd {
 
             // Continue to collect zombies whose SIGCHLD might get coalesced
             while let Some(siginfo) = waitid(P_ALL, 0, waitid_option)? {
-                self.map.insert(
+                self.insert(
                     Pid(unsafe { siginfo.si_pid() }),
-                    ExitInfo {
-                        uid: unsafe { siginfo.si_uid() },
-                        wstatus: unsafe { siginfo.si_status() },
-                        utime: unsafe { siginfo.si_utime() },
-                        stime: unsafe { siginfo.si_stime() }
-                    }
+                    ExitInfo::from_siginfo(&siginfo)
                 );
             }
         }
     }
 
-    pub async fn wait(&self, pid: pid_t) -> ExitInfo {
+    fn insert(&self, pid: Pid, info: ExitInfo) {
+        let mut order = self.order.lock().unwrap();
+
+        let seq = order.next_seq;
+        order.next_seq += 1;
+
+        self.map.insert(pid, Entry { seq, info });
+        order.queue.push_back((seq, pid));
+
+        // Evict the oldest entries
+        while self.map.len() > self.max_pending {
+            let (seq, pid) = match order.queue.pop_front() {
+                Some(item) => item,
+                None => break,
+            };
+
+            let is_same_entry = match self.map.get(&pid) {
+                Some(val) => val.value().seq == seq,
+                None => false,
+            };
+            if is_same_entry {
+                self.map.remove(&pid);
+            }
+        }
+
+        // Remove entries that are already retrieved via wait, so that the queue
+        // does not grow unbounded.
+        if order.queue.len() > 2 * self.map.len().max(64) {
+            let map = &self.map;
+            order.queue.retain(|(seq, pid)| match map.get(pid) {
+                Some(val) => val.value().seq == *seq,
+                None => false,
+            });
+        }
+    }
+
+    /// Remove the entry for `pid` from the map.
+    ///
+    /// It is done with `order` locked, same as `insert`, so that concurrent
+    /// `take` on the same pid cannot both retrieve the entry, nor remove an
+    /// entry inserted in between for a reused pid.
+    fn take(&self, pid: &Pid) -> Option<ExitInfo> {
+        let _order = self.order.lock().unwrap();
+
+        let info = self.map.get(pid).map(|val| val.value().info)?;
+        self.map.remove(pid);
+        Some(info)
+    }
+
+    /// Number of children that have terminated but not yet retrieved via `wait`.
+    pub fn pending_count(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown_rx.borrow()
+    }
+
+    /// Stop the reaper task, which causes the `JoinHandle` returned by `new`
+    /// to resolve to `Ok(())`.
+    ///
+    /// Zombies are no longer reaped after the reaper task is stopped.
+    pub fn shutdown(&self) {
+        // self holds a receiver, so it never fails.
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Wait for child `pid` to terminate.
+    ///
+    /// The exit info of each child can only be retrieved once, so there should
+    /// be only a single waiter for each child: if multiple `wait` on the same
+    /// pid run concurrently, only one of them returns it and the others would
+    /// block until the pid is reused, same as any subsequent `wait`.
+    ///
+    /// Returns `None` if the reaper is shut down via `shutdown` before `pid`
+    /// terminates, including waiters that are already waiting when `shutdown`
+    /// is called.
+    pub async fn wait(&self, pid: pid_t) -> Option<ExitInfo> {
         let pid = Pid(pid);
+        let mut shutdown = self.shutdown_rx.clone();
+
         loop {
-            match self.map.wait(&pid).await {
-                Some(val) => break *(val.value()),
-                None => continue,
+            if *shutdown.borrow() {
+                break self.take(&pid);
+            }
+
+            tokio::select! {
+                val = self.map.wait(&pid) => match val {
+                    Some(val) => {
+                        drop(val);
+                        match self.take(&pid) {
+                            Some(info) => break Some(info),
+                            // Retrieved by another concurrent wait
+                            None => continue,
+                        }
+                    },
+                    None => continue,
+                },
+                _ = shutdown.changed() => continue,
             }
         }
     }
 }
 
+/// Tokio-free alternative to `SigChldFd`, which reaps zombies synchronously.
+///
+/// `SIGCHLD` is blocked and received via a signalfd, which is registered in
+/// an `EpollReactor` for `wait`, or can be registered in the `EpollReactor`
+/// of the caller via `get_fd` and then drained via `reap` once readable.
+///
+/// Same as `SigChldFd`, it cannot be used after forked.
+#[derive(Debug)]
+pub struct BlockingSigChld {
+    fd: FdBox,
+    reactor: EpollReactor,
+}
+impl BlockingSigChld {
+    pub fn new() -> Result<BlockingSigChld> {
+        let fd = new_sigchld_fd()?;
+
+        let reactor = EpollReactor::new()?;
+        reactor.add(&fd, EpollEvents::EPOLLIN, 0)?;
+
+        Ok(BlockingSigChld { fd, reactor })
+    }
+
+    /// The signalfd, which becomes readable once any child terminates.
+    pub fn get_fd(&self) -> Fd {
+        *self.fd
+    }
+
+    /// Reap all the children that have terminated without blocking, calling
+    /// `f` with the pid and exit info of each of them.
+    pub fn reap<F: FnMut(pid_t, ExitInfo)>(&self, mut f: F) -> Result<()> {
+        use libc::P_ALL;
+
+        // Drain before waitid, so that SIGCHLD generated after waitid is not
+        // lost.
+        drain_signalfd(&self.fd)?;
+
+        while let Some(siginfo) = waitid(P_ALL, 0, libc::WEXITED | libc::WNOHANG)? {
+            f(unsafe { siginfo.si_pid() }, ExitInfo::from_siginfo(&siginfo));
+        }
+
+        Ok(())
+    }
+
+    /// Block until any child terminates or `timeout` expires, then reap all
+    /// the children that have terminated.
+    ///
+    ///  * `timeout` - If `None`, then wait indefinitely.
+    ///
+    /// Returns the pid and exit info of the children reaped, which is empty if
+    /// timed out.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Vec<(pid_t, ExitInfo)>> {
+        let mut reaped = Vec::new();
+
+        // Reap the children that terminated before SIGCHLD was blocked.
+        self.reap(|pid, info| reaped.push((pid, info)))?;
+        if !reaped.is_empty() {
+            return Ok(reaped);
+        }
+
+        let mut events = [EpollEvent::default(); 1];
+        if self.reactor.wait(&mut events, timeout)? != 0 {
+            self.reap(|pid, info| reaped.push((pid, info)))?;
+        }
+
+        Ok(reaped)
+    }
+}
+
+/// How the child changed state, decoded from the `siginfo_t` returned by
+/// `waitid`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// The child exited normally with the exit status.
+    Exited(c_int),
+    /// The child is terminated by `sig`.
+    Signaled { sig: Signal, core_dumped: bool },
+    /// The child is stopped (or trapped) by the signal.
+    Stopped(Signal),
+    /// The child is resumed by `SIGCONT`.
+    Continued,
+    /// `si_code` or `si_status` returned by `waitid` is not recognized.
+    Unknown { code: c_int, status: c_int },
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct ExitInfo {
     /// uid of the child when it exits
     uid: libc::uid_t,
-    /// exit status of the child
-    wstatus: c_int,
+    /// `si_code`, one of `CLD_*`
+    code: c_int,
+    /// `si_status`, either exit status or signal depending on `code`
+    status: c_int,
     /// user time consumed
     utime: libc::clock_t,
     /// system time consumed
     stime: libc::clock_t,
 }
 impl ExitInfo {
+    /// * `siginfo` - returned by `waitid`
+    pub(crate) fn from_siginfo(siginfo: &libc::siginfo_t) -> ExitInfo {
+        ExitInfo {
+            uid: unsafe { siginfo.si_uid() },
+            code: siginfo.si_code,
+            status: unsafe { siginfo.si_status() },
+            utime: unsafe { siginfo.si_utime() },
+            stime: unsafe { siginfo.si_stime() }
+        }
+    }
+
+    /// Decode the state change of the child.
+    ///
+    /// `waitid` reports the exit status or signal in `si_status` and how to
+    /// interpret it in `si_code`, so `WIF*` macros cannot be used here.
+    ///
+    /// Returns `WaitStatus::Unknown` instead of panicking if `si_code` is not
+    /// one of `CLD_*` or `si_status` is not a valid signal.
+    pub fn status(&self) -> WaitStatus {
+        let unknown = WaitStatus::Unknown { code: self.code, status: self.status };
+        let sig = Signal::new(self.status);
+
+        match (self.code, sig) {
+            (libc::CLD_EXITED, _) => WaitStatus::Exited(self.status),
+            (libc::CLD_KILLED, Some(sig)) => WaitStatus::Signaled { sig, core_dumped: false },
+            (libc::CLD_DUMPED, Some(sig)) => WaitStatus::Signaled { sig, core_dumped: true },
+            (libc::CLD_STOPPED, Some(sig)) | (libc::CLD_TRAPPED, Some(sig)) =>
+                WaitStatus::Stopped(sig),
+            (libc::CLD_CONTINUED, _) => WaitStatus::Continued,
+            _ => unknown,
+        }
+    }
+
     /// uid of the process when it exits
     pub fn get_uid(&self) -> libc::uid_t {
         self.uid
@@ -231,19 +515,65 @@ impl ExitInfo {
     /// Get exit status if the child terminated normally instead of terminated
     /// by signal
     pub fn get_exit_status(&self) -> Option<c_int> {
-        if libc::WIFEXITED(self.wstatus) {
-            Some(libc::WEXITSTATUS(self.wstatus))
-        } else {
-            None
+        match self.status() {
+            WaitStatus::Exited(exit_status) => Some(exit_status),
+            _ => None,
         }
     }
 
     /// Get the signal that terminated the process if it is killed by signal
     pub fn get_term_sig(&self) -> Option<c_int> {
-        if libc::WIFSIGNALED(self.wstatus) {
-            Some(libc::WTERMSIG(self.wstatus))
-        } else {
-            None
+        match self.status() {
+            WaitStatus::Signaled { sig, .. } => Some(sig.get_signo()),
+            _ => None,
+        }
+    }
+
+    /// Whether the process is terminated by `SIGXCPU` or `SIGKILL`, which is
+    /// how it is terminated once it exceeds `Command::cpu_time_limit`.
+    ///
+    /// Since `SIGKILL` can also be sent by others, e.g. the OOM killer, it
+    /// might return true even if the process is within the limit.
+    pub fn exceeded_cpu_limit(&self) -> bool {
+        match self.status() {
+            WaitStatus::Signaled { sig, .. } =>
+                sig == Signal::SIGXCPU || sig == Signal::SIGKILL,
+            _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ExitInfo, WaitStatus};
+    use crate::syscall::Signal;
+
+    fn exit_info(code: libc::c_int, status: libc::c_int) -> ExitInfo {
+        ExitInfo { uid: 0, code, status, utime: 0, stime: 0 }
+    }
+
+    #[test]
+    fn test_exit_info_status() {
+        assert_eq!(exit_info(libc::CLD_EXITED, 3).status(), WaitStatus::Exited(3));
+        assert_eq!(
+            exit_info(libc::CLD_KILLED, libc::SIGTERM).status(),
+            WaitStatus::Signaled { sig: Signal::SIGTERM, core_dumped: false }
+        );
+        assert_eq!(
+            exit_info(libc::CLD_DUMPED, libc::SIGSEGV).status(),
+            WaitStatus::Signaled { sig: Signal::SIGSEGV, core_dumped: true }
+        );
+        assert_eq!(
+            exit_info(libc::CLD_TRAPPED, libc::SIGTRAP).status(),
+            WaitStatus::Stopped(Signal::SIGTRAP)
+        );
+        assert_eq!(exit_info(libc::CLD_CONTINUED, libc::SIGCONT).status(), WaitStatus::Continued);
+
+        assert_eq!(exit_info(100, 0).status(), WaitStatus::Unknown { code: 100, status: 0 });
+        assert_eq!(
+            exit_info(libc::CLD_KILLED, 0).status(),
+            WaitStatus::Unknown { code: libc::CLD_KILLED, status: 0 }
+        );
+        assert_eq!(exit_info(100, 0).get_term_sig(), None);
+    }
+}
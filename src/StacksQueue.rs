@@ -1,15 +1,190 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering::Relaxed};
+
 use crossbeam_queue::SegQueue;
 
-use crate::lowlevel::Stack;
+use crate::lowlevel::{Stack, StackObjectAllocator};
+use crate::syscall::{getrandom, get_pagesz, GetrandomFlags};
+use crate::error::SyscallError;
 
 static QUEUE: SegQueue<Stack> = SegQueue::new();
 
+static POOLED_COUNT: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_RESERVED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static GETS: AtomicU64 = AtomicU64::new(0);
+static PUTS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+static RANDOMIZE_PLACEMENT: AtomicBool = AtomicBool::new(false);
+
+/// Max number of pages the stack of the child is moved down by when
+/// `set_randomize_placement(true)`.
+pub const MAX_PLACEMENT_JITTER_PAGES: usize = 16;
+
 pub fn get() -> crate::lowlevel::Stack
 {
-    QUEUE.pop().unwrap_or(Stack::new())
+    GETS.fetch_add(1, Relaxed);
+
+    match QUEUE.pop() {
+        Some(stack) => {
+            // Decremented after pop, so that it never goes below 0
+            POOLED_COUNT.fetch_sub(1, Relaxed);
+            TOTAL_RESERVED_BYTES.fetch_sub(stack.reserved_bytes(), Relaxed);
+            stack
+        },
+        None => {
+            MISSES.fetch_add(1, Relaxed);
+            Stack::new()
+        },
+    }
 }
 
 pub fn put(stack: crate::lowlevel::Stack)
 {
+    PUTS.fetch_add(1, Relaxed);
+
+    // Incremented before push, so that get never decrements it below 0
+    POOLED_COUNT.fetch_add(1, Relaxed);
+    TOTAL_RESERVED_BYTES.fetch_add(stack.reserved_bytes(), Relaxed);
     QUEUE.push(stack);
 }
+
+/// If true, the stack of each child spawned by `process::Command` is moved down
+/// by a random number of pages, from 0 up to `MAX_PLACEMENT_JITTER_PAGES`,
+/// read via `getrandom`, so that the address of the stack is less predictable
+/// even though the stacks are reused.
+///
+/// It costs up to `MAX_PLACEMENT_JITTER_PAGES` pages of address space per stack
+/// and only matters if the child runs untrusted logic before `execve`, since
+/// the stack is gone once it calls `execve`.
+///
+/// Defaults to false.
+pub fn set_randomize_placement(randomize: bool) {
+    RANDOMIZE_PLACEMENT.store(randomize, Relaxed);
+}
+
+pub fn get_randomize_placement() -> bool {
+    RANDOMIZE_PLACEMENT.load(Relaxed)
+}
+
+/// Random number of bytes, which is a multiple of the page size, to move the
+/// stack down by, or 0 if `get_randomize_placement()` is false.
+fn placement_jitter() -> Result<usize, SyscallError> {
+    if !get_randomize_placement() {
+        return Ok(0);
+    }
+
+    let mut buf = [0 as u8; 8];
+    getrandom(&mut buf, GetrandomFlags::empty())?;
+
+    let pages = u64::from_ne_bytes(buf) as usize % (MAX_PLACEMENT_JITTER_PAGES + 1);
+    Ok(pages * get_pagesz())
+}
+
+/// Same as `Stack::reserve`, but the stack is moved down by a random amount
+/// if `get_randomize_placement()` is true.
+pub(crate) fn reserve(stack: &mut Stack, reserved_stack_sz: usize, reserved_obj_sz: usize)
+    -> Result<StackObjectAllocator, SyscallError>
+{
+    let jitter = placement_jitter()?;
+
+    let mut allocator = stack.reserve(reserved_stack_sz, reserved_obj_sz + jitter)?;
+    let padded = allocator.pad_top(jitter);
+    debug_assert!(padded);
+
+    Ok(allocator)
+}
+
+/// Snapshot of the pool of stacks used by `process::Command`.
+///
+/// Counters are updated with relaxed atomics, so fields read while other
+/// threads are calling `get` or `put` might be slightly inconsistent
+/// with each other.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of stacks currently in the pool
+    pub pooled_count: usize,
+    /// Sum of `Stack::reserved_bytes` of all stacks currently in the pool
+    pub total_reserved_bytes: usize,
+    /// Number of calls to `get`
+    pub gets: u64,
+    /// Number of calls to `put`
+    pub puts: u64,
+    /// Number of calls to `get` that found the pool empty and created
+    /// a new stack
+    pub misses: u64,
+}
+impl PoolStats {
+    /// Ratio of `get` served from the pool, or `None` if `get` has never
+    /// been called.
+    pub fn get_hit_rate(&self) -> Option<f64> {
+        if self.gets == 0 {
+            None
+        } else {
+            Some((self.gets - self.misses) as f64 / self.gets as f64)
+        }
+    }
+}
+
+pub fn stats() -> PoolStats
+{
+    PoolStats {
+        pooled_count: POOLED_COUNT.load(Relaxed),
+        total_reserved_bytes: TOTAL_RESERVED_BYTES.load(Relaxed),
+        gets: GETS.load(Relaxed),
+        puts: PUTS.load(Relaxed),
+        misses: MISSES.load(Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::tests::run;
+
+    /// The counters are global, so it is run in a forked process, where no
+    /// other test calls `get` or `put` concurrently.
+    #[test]
+    fn test_stats() {
+        assert_eq!(run(|| {
+            // Drain the pool, so that the next `get` is a miss.
+            let mut drained = Vec::new();
+            while let Some(stack) = QUEUE.pop() {
+                POOLED_COUNT.fetch_sub(1, Relaxed);
+                TOTAL_RESERVED_BYTES.fetch_sub(stack.reserved_bytes(), Relaxed);
+                drained.push(stack);
+            }
+
+            let before = stats();
+            assert_eq!(before.pooled_count, 0);
+            assert_eq!(before.total_reserved_bytes, 0);
+
+            let mut stack = get();
+            reserve(&mut stack, 4096, 0).unwrap();
+            let reserved_bytes = stack.reserved_bytes();
+            assert!(reserved_bytes > 0);
+
+            let stats1 = stats();
+            assert_eq!(stats1.gets, before.gets + 1);
+            assert_eq!(stats1.misses, before.misses + 1);
+            assert_eq!(stats1.puts, before.puts);
+
+            put(stack);
+            let stats2 = stats();
+            assert_eq!(stats2.puts, before.puts + 1);
+            assert_eq!(stats2.pooled_count, 1);
+            assert_eq!(stats2.total_reserved_bytes, reserved_bytes);
+
+            let stack = get();
+            let stats3 = stats();
+            assert_eq!(stats3.gets, before.gets + 2);
+            assert_eq!(stats3.misses, before.misses + 1);
+            assert_eq!(stats3.pooled_count, 0);
+            assert_eq!(stats3.total_reserved_bytes, 0);
+            assert_eq!(stack.reserved_bytes(), reserved_bytes);
+
+            let stats = PoolStats { gets: 4, misses: 1, ..PoolStats::default() };
+            assert_eq!(stats.get_hit_rate(), Some(0.75));
+            assert_eq!(PoolStats::default().get_hit_rate(), None);
+        }), 0);
+    }
+}
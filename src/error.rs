@@ -4,6 +4,7 @@
 
 use std::ffi::CStr;
 use std::fmt;
+use std::panic::Location;
 use std::slice::from_raw_parts;
 
 use once_cell::sync::OnceCell;
@@ -11,12 +12,18 @@ use once_cell::sync::OnceCell;
 include!(concat!(env!("OUT_DIR"), "/errno_msgs_binding.rs"));
 
 /// * `result` - return value of syscall
-pub const fn toResult(result: i64) -> Result<u64, SyscallError> {
+///
+/// In debug builds, the location of the caller is captured in the error, which
+/// is the syscall wrapper that failed.
+#[track_caller]
+pub fn toResult(result: i64) -> Result<u64, SyscallError> {
     if result >= 0 {
         Ok(result as u64)
     } else {
-        Err(SyscallError{
-            errno: (-result) as u32
+        Err(SyscallError {
+            errno: (-result) as u32,
+            #[cfg(debug_assertions)]
+            location: Some(Location::caller()),
         })
     }
 }
@@ -43,15 +50,45 @@ pub fn get_errno_msgs() -> &'static errno_msgs_t {
 
 pub struct SyscallError {
     errno: u32,
+    /// Only captured in debug builds, so that it is a bare errno in release
+    /// builds.
+    #[cfg(debug_assertions)]
+    location: Option<&'static Location<'static>>,
 }
 impl SyscallError {
     pub const fn new(errno: u32) -> SyscallError {
-        SyscallError { errno }
+        SyscallError {
+            errno,
+            #[cfg(debug_assertions)]
+            location: None,
+        }
+    }
+
+    /// Returns `None` if `err` is not an os error, e.g. a custom `io::Error`,
+    /// or if its errno is not positive.
+    pub fn from_io_error(err: &std::io::Error) -> Option<SyscallError> {
+        match err.raw_os_error() {
+            Some(errno) if errno > 0 => Some(SyscallError::new(errno as u32)),
+            _ => None,
+        }
     }
 
     pub const fn get_errno(&self) -> i32 {
         self.errno as i32
     }
+
+    /// Where the error is returned from the syscall, which is only captured
+    /// in debug builds for errors created via `toResult`.
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        #[cfg(debug_assertions)]
+        {
+            self.location
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            None
+        }
+    }
     pub fn get_msg(&self) -> &'static str {
         /* self.errno should be in range 1..errno_msgs_sz */
         if self.errno <= errno_msgs_sz as u32 {
@@ -68,7 +105,11 @@ impl fmt::Display for SyscallError {
 }
 impl fmt::Debug for SyscallError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(self, f)
+        fmt::Display::fmt(self, f)?;
+        match self.location() {
+            Some(location) => write!(f, " at {}", location),
+            None => Ok(()),
+        }
     }
 }
 impl From<SyscallError> for std::io::Error {
@@ -77,6 +118,11 @@ impl From<SyscallError> for std::io::Error {
     }
 }
 
+/// `EIO` is used if `err` is not an os error.
+pub(crate) fn to_syscall_error(err: std::io::Error) -> SyscallError {
+    SyscallError::from_io_error(&err).unwrap_or(SyscallError::new(libc::EIO as u32))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::error::*;
@@ -85,4 +131,25 @@ mod tests {
     fn test_get_errno_msgs() {
         println!("{:#?}", get_errno_msgs());
     }
+
+    #[test]
+    fn test_location() {
+        let err = toResult(-(libc::EINVAL as i64)).unwrap_err();
+        if cfg!(debug_assertions) {
+            assert_eq!(err.location().unwrap().file(), file!());
+        } else {
+            assert!(err.location().is_none());
+        }
+
+        assert!(SyscallError::new(libc::EINVAL as u32).location().is_none());
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let err: std::io::Error = SyscallError::new(libc::ENOENT as u32).into();
+        assert_eq!(SyscallError::from_io_error(&err).unwrap().get_errno(), libc::ENOENT);
+
+        let err = std::io::Error::new(std::io::ErrorKind::Other, "custom");
+        assert!(SyscallError::from_io_error(&err).is_none());
+    }
 }
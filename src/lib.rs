@@ -17,8 +17,11 @@ pub mod lowlevel;
 /// highlevel wrapper of aspawn
 pub mod process;
 
-mod StacksQueue;
+/// pool of stacks reused by `process::Command`
+pub mod StacksQueue;
 mod SignalFd;
+#[cfg(feature = "async")]
+mod AsyncFd;
 
 extern crate once_cell;
 extern crate libc;
@@ -28,7 +31,9 @@ extern crate bitflags;
 pub extern crate cstr;
 
 extern crate crossbeam_queue; // For mod StacksQueue
-extern crate tokio;           // For mod process and SignalFd
+#[cfg(feature = "async")]
+extern crate tokio;           // For mod process, SignalFd and AsyncFd
+#[cfg(feature = "async")]
 extern crate waitmap;         // For mod SignalFd
 
 #[cfg(test)]
@@ -1,9 +1,11 @@
 use std::mem;
+use std::fmt;
 use std::pin::Pin;
 use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::os::raw::{c_void, c_int};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::raw::{c_void, c_int, c_char};
 use std::marker::PhantomData;
+use std::ffi::CStr;
 
 use crate::expect;
 use crate::error;
@@ -16,8 +18,8 @@ use error::toResult;
 
 pub use syscall::sigset_t;
 pub use syscall::pid_t;
-pub use syscall::{Fd, FdBox};
-use syscall::FromRaw;
+pub use syscall::{Fd, FdBox, CStrArray, CloneFlags};
+use syscall::{FromRaw, FdBasicOp, FdFlags, SigprocmaskHow, sigprocmask, sigfillset};
 
 use utility::to_void_ptr;
 
@@ -66,6 +68,79 @@ impl Stack {
         }
         Ok(StackObjectAllocator::new(self.stack_impl, reserved_obj_sz))
     }
+
+    /// Same as `reserve`, except that if the stack is already reserved, the
+    /// object arena is checked against the memory mmaped for it via
+    /// `get_max_obj_sz`, so that the stack is never mmaped again, and failures
+    /// are distinguished by `ReserveError`.
+    ///
+    /// **This API is safe to be used inside avfork callback.**
+    pub fn try_reserve(&mut self, reserved_stack_sz: usize, reserved_obj_sz: usize)
+        -> Result<StackObjectAllocator, ReserveError>
+    {
+        if let Some(max) = self.get_max_obj_sz(reserved_stack_sz) {
+            if reserved_obj_sz > max {
+                return Err(ReserveError::ObjectArenaTooLarge {
+                    requested: reserved_obj_sz,
+                    max,
+                });
+            }
+        }
+
+        self.reserve(reserved_stack_sz, reserved_obj_sz)
+            .map_err(ReserveError::StackMmapFailed)
+    }
+
+    /// Size of the memory mmaped for this stack in bytes, which is 0 if
+    /// `reserve` has never been called.
+    ///
+    /// **This API is safe to be used inside avfork callback.**
+    pub fn reserved_bytes(&self) -> usize {
+        self.stack_impl.size as usize
+    }
+
+    /// The largest object arena that fits in the memory already mmaped for
+    /// this stack, along with a stack of `reserved_stack_sz` bytes.
+    ///
+    /// Returns `None` if `reserve` has never been called, in which case the
+    /// arena is only bounded by the memory that can be mmaped.
+    ///
+    /// **This API is safe to be used inside avfork callback.**
+    pub fn get_max_obj_sz(&self, reserved_stack_sz: usize) -> Option<usize> {
+        match self.reserved_bytes() {
+            0 => None,
+            reserved => Some(
+                reserved
+                    .saturating_sub(BASIC_STACK_SZ)
+                    .saturating_sub(reserved_stack_sz)
+            ),
+        }
+    }
+}
+
+/// Bytes unconditionally allocated by `Stack::reserve` for basic operations.
+const BASIC_STACK_SZ: usize = 32 * 1024;
+
+/// Returned by `Stack::try_reserve`.
+#[derive(Debug)]
+pub enum ReserveError {
+    /// Failed to mmap or grow the stack, e.g. `ENOMEM`.
+    StackMmapFailed(SyscallError),
+    /// The object arena requested does not fit in the memory already mmaped
+    /// for the stack, so retrying with a smaller arena of at most `max` bytes
+    /// would succeed without mmaping again.
+    ObjectArenaTooLarge { requested: usize, max: usize },
+}
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReserveError::StackMmapFailed(err) =>
+                write!(f, "Failed to mmap the stack: {}", err),
+            ReserveError::ObjectArenaTooLarge { requested, max } =>
+                write!(f, "Object arena of {} bytes is larger than the max {} bytes",
+                       requested, max),
+        }
+    }
 }
 
 /// StackObjectAllocator is a special class used to ensure that:
@@ -82,6 +157,8 @@ pub struct StackObjectAllocator<'a> {
     ///
     /// cell stores a copy of Stack_t and alloc_obj_sz
     cell: UnsafeCell<(aspawn::Stack_t, usize)>,
+    /// Stack_t right after reserve, used by reset
+    stack_impl: aspawn::Stack_t,
     reserved_obj_sz: usize,
     phantom: PhantomData<&'a Stack>,
 }
@@ -95,18 +172,57 @@ impl<'a> StackObjectAllocator<'a> {
     {
         StackObjectAllocator {
             cell: UnsafeCell::new((stack_impl, 0)),
+            stack_impl,
             reserved_obj_sz,
             phantom: PhantomData,
         }
     }
 
-    pub fn alloc_obj<T>(&self, obj: T) -> Result<StackBox<T>, T> {
-        let align = mem::align_of::<T>();
-        let size = mem::size_of::<T>();
+    /// Address range of the stack the child created by `avfork` runs on,
+    /// which excludes the objects allocated at its top.
+    pub fn get_stack_range(&self) -> Range<usize> {
+        let stack = unsafe { (*self.cell.get()).0 };
+        let addr = stack.addr as usize;
+        addr..(addr + stack.size as usize)
+    }
 
-        let remnant = size % align;
-        let size = size + if remnant != 0 { align - remnant } else { 0 };
+    /// Number of bytes that are reserved but not yet allocated.
+    pub fn get_remaining_obj_sz(&self) -> usize {
+        let alloc_obj_sz = unsafe { (*self.cell.get()).1 };
+        self.reserved_obj_sz - alloc_obj_sz
+    }
+
+    /// Free all the objects allocated, so that the whole object arena can be
+    /// reused without reserving the stack again.
+    ///
+    /// It takes `&mut self`, so no `StackBox` allocated from it can be alive.
+    /// The objects are not dropped, since each `StackBox` drops its object.
+    pub fn reset(&mut self) {
+        *self.cell.get_mut() = (self.stack_impl, 0);
+    }
+
+    /// Allocate `size` bytes of padding at the top of the stack, which moves
+    /// the objects allocated afterwards and the stack of the child down.
+    ///
+    /// Unlike the objects, the padding is kept across `reset`.
+    ///
+    /// Returns false if there isn't enough bytes left in the object arena.
+    pub fn pad_top(&mut self, size: usize) -> bool {
+        if self.alloc_raw(size).is_none() {
+            return false;
+        }
 
+        let cell = self.cell.get_mut();
+        self.stack_impl = cell.0;
+        self.reserved_obj_sz -= cell.1;
+        cell.1 = 0;
+
+        true
+    }
+
+    /// Allocate `size` bytes, which must be a multiple of the alignment of
+    /// the objects to be put there.
+    fn alloc_raw(&self, size: usize) -> Option<*mut c_void> {
         let alloc_obj_sz;
         let stack_impl;
 
@@ -117,22 +233,60 @@ impl<'a> StackObjectAllocator<'a> {
         }
 
         if (*alloc_obj_sz + size) > self.reserved_obj_sz {
-            Err(obj)
+            None
         } else {
             (*alloc_obj_sz) += size;
 
-            let addr;
             unsafe {
                 let size = size as u64;
-                addr = aspawn::allocate_obj_on_stack(stack_impl, size);
+                Some(aspawn::allocate_obj_on_stack(stack_impl, size))
             }
+        }
+    }
 
-            let addr = addr as *mut T;
-            unsafe {
-                // overwrite addr without dropping
-                addr.write(obj);
+    /// Allocate `func`, pass it to `f` to be called with `avfork`, then drop
+    /// it and free its slot in the object arena, along with any object
+    /// allocated in `f`, so that children can be spawned sequentially from
+    /// the same allocator without reserving the stack again.
+    ///
+    /// Every child created via this allocator runs on the same stack region,
+    /// thus the children must never overlap: `f` must not return until the
+    /// child has called `execve` or exited, i.e. until reading the fd returned
+    /// by `avfork` returns EOF, otherwise the next child would corrupt the
+    /// stack and `func` of the previous one, which is still running.
+    ///
+    /// Returns `Err(func)` if there isn't enough bytes left in the arena.
+    pub fn alloc_callback_scoped<Func, F, R>(&mut self, func: Func, f: F) -> Result<R, Func>
+        where F: FnOnce(&Self, Pin<&Func>) -> R
+    {
+        let saved = *self.cell.get_mut();
+
+        let ret = {
+            let func = self.alloc_obj(func)?;
+            f(self, func.pin())
+        };
+
+        *self.cell.get_mut() = saved;
+        Ok(ret)
+    }
+
+    pub fn alloc_obj<T>(&self, obj: T) -> Result<StackBox<T>, T> {
+        let align = mem::align_of::<T>();
+        let size = mem::size_of::<T>();
+
+        let remnant = size % align;
+        let size = size + if remnant != 0 { align - remnant } else { 0 };
+
+        match self.alloc_raw(size) {
+            None => Err(obj),
+            Some(addr) => {
+                let addr = addr as *mut T;
+                unsafe {
+                    // overwrite addr without dropping
+                    addr.write(obj);
+                }
+                Ok(StackBox::new(addr))
             }
-            Ok(StackBox::new(addr))
         }
     }
 }
@@ -154,6 +308,69 @@ impl<'a> std::fmt::Debug for StackObjectAllocator<'a> {
     }
 }
 
+/// Computes the layout of argv (or envp) to be built on the object arena of
+/// a `Stack`, so that exactly enough bytes can be reserved up front.
+///
+/// The layout consists of the null-terminated array of pointers followed by
+/// the nul-terminated strings, padded to the alignment of pointer.
+///
+/// **All APIs of this struct are safe to be used inside avfork callback.**
+pub struct ArgvLayout;
+impl ArgvLayout {
+    /// Returns (bytes of object arena required, number of arguments).
+    pub fn compute(args: &[&CStr]) -> (usize, usize) {
+        let ptrs_sz = (args.len() + 1) * mem::size_of::<*const c_char>();
+        let strs_sz = args
+            .iter()
+            .map(|arg| arg.to_bytes_with_nul().len())
+            .sum();
+
+        (ptrs_sz + ArgvLayout::pad(strs_sz), args.len())
+    }
+
+    fn pad(size: usize) -> usize {
+        let align = mem::align_of::<*const c_char>();
+        let remnant = size % align;
+        size + if remnant != 0 { align - remnant } else { 0 }
+    }
+
+    /// Copy `args` onto the object arena of `allocator`.
+    ///
+    /// Returns `None` without allocating anything if the remaining object arena
+    /// is less than the size computed by `ArgvLayout::compute`.
+    pub fn build_on<'a>(allocator: &'a StackObjectAllocator, args: &[&CStr])
+        -> Option<CStrArray<'a>>
+    {
+        let (arena_sz, cnt) = ArgvLayout::compute(args);
+        if arena_sz > allocator.get_remaining_obj_sz() {
+            return None;
+        }
+
+        let ptrs_sz = (cnt + 1) * mem::size_of::<*const c_char>();
+        let ptrs = allocator.alloc_raw(ptrs_sz)? as *mut *const c_char;
+        let mut strs = allocator.alloc_raw(arena_sz - ptrs_sz)? as *mut c_char;
+
+        for (i, arg) in args.iter().enumerate() {
+            let bytes = arg.to_bytes_with_nul();
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    bytes.as_ptr() as *const c_char, strs, bytes.len()
+                );
+                ptrs.add(i).write(strs);
+                strs = strs.add(bytes.len());
+            }
+        }
+
+        unsafe {
+            ptrs.add(cnt).write(std::ptr::null());
+
+            let arr = std::slice::from_raw_parts(ptrs as *const *const c_char, cnt + 1);
+            // arr is terminated by null
+            Some(CStrArray::from_raw(arr))
+        }
+    }
+}
+
 /// **All APIs of this struct are safe to be used inside avfork callback.**
 #[derive(Debug)]
 pub struct StackBox<'a, T> {
@@ -212,12 +429,32 @@ fn aspawn_fn<Func>(arg: *mut c_void, write_end_fd: c_int, old_sigset: *mut c_voi
 ///
 /// Returns fd of read end of CLOEXEC pipe and the pid of the child process.
 ///
+/// The child runs on the stack of `stack_alloc` and uses `func` until it
+/// calls `execve` or exits, after which reading the fd returns EOF, unless
+/// `func` leaks the write end it is passed.
+/// Only then the stack and `func` are free to be reused, e.g. by dropping
+/// `func`, `StackObjectAllocator::reset` or spawning the next child from the
+/// same allocator, check `StackObjectAllocator::alloc_callback_scoped`.
+///
+/// A `func` that overflows the stack is killed by `SIGSEGV`, which also closes
+/// the write end and thus cannot be told apart from a successful `execve` by
+/// reading the fd, so the stack should be reserved generously.
+/// `process::Command` detects it in debug builds by handling `SIGSEGV` on an
+/// alternate signal stack in the child, which can be done in `func` via
+/// `syscall::set_sigaltstack` and `syscall::sigaction_on_altstack`, with
+/// the fault address checked against `StackObjectAllocator::get_stack_range`.
+///
 /// avfork would disable thread cancellation, then it would revert it before return.
 ///
 /// It would also mask all signals in parent and reset the signal handler in 
 /// the child process.
 /// Before aspawn returns in parent, it would revert the signal mask.
 ///
+/// The signal mask belongs to each thread and is not shared even though the
+/// child shares the memory with the parent, thus changing the mask in `func`
+/// only affects the child (and the program it executes), while the parent
+/// reverts to the mask it saved before creating the child.
+///
 /// In the function fn, you can only use syscall declared in syscall
 /// Use of any glibc function or any function that modifies 
 /// global/thread-local variable is undefined behavior.
@@ -246,6 +483,131 @@ pub fn avfork<Func>(stack_alloc: &StackObjectAllocator, func: Pin<&Func>)
     Ok((unsafe { FdBox::from_raw(fd as i32) }, pid))
 }
 
+/// Arguments passed to `avfork_newpid_fn`, which live on the stack of the
+/// parent that is suspended until the child calls `execve` or exits.
+struct NewPidArgs<'a, Func> {
+    func: &'a Func,
+    write_end_fd: c_int,
+    old_sigset: sigset_t,
+}
+
+unsafe extern "C"
+fn avfork_newpid_fn<Func>(arg: *mut c_void) -> c_int
+    where Func: Fn(Fd, &mut sigset_t) -> c_int
+{
+    let args = &mut *(arg as *mut NewPidArgs<Func>);
+
+    if syscall::reset_signal_handlers().is_err() {
+        return 1;
+    }
+
+    (args.func)(Fd::from_raw(args.write_end_fd), &mut args.old_sigset)
+}
+
+/// Same as `avfork`, except that the child is created via `clone` with
+/// additional namespace `flags`, e.g. `CloneFlags::CLONE_NEWPID`.
+///
+/// `unshare(CLONE_NEWPID)` only affects the children created afterwards, so
+/// this is the only way to run the callback, and the program executed, as
+/// pid 1 of a new pid namespace:
+///  - the child sees itself as pid 1, while the returned pid is the one in
+///    the pid namespace of the caller,
+///  - the child is the init of the new namespace, thus it must reap its own
+///    orphans, and once it exits, all other processes in the namespace are
+///    killed by `SIGKILL`.
+///
+/// Creating any namespace requires `CAP_SYS_ADMIN`, unless
+/// `CloneFlags::CLONE_NEWUSER` is passed as well.
+///
+/// Unlike `avfork`, the caller is suspended until the child calls `execve`
+/// or exits, like `vfork`, thus `func` must not wait for the caller.
+///
+/// * `func` - same as `avfork`.
+///
+/// Returns fd of read end of CLOEXEC pipe and the pid of the child process.
+pub fn avfork_newpid<Func>(
+    stack_alloc: &StackObjectAllocator, func: Pin<&Func>, flags: CloneFlags)
+    -> Result<(FdBox, pid_t), SyscallError> where Func: Fn(Fd, &mut sigset_t) -> c_int
+{
+    // Objects are allocated from the top of the stack, so the stack used by
+    // the child starts right below them.
+    let stack = unsafe { (*stack_alloc.cell.get()).0 };
+    let stack_top = (stack.addr as usize + stack.size as usize) & !15;
+
+    clone_vfork(stack_top, func.get_ref(), flags)
+}
+
+/// Minimum size of the stack passed to `avfork_with_stack`.
+pub const MIN_AVFORK_STACK_SZ: usize = 4096 * 4;
+
+/// Alignment of the stack passed to `avfork_with_stack`, which is the
+/// alignment of stack pointer required by the ABI.
+pub const AVFORK_STACK_ALIGN: usize = 16;
+
+/// Same as `avfork_newpid` without any namespace flags, except that the child
+/// runs on `stack` provided by the caller instead of the cached stack of
+/// aspawn, e.g. a region with guard pages so that overflow is caught.
+///
+///  * `stack` - its address must be aligned to `AVFORK_STACK_ALIGN` and its len
+///    must be at least `MIN_AVFORK_STACK_SZ`, otherwise `EINVAL` is returned.
+///    The stack pointer of the child starts at its high end.
+///  * `func` - same as `avfork`, it is not copied onto `stack`, thus it must
+///    not be placed on `stack` either.
+///
+/// Like `avfork_newpid`, the caller is suspended until the child calls
+/// `execve` or exits, so that `stack` is not used by the child anymore when
+/// this function returns.
+///
+/// Returns fd of read end of CLOEXEC pipe and the pid of the child process.
+pub fn avfork_with_stack<Func>(stack: &mut [u8], func: Pin<&Func>)
+    -> Result<(FdBox, pid_t), SyscallError> where Func: Fn(Fd, &mut sigset_t) -> c_int
+{
+    let addr = stack.as_mut_ptr() as usize;
+    if addr % AVFORK_STACK_ALIGN != 0 || stack.len() < MIN_AVFORK_STACK_SZ {
+        return Err(SyscallError::new(libc::EINVAL as u32));
+    }
+
+    let stack_top = (addr + stack.len()) & !(AVFORK_STACK_ALIGN - 1);
+
+    clone_vfork(stack_top, func.get_ref(), CloneFlags::empty())
+}
+
+/// Create the child running `func` on `stack_top` via `clone` with
+/// `CLONE_VM | CLONE_VFORK` and additional `flags`.
+fn clone_vfork<Func>(stack_top: usize, func: &Func, flags: CloneFlags)
+    -> Result<(FdBox, pid_t), SyscallError> where Func: Fn(Fd, &mut sigset_t) -> c_int
+{
+    let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC)?;
+
+    let old_sigset = sigprocmask(SigprocmaskHow::SIG_SETMASK, Some(&sigfillset()))?;
+
+    let mut args = NewPidArgs {
+        func,
+        write_end_fd: write_end.get_fd(),
+        old_sigset,
+    };
+
+    let flags = flags.bits() | libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD;
+    let pid = unsafe {
+        libc::clone(
+            avfork_newpid_fn::<Func>,
+            stack_top as *mut c_void,
+            flags,
+            &mut args as *mut NewPidArgs<Func> as *mut c_void
+        )
+    };
+    let result = if pid < 0 {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL);
+        Err(SyscallError::new(errno as u32))
+    } else {
+        Ok((read_end, pid))
+    };
+
+    sigprocmask(SigprocmaskHow::SIG_SETMASK, Some(&old_sigset))?;
+
+    result
+}
+
 /// **NOT TESTED**
 ///
 /// * `func` - takes a Fd and sigset of the parent program, returns a c_int as 
@@ -314,6 +676,68 @@ mod tests {
         assert_matches!(allocator.alloc_obj(2333), Result::Err(2333));
     }
 
+    #[test]
+    fn test_stack_reserved_bytes() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.reserved_bytes(), 0);
+
+        stack.reserve(4096, 100).unwrap();
+        assert!(stack.reserved_bytes() >= 4096 + 100);
+    }
+
+    #[test]
+    fn test_stack_allocator_reset() {
+        let mut stack = Stack::new();
+
+        let mut allocator = stack.reserve(0, mem::size_of::<u64>()).unwrap();
+        for i in 0..3 {
+            let obj = allocator.alloc_obj(i as u64).unwrap();
+            assert_eq!(*obj, i as u64);
+            assert_eq!(allocator.get_remaining_obj_sz(), 0);
+            drop(obj);
+
+            allocator.reset();
+            assert_eq!(allocator.get_remaining_obj_sz(), mem::size_of::<u64>());
+        }
+    }
+
+    #[test]
+    fn test_stack_allocator_pad_top() {
+        let mut stack = Stack::new();
+
+        let mut allocator = stack.reserve(0, 4096 + mem::size_of::<u64>()).unwrap();
+        assert!(!allocator.pad_top(8192));
+        assert!(allocator.pad_top(4096));
+        assert_eq!(allocator.get_remaining_obj_sz(), mem::size_of::<u64>());
+
+        let obj = allocator.alloc_obj(1 as u64).unwrap();
+        drop(obj);
+
+        allocator.reset();
+        assert_eq!(allocator.get_remaining_obj_sz(), mem::size_of::<u64>());
+    }
+
+    #[test]
+    fn test_stack_try_reserve_too_large() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.get_max_obj_sz(4096), None);
+
+        stack.reserve(4096, mem::size_of::<u64>()).unwrap();
+        let max = stack.get_max_obj_sz(4096).unwrap();
+        assert!(max >= mem::size_of::<u64>());
+        assert_matches!(
+            stack.try_reserve(4096, max + 1),
+            Result::Err(ReserveError::ObjectArenaTooLarge { requested, max: m })
+                if requested == max + 1 && m == max
+        );
+
+        let reserved = stack.reserved_bytes();
+        let allocator = stack.try_reserve(4096, max).unwrap();
+        let _i = allocator.alloc_obj(1 as u64).unwrap();
+        drop(allocator);
+        assert_eq!(stack.reserved_bytes(), reserved);
+    }
+
     #[test]
     fn test_stack_reserve() {
         let mut stack = Stack::new();
@@ -340,6 +764,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_argv_layout() {
+        let args = [cstr!("/bin/echo"), cstr!("Hello"), cstr!("World!")];
+
+        let (arena_sz, cnt) = ArgvLayout::compute(&args);
+        assert_eq!(cnt, 3);
+
+        let mut stack = Stack::new();
+
+        let allocator = stack.reserve(0, arena_sz - 1).unwrap();
+        assert!(ArgvLayout::build_on(&allocator, &args).is_none());
+        assert_eq!(allocator.get_remaining_obj_sz(), arena_sz - 1);
+
+        let allocator = stack.reserve(0, arena_sz).unwrap();
+        let argv = ArgvLayout::build_on(&allocator, &args).unwrap();
+        assert_eq!(allocator.get_remaining_obj_sz(), 0);
+
+        let ptrs = unsafe { std::slice::from_raw_parts(argv.as_ptr(), cnt + 1) };
+        for (ptr, arg) in ptrs.iter().zip(args.iter()) {
+            assert_eq!(unsafe { CStr::from_ptr(*ptr) }, *arg);
+        }
+        assert!(ptrs[cnt].is_null());
+    }
+
     #[test]
     fn test_stackbox_pin() {
         let mut stack = Stack::new();
@@ -398,11 +846,70 @@ mod tests {
         0
     }
 
+    #[test]
+    fn test_alloc_callback_scoped() {
+        let mut stack = Stack::new();
+
+        let mut allocator = stack.reserve(4096 * 100, 100).unwrap();
+        let obj = allocator.alloc_obj(1 as u64).unwrap();
+        drop(obj);
+        let remaining = allocator.get_remaining_obj_sz();
+
+        for _ in 0..10 {
+            let pid = allocator.alloc_callback_scoped(dummy_avfork_callback, |allocator, f| {
+                let (fd, pid) = avfork(allocator, f).unwrap();
+
+                // The child must not be running on the stack once returned.
+                let mut buf = [1 as u8; 1];
+                assert_eq!(fd.read(&mut buf).unwrap(), 0);
+
+                pid
+            }).unwrap();
+
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert_eq!(allocator.get_remaining_obj_sz(), remaining);
+        }
+    }
+
     #[test]
     fn test_avfork_naive() {
         test_callback(dummy_avfork_callback);
     }
 
+    fn is_pid_one_callback(_fd: Fd, _old_sigset: &mut sigset_t) -> c_int {
+        if crate::syscall::getpid() == 1 { 0 } else { 1 }
+    }
+
+    #[test]
+    fn test_avfork_newpid() {
+        let mut stack = Stack::new();
+        let allocator = stack.reserve(4096 * 100, 100).unwrap();
+
+        // The stack of the child starts right below the objects.
+        let obj = allocator.alloc_obj(1 as u64).unwrap();
+
+        let f = is_pid_one_callback;
+        let flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWPID;
+        let (fd, pid) = match avfork_newpid(&allocator, Pin::new(&f), flags) {
+            Ok(ret) => ret,
+            // Unprivileged user namespaces are disabled.
+            Err(err) if [libc::EPERM, libc::EINVAL, libc::ENOSPC].contains(&err.get_errno()) =>
+                return,
+            Err(err) => panic!("avfork_newpid failed: {}", err),
+        };
+
+        let mut buf = [1 as u8; 1];
+        assert_eq!(fd.read(&mut buf).unwrap(), 0);
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        assert_eq!(*obj, 1);
+    }
+
     fn test_avfork_exec_callback(_fd: Fd, _old_sigset: &mut sigset_t) -> c_int {
         use crate::syscall::*;
         use crate::{CStrArray, errx};
@@ -440,6 +947,90 @@ mod tests {
         test_callback(test_avfork_cd_exec_callback);
     }
 
+    fn test_avfork_sigmask_callback(_fd: Fd, _old_sigset: &mut sigset_t) -> c_int {
+        use crate::syscall::*;
+        use crate::{CStrArray, errx};
+        use crate::utility::unwrap;
+
+        let mut set = sigemptyset();
+        sigaddset(&mut set, Signal::SIGUSR1);
+        unwrap(sigprocmask(SigprocmaskHow::SIG_SETMASK, Some(&set)));
+
+        // SIGUSR1 is 10, thus it is bit 9 in SigBlk
+        let err = execve(
+            &cstr!("/bin/sh"),
+            &CStrArray!(
+                "/bin/sh", "-c",
+                "grep -q '^SigBlk:[[:space:]]*0*200$' /proc/self/status"
+            ),
+            &CStrArray!("A=B")
+        );
+        errx!(1, "execve failed: {}", err);
+    }
+
+    #[test]
+    fn test_avfork_sigmask() {
+        use crate::syscall::{Signal, sigemptyset, sigaddset, sigismember};
+
+        let mut set = sigemptyset();
+        sigaddset(&mut set, Signal::SIGUSR2);
+        let orig = sigprocmask(SigprocmaskHow::SIG_BLOCK, Some(&set)).unwrap();
+
+        let mut stack = Stack::new();
+        let allocator = stack.reserve(4096 * 100, 100).unwrap();
+        let f = match allocator.alloc_obj(test_avfork_sigmask_callback) {
+            Ok(f) => f,
+            Err(_) => panic!("allocation failed"),
+        };
+
+        let (fd, pid) = avfork(&allocator, f.pin()).unwrap();
+
+        let mut buf = [1 as u8; 1];
+        assert_eq!(fd.read(&mut buf).unwrap(), 0);
+
+        let mut status: c_int = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        let mask = sigprocmask(SigprocmaskHow::SIG_SETMASK, None).unwrap();
+        assert!(sigismember(&mask, Signal::SIGUSR2));
+        assert!(!sigismember(&mask, Signal::SIGUSR1));
+        assert!(!sigismember(&mask, Signal::SIGTERM));
+
+        sigprocmask(SigprocmaskHow::SIG_SETMASK, Some(&orig)).unwrap();
+    }
+
+    #[test]
+    fn test_avfork_with_stack() {
+        #[repr(C, align(16))]
+        struct AlignedStack([u8; MIN_AVFORK_STACK_SZ]);
+
+        let mut stack = Box::new(AlignedStack([0; MIN_AVFORK_STACK_SZ]));
+
+        let f = dummy_avfork_callback;
+        assert_matches!(
+            avfork_with_stack(&mut stack.0[..4096], Pin::new(&f)),
+            Err(err) if err.get_errno() == libc::EINVAL
+        );
+        assert_matches!(
+            avfork_with_stack(&mut stack.0[1..], Pin::new(&f)),
+            Err(err) if err.get_errno() == libc::EINVAL
+        );
+
+        let f = test_avfork_exec_callback;
+        let (fd, pid) = avfork_with_stack(&mut stack.0, Pin::new(&f)).unwrap();
+
+        let mut buf = [1 as u8; 1];
+        assert_eq!(fd.read(&mut buf).unwrap(), 0);
+
+        // Exits with 0 only if execve on the stack provided succeeded.
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
     //fn dummy_avfork_rec_callback(fd: Fd, old_sigset: &mut sigset_t) -> c_int {
     //    let mut stack = Stack::new();
 
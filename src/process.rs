@@ -1,14 +1,3857 @@
 pub use std::ffi::CStr;
 
+use std::fmt;
+use std::mem;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::iter::once;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::convert::{Infallible, TryInto};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::{CString, NulError};
+use std::time::{Duration, Instant};
+use std::os::raw::{c_int, c_char};
+use std::os::unix::ffi::OsStrExt;
+
 use crate::lowlevel;
 use crate::syscall;
 use crate::error;
 use crate::utility;
 use crate::StacksQueue;
+use crate::autorestart;
+
+use once_cell::sync::OnceCell;
 
-use lowlevel::{Stack, StackObjectAllocator};
+use lowlevel::{StackObjectAllocator, avfork};
+use syscall::{Fd, FdBox, sigset_t, pid_t, uid_t, gid_t, CStrArray, SigprocmaskHow, CloneFlags};
+use syscall::{sigprocmask, execve, pidfd_open, chroot, chdir, setns, PATH_MAX};
+use syscall::{execvel, ExecvelCandidate, Filename, execveat, ExecveAtFlags};
+use syscall::{FdPathBox, FdPathMode};
+use syscall::{kill, tgkill, getpid, gettid, pidfd_send_signal, Signal, FdBasicOp, FromRaw};
+use syscall::{AccessMode, FdFlags, FdPath, Dirents};
+use syscall::{faccessat2, AccessCheck, FaccessFlags};
+use syscall::{EpollReactor, EpollEvent, EpollEvents};
+use syscall::{setgroups, setresgid, setresuid, setsid, setpgid};
+use syscall::{prlimit, PrlimitResource, unshare, mount, MountFlags, dev_null};
+use syscall::{MemfdFlags, SealFlags};
+use syscall::{statx, StatxMask, Mode};
+use syscall::{redirect_stdio, signal_ignore, signal_default, ppoll};
+use syscall::{sigemptyset, sched_setscheduler, SchedPolicy};
+use syscall::{write_uid_map, write_gid_map, deny_setgroups};
+#[cfg(debug_assertions)]
+use syscall::{set_sigaltstack, get_sigaltstack, sigaction_on_altstack, sigaddset, get_pagesz};
+#[cfg(debug_assertions)]
+use std::ops::Range;
+#[cfg(debug_assertions)]
+use std::os::raw::c_void;
 
 pub use error::SyscallError;
+use error::to_syscall_error;
 pub use utility::{expect, unwrap};
-pub use syscall::{AT_FDCWD, STDOUT, STDERR};
+pub use syscall::{AT_FDCWD, STDIN, STDOUT, STDERR};
+pub use crate::SignalFd::{BlockingSigChld, ExitInfo, WaitStatus};
+#[cfg(feature = "async")]
+pub use crate::SignalFd::SigChldFd;
+#[cfg(feature = "async")]
+pub use crate::AsyncFd::AsyncFdBox;
+#[cfg(feature = "async")]
+pub use tokio::io::Interest;
+
+use crate::SignalFd::waitid;
+
+#[cfg(feature = "async")]
+use tokio::io::unix::AsyncFd;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::future::Future;
+
+/// Used if `$PATH` is not set, same as glibc.
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// `$PATH` of the parent, or `DEFAULT_PATH` if it is not set.
+///
+/// Entries that are not valid UTF-8 are skipped, since `ExecvelCandidate` only
+/// takes `str`, instead of falling back to `DEFAULT_PATH` for the whole `$PATH`.
+fn get_path_env() -> String {
+    let path_env = match std::env::var_os("PATH") {
+        Some(path_env) => path_env,
+        None => return DEFAULT_PATH.to_owned(),
+    };
+
+    path_env
+        .as_bytes()
+        .split(|byte| *byte == b':')
+        .filter_map(|path| std::str::from_utf8(path).ok())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Name of the environment variable containing the fd number of the write end
+/// of the readiness pipe created by `Command::readiness_fd`.
+pub const READY_FD_ENV: &str = "AVFORK_READY_FD";
+
+/// Size of the object arena reserved for the callback, which only captures
+/// a few references.
+///
+/// Checked at compile time by `CallbackFits`.
+const CALLBACK_OBJ_SZ: usize = 256;
+
+/// Fails to compile if the callback `Func` does not fit in `CALLBACK_OBJ_SZ`.
+struct CallbackFits<Func>(PhantomData<Func>);
+impl<Func> CallbackFits<Func> {
+    const OK: () = assert!(
+        mem::size_of::<Func>() <= CALLBACK_OBJ_SZ,
+        "CALLBACK_OBJ_SZ is too small for the callback"
+    );
+}
+
+/// Evaluate `CallbackFits` for the type of `callback`, which might be unnamable.
+fn check_callback_fits<Func>(_callback: &Func) {
+    #[allow(clippy::let_unit_value)]
+    let () = CallbackFits::<Func>::OK;
+}
+
+/// Size of the stack reserved for the callback running in the child.
+///
+/// `execvel` alone would put `PATH_MAX` bytes on the stack, and in debug builds
+/// `catch_stack_overflow` puts the alternate signal stack on it as well.
+const STACK_SZ: usize =
+    PATH_MAX + 16 * 4096 + if cfg!(debug_assertions) { ALT_STACK_SZ } else { 0 };
+
+/// The step in the child during which the spawn failed.
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpawnStage {
+    Chroot = 1,
+    Chdir,
+    SetGroups,
+    SetGid,
+    SetUid,
+    DupStdio,
+    Rlimit,
+    /// Any other step before `execve`, e.g. `setns` and `setsid`.
+    PreExec,
+    Exec,
+    /// `unshare(CLONE_NEWUSER)` or waiting for the parent to write the id maps.
+    UserNs,
+    /// Writing to `/proc/self/oom_score_adj`.
+    OomScoreAdj,
+    /// Waiting for the parent to move the child into `Command::cgroup`.
+    Cgroup,
+    /// `Command::mount_proc_at`.
+    MountProc,
+    /// The child is not pid 1 of a new pid namespace, which is required by
+    /// `Command::mount_proc_at`.
+    PidNs,
+}
+impl SpawnStage {
+    const ALL: [SpawnStage; 14] = [
+        SpawnStage::Chroot,
+        SpawnStage::Chdir,
+        SpawnStage::SetGroups,
+        SpawnStage::SetGid,
+        SpawnStage::SetUid,
+        SpawnStage::DupStdio,
+        SpawnStage::Rlimit,
+        SpawnStage::PreExec,
+        SpawnStage::Exec,
+        SpawnStage::UserNs,
+        SpawnStage::OomScoreAdj,
+        SpawnStage::Cgroup,
+        SpawnStage::MountProc,
+        SpawnStage::PidNs,
+    ];
+
+    fn from_raw(stage: c_int) -> Option<SpawnStage> {
+        SpawnStage::ALL.iter().copied().find(|each| *each as c_int == stage)
+    }
+}
+
+/// Marker for types that can be passed to the callback of `Command::pre_exec`,
+/// which runs in the child sharing memory with the parent.
+///
+/// # Safety
+///
+/// Using the value in the child must not allocate, free or lock, e.g. it must
+/// not contain any `Mutex` or `RefCell` that can reach heap memory.
+///
+/// It is implemented for primitives and fd types, and can be implemented for
+/// user-defined `Copy` types whose fields are all `AsyncSignalSafe`:
+///
+/// ```ignore
+/// #[derive(Copy, Clone)]
+/// struct State { fd: Fd, uid: uid_t }
+/// unsafe impl AsyncSignalSafe for State {}
+/// ```
+pub unsafe trait AsyncSignalSafe: Copy + Send + Sync {}
+
+macro_rules! impl_AsyncSignalSafe {
+    ( $( $type:ty ),* ) => {
+        $( unsafe impl AsyncSignalSafe for $type {} )*
+    };
+}
+impl_AsyncSignalSafe!(
+    (), bool, char, f32, f64,
+    i8, i16, i32, i64, i128, isize,
+    u8, u16, u32, u64, u128, usize,
+    Fd, FdPath, Signal, &'static CStr
+);
+unsafe impl<T: AsyncSignalSafe> AsyncSignalSafe for Option<T> {}
+unsafe impl<T: AsyncSignalSafe, const N: usize> AsyncSignalSafe for [T; N] {}
+unsafe impl<T: AsyncSignalSafe> AsyncSignalSafe for &'static [T] {}
+unsafe impl<A: AsyncSignalSafe, B: AsyncSignalSafe> AsyncSignalSafe for (A, B) {}
+unsafe impl<A, B, C> AsyncSignalSafe for (A, B, C)
+    where A: AsyncSignalSafe, B: AsyncSignalSafe, C: AsyncSignalSafe {}
+
+/// Callback added via `Command::pre_exec`.
+struct PreExecHook(Box<dyn Fn() -> Result<(), SyscallError> + Send + Sync>);
+impl fmt::Debug for PreExecHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreExecHook")
+    }
+}
+
+/// Callback added via `Command::stop_on_exec`.
+struct StopHook(Box<dyn Fn(pid_t) -> Result<(), SyscallError> + Send + Sync>);
+impl fmt::Debug for StopHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StopHook")
+    }
+}
+
+/// Returns a closure for `map_err` that tags the error with `stage`.
+fn at(stage: SpawnStage) -> impl Fn(SyscallError) -> (SpawnStage, SyscallError) {
+    move |err| (stage, err)
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// Failed in the parent.
+    Syscall(SyscallError),
+    /// Failed in the child at `stage`.
+    Child { stage: SpawnStage, err: SyscallError },
+    /// `chroot` is set, but `current_dir` is not an absolute path or it contains
+    /// `..`, or `current_dir_fd` is set, which might escape the new root.
+    CwdEscapesChroot,
+    /// The `SigChldFd` is shut down before the child terminates.
+    ReaperShutdown,
+    /// The child does not terminate within `Command::timeout`, thus it is
+    /// killed by `SIGKILL` and reaped, with its pid and exit info returned.
+    ///
+    /// The pid is only for correlating logs and audit records: since the child
+    /// is already reaped, it might be reused by another process.
+    TimedOut { pid: pid_t, info: ExitInfo },
+    /// A `&str` passed to `Command::arg_str` or `Command::env_str` contains
+    /// an interior nul byte.
+    InteriorNul(NulError),
+    /// A key passed to `Command::env_keep` contains `=`.
+    InvalidEnvKey(String),
+    /// The value passed to `Command::oom_score_adj` is not in -1000..=1000.
+    OomScoreAdjOutOfRange(i32),
+    /// The executable cannot be found, either at `path` or in any directory
+    /// of `$PATH`.
+    ///
+    /// Only returned by `Command::validate`.
+    ProgramNotFound(SyscallError),
+    /// The fd passed via `Command::keep_fd` is one of stdin, stdout or stderr,
+    /// which is overwritten when redirecting stdio.
+    StdioFdConflict(c_int),
+    /// `Command::ld_preload` is set, but the executable is setuid or setgid,
+    /// thus `LD_PRELOAD` is likely ignored by the dynamic loader.
+    PreloadIgnored,
+    /// stdout or stderr is `Stdio::Piped` in `spawn_and_wait`, which nobody
+    /// reads, so the child would block forever once the pipe is full.
+    UnreadPipe,
+    /// Both `Command::groups` and `Command::unshare_user` are set, but
+    /// `setgroups` is denied in the new user namespace.
+    GroupsInUserNs,
+    /// Only detected in debug builds.
+    ///
+    /// The child is killed by `SIGSEGV` at an address within or right below
+    /// the stack of the given size in bytes it runs on, i.e. the callback,
+    /// e.g. a `Command::pre_exec` hook, likely overflowed its stack.
+    StackOverflow(usize),
+}
+impl From<SyscallError> for SpawnError {
+    fn from(err: SyscallError) -> Self {
+        SpawnError::Syscall(err)
+    }
+}
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::Syscall(err) => write!(f, "{}", err),
+            SpawnError::Child { stage, err } =>
+                write!(f, "child failed at {:?}: {}", stage, err),
+            SpawnError::CwdEscapesChroot =>
+                write!(f, "current_dir might escape chroot"),
+            SpawnError::ReaperShutdown =>
+                write!(f, "SigChldFd is shut down before the child terminates"),
+            SpawnError::TimedOut { pid, info } =>
+                write!(f, "child {} timed out and is killed: {:?}", pid, info.status()),
+            SpawnError::InteriorNul(err) => write!(f, "{}", err),
+            SpawnError::InvalidEnvKey(key) =>
+                write!(f, "environment variable key {:?} contains '='", key),
+            SpawnError::OomScoreAdjOutOfRange(score) =>
+                write!(f, "oom_score_adj {} is not in -1000..=1000", score),
+            SpawnError::ProgramNotFound(err) =>
+                write!(f, "executable cannot be found: {}", err),
+            SpawnError::StdioFdConflict(fd) =>
+                write!(f, "fd {} is overwritten when redirecting stdio", fd),
+            SpawnError::PreloadIgnored =>
+                write!(f, "executable is setuid or setgid, LD_PRELOAD is ignored"),
+            SpawnError::UnreadPipe =>
+                write!(f, "stdout or stderr is piped but never read"),
+            SpawnError::GroupsInUserNs =>
+                write!(f, "groups cannot be set since setgroups is denied by unshare_user"),
+            SpawnError::StackOverflow(stack_sz) =>
+                write!(f, "callback likely overflowed its reserved stack of {} bytes", stack_sz),
+        }
+    }
+}
+
+/// Configuration of stdin, stdout or stderr of the child.
+#[derive(Debug)]
+pub enum Stdio {
+    /// Inherit from the parent.
+    Inherit,
+    /// Redirect to `/dev/null`.
+    Null,
+    /// Create a pipe connected to the child, whose parent end is available
+    /// via `Child::take_stdin`, `Child::take_stdout` or `Child::take_stderr`.
+    Piped,
+    /// Redirect to `fd`, which is duplicated in the child, so the same
+    /// `Command` can still be spawned multiple times.
+    ///
+    /// `fd` can be any fd including stdio, e.g. stdout can be `Stdio::Fd` of
+    /// fd 2 while stderr is `Stdio::Fd` of fd 1, since stdio is redirected
+    /// via `redirect_stdio` as if it were done simultaneously.
+    Fd(FdBox),
+}
+
+/// A builder for spawning child process using `avfork`.
+///
+/// All the heap allocations are done in the parent before `avfork` is called,
+/// the callback running in the child only uses syscalls from module `syscall`.
+///
+/// In the child, the settings are applied in the following order:
+///  1. `cgroup`, the child waits for the parent to move it into the cgroup.
+///  2. `unshare_user`
+///  3. `netns`
+///  4. `oom_score_adj`, which needs `/proc` of the original root.
+///  5. `mount_proc_at`
+///  6. `chroot`
+///  7. `current_dir`, which is resolved relative to the new root, or
+///     `current_dir_fd`.
+///     If `chroot` is set but `current_dir` is not, then the child chdir to `/`
+///     of the new root.
+///  8. `reset_rlimits_except`
+///  9. `groups`
+///  10. `gid`
+///  11. `uid`
+///  12. `max_open_files` and `cpu_time_limit`
+///  13. `stdin` or `stdin_memfd`, `stdout`, `stderr` or `stderr_to_stdout`,
+///      `log_to` and `keep_fd`
+///  14. `new_process_group` or `setsid`
+///  15. `inherit_controlling_tty`
+///  16. `reset_thread_attributes`
+///  17. `pre_exec`, in the order they are added
+///  18. `signal_mask`
+///  19. `readiness_fd`
+///  20. `stop_on_exec`
+///
+/// `SIGPIPE` is ignored before all of them and restored to `SIG_DFL` right
+/// before `pre_exec`, unless `inherit_sigpipe` is set.
+///
+/// Thus the child can still chroot and chdir to directories that are only
+/// accessible by the original credentials.
+#[derive(Debug)]
+pub struct Command {
+    path: CString,
+    search_path: bool,
+    /// `O_PATH` fd of `/proc/self/exe` opened by `reexec_self`
+    exe: Option<FdPathBox>,
+    args: Vec<CString>,
+    /// Entries in the form of `KEY=VALUE`
+    envs: Vec<CString>,
+    env_clear: bool,
+    /// Keys of environment variables inherited despite `env_clear`
+    env_keep: Vec<String>,
+    /// The first error from `arg_str` or `env_str`, returned on spawn.
+    nul_error: Option<NulError>,
+    /// The first key containing `=` passed to `env_keep`, returned on spawn.
+    invalid_env_key: Option<String>,
+    /// Whether `LD_PRELOAD` is set via `ld_preload`
+    ld_preload: bool,
+
+    cgroup: Option<FdBox>,
+    user_ns: Option<IdMaps>,
+    netns: Option<FdBox>,
+    chroot: Option<CString>,
+    current_dir: Option<CString>,
+    current_dir_fd: Option<FdPathBox>,
+    groups: Option<Vec<gid_t>>,
+    gid: Option<gid_t>,
+    uid: Option<uid_t>,
+
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+    stderr_to_stdout: bool,
+    /// Written to stdin of the child by `output` and `spawn_and_wait(_async)`
+    stdin_bytes: Option<Vec<u8>>,
+    /// Copied into a sealed memfd used as stdin on every spawn
+    stdin_memfd: Option<Vec<u8>>,
+    keep_fds: Vec<FdBox>,
+
+    timeout: Option<Duration>,
+
+    max_open_files: Option<u64>,
+    cpu_time_limit: Option<Duration>,
+    /// Resources whose limits are kept when resetting the others
+    reset_rlimits_except: Option<Vec<PrlimitResource>>,
+    oom_score_adj: Option<i32>,
+    mount_proc_at: Option<CString>,
+    new_process_group: bool,
+    setsid: bool,
+    inherit_controlling_tty: bool,
+    inherit_sigpipe: bool,
+    pre_exec: Vec<PreExecHook>,
+    signal_mask: Option<sigset_t>,
+    readiness_fd: bool,
+    stop_on_exec: Option<StopHook>,
+    reset_thread_attributes: bool,
+}
+impl Command {
+    /// * `path` - path to the executable, which is also used as `argv[0]`.
+    ///
+    /// If `path` contains a slash anywhere, e.g. `/bin/ls`, `bin/ls` or
+    /// `./ls`, then it is executed directly via `execve`, otherwise it is
+    /// searched in the directories of `$PATH` of the parent like `execvp`,
+    /// unless `search_path(false)` is set.
+    ///
+    /// By default, the child inherits the environment of the parent.
+    pub fn new(path: &CStr) -> Command {
+        Command {
+            path: path.to_owned(),
+            search_path: true,
+            exe: None,
+            args: vec![path.to_owned()],
+            envs: Vec::new(),
+            env_clear: false,
+            env_keep: Vec::new(),
+            ld_preload: false,
+            nul_error: None,
+            invalid_env_key: None,
+
+            cgroup: None,
+            user_ns: None,
+            netns: None,
+            chroot: None,
+            current_dir: None,
+            current_dir_fd: None,
+            groups: None,
+            gid: None,
+            uid: None,
+
+            stdin: Stdio::Inherit,
+            stdout: Stdio::Inherit,
+            stderr: Stdio::Inherit,
+            stderr_to_stdout: false,
+            stdin_bytes: None,
+            stdin_memfd: None,
+            keep_fds: Vec::new(),
+
+            timeout: None,
+
+            max_open_files: None,
+            cpu_time_limit: None,
+            reset_rlimits_except: None,
+            oom_score_adj: None,
+            mount_proc_at: None,
+            new_process_group: false,
+            setsid: false,
+            inherit_controlling_tty: false,
+            inherit_sigpipe: false,
+            pre_exec: Vec::new(),
+            signal_mask: None,
+            readiness_fd: false,
+            stop_on_exec: None,
+            reset_thread_attributes: false,
+        }
+    }
+
+    /// Spawn the executable of the current process again, e.g. a supervisor
+    /// re-executing itself.
+    ///
+    /// `/proc/self/exe` is opened with `O_PATH` now in the parent and the child
+    /// executes the fd via `execveat(fd, "", ..., AT_EMPTY_PATH)`, so it is not
+    /// affected by `chroot` or `current_dir`.
+    ///
+    /// The fd refers to the image that is currently running, even if the file
+    /// is deleted or replaced on disk since it was executed, thus to upgrade to
+    /// a new on-disk image, use `new` with its path instead.
+    /// Either way, fds passed via `keep_fd`, e.g. a listening socket, are
+    /// preserved in the new process.
+    ///
+    /// `argv[0]` is the same as the current process.
+    pub fn reexec_self() -> Result<Command, SyscallError> {
+        let path = cstr!("/proc/self/exe");
+        let exe = FdPathBox::openat(AT_FDCWD, path, FdPathMode::anyPath, true)?;
+
+        let mut command = Command::new(path);
+        command.search_path = false;
+        command.exe = Some(exe);
+        if let Some(arg0) = std::env::args_os().next() {
+            if let Ok(arg0) = CString::new(arg0.as_bytes()) {
+                command.args[0] = arg0;
+            }
+        }
+
+        Ok(command)
+    }
+
+    /// Whether to search `path` passed to `new` in `$PATH` if it does not
+    /// contain any slash, defaults to true.
+    ///
+    /// If set to false, then `path` is always executed directly via `execve`,
+    /// thus a path without slash is resolved relative to `current_dir`.
+    ///
+    /// A path containing slash, including a leading `./`, is never searched.
+    pub fn search_path(&mut self, search_path: bool) -> &mut Self {
+        self.search_path = search_path;
+        self
+    }
+
+    pub fn arg(&mut self, arg: &CStr) -> &mut Self {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    /// Add or overwrite environment variable `key`.
+    pub fn env(&mut self, key: &CStr, val: &CStr) -> &mut Self {
+        let key = key.to_bytes();
+        let val = val.to_bytes();
+
+        let mut entry = Vec::with_capacity(key.len() + 1 + val.len());
+        entry.extend_from_slice(key);
+        entry.push(b'=');
+        entry.extend_from_slice(val);
+
+        // Neither key nor val contains any nul byte
+        self.envs.push(unsafe { CString::from_vec_unchecked(entry) });
+        self
+    }
+
+    /// Same as `arg`, except that `arg` is checked for interior nul byte.
+    ///
+    /// If it contains any, then `SpawnError::InteriorNul` is returned on spawn.
+    pub fn arg_str(&mut self, arg: &str) -> &mut Self {
+        match CString::new(arg) {
+            Ok(arg) => self.args.push(arg),
+            Err(err) => self.set_nul_error(err),
+        }
+        self
+    }
+
+    /// Same as `env`, except that `key` and `val` are checked for interior nul
+    /// byte.
+    ///
+    /// If any of them contains one, then `SpawnError::InteriorNul` is returned
+    /// on spawn.
+    pub fn env_str(&mut self, key: &str, val: &str) -> &mut Self {
+        let key = match CString::new(key) {
+            Ok(key) => key,
+            Err(err) => {
+                self.set_nul_error(err);
+                return self
+            },
+        };
+        match CString::new(val) {
+            Ok(val) => self.env(&key, &val),
+            Err(err) => {
+                self.set_nul_error(err);
+                self
+            },
+        }
+    }
+
+    /// Only the first error is kept.
+    fn set_nul_error(&mut self, err: NulError) {
+        if self.nul_error.is_none() {
+            self.nul_error = Some(err);
+        }
+    }
+
+    /// Do not inherit any environment variable from the parent, except for
+    /// those passed to `env_keep`.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_clear = true;
+        self
+    }
+
+    /// Inherit environment variables `keys` from the parent even if
+    /// `env_clear` is set, e.g. `env_clear().env_keep(&["TZ", "LANG"])`.
+    ///
+    /// The values are read from the environment of the parent on spawn, and
+    /// the keys that are not present in the parent are omitted.
+    ///
+    /// Variables set via `env` take precedence.
+    ///
+    /// If any of `keys` contains a nul byte, then `SpawnError::InteriorNul` is
+    /// returned on spawn, and if it contains `=`, which can never be part of a
+    /// key, then `SpawnError::InvalidEnvKey` is returned on spawn.
+    pub fn env_keep(&mut self, keys: &[&str]) -> &mut Self {
+        for key in keys {
+            if let Err(err) = CString::new(*key) {
+                self.set_nul_error(err);
+            } else if key.contains('=') && self.invalid_env_key.is_none() {
+                self.invalid_env_key = Some((*key).to_owned());
+            }
+
+            self.env_keep.push((*key).to_owned());
+        }
+        self
+    }
+
+    /// Set environment variable `LD_PRELOAD` to `libs`, and check on spawn that
+    /// the executable is not setuid or setgid, in which case the dynamic
+    /// loader ignores `LD_PRELOAD` for libraries not in the standard search
+    /// directories and `SpawnError::PreloadIgnored` is returned.
+    ///
+    /// Only the executable itself is checked, not the interpreter of a script
+    /// executed via shebang, and the check is skipped if `chroot` is set or if
+    /// the executable is a relative path resolved after `current_dir`.
+    ///
+    /// Ultimately, it is `AT_SECURE` in the auxiliary vector of the child that
+    /// decides whether the loader runs in secure-execution mode, which also
+    /// depends on file capabilities, LSMs and whether the filesystem is mounted
+    /// with `nosuid`, so this check is neither sufficient nor necessary.
+    pub fn ld_preload(&mut self, libs: &CStr) -> &mut Self {
+        self.ld_preload = true;
+        self.env(cstr!("LD_PRELOAD"), libs)
+    }
+
+    /// Place the child in the cgroup v2 directory referred to by `fd`.
+    ///
+    /// The race-free way is `clone3` with `CLONE_INTO_CGROUP` (Linux 5.7+),
+    /// which creates the child directly in the cgroup, but the child is
+    /// created by `avfork` of aspawn, which does not support it.
+    ///
+    /// Instead, the parent writes the pid of the child to `cgroup.procs` in
+    /// `fd` right after the fork, while the child blocks on a pipe before
+    /// applying any other setting, so that the child does nothing but waiting
+    /// while it is still in the cgroup of the parent.
+    ///
+    /// If the parent fails to write `cgroup.procs`, e.g. `EACCES`, then the
+    /// error is returned as `SpawnError::Syscall` and the child exits.
+    pub fn cgroup(&mut self, fd: FdBox) -> &mut Self {
+        self.cgroup = Some(fd);
+        self
+    }
+
+    /// Run the child in a new user namespace with `uid_map` and `gid_map`,
+    /// each entry of which is (first id inside the namespace, first id outside,
+    /// length of the range).
+    ///
+    /// An unprivileged process cannot write the id maps of its own user
+    /// namespace (except for mapping itself), so the two-process dance that
+    /// rootless containers require is done:
+    ///  1. the child calls `unshare(CLONE_NEWUSER)` and notifies the parent
+    ///     via a pipe,
+    ///  2. the parent writes "deny" to `/proc/[pid]/setgroups`, then writes
+    ///     `uid_map` and `gid_map`, and notifies the child via another pipe,
+    ///  3. the child continues with the rest of the settings and `execve`.
+    ///
+    /// If either side fails or exits, the other side observes EOF on the pipe
+    /// instead of blocking forever.
+    ///
+    /// Since setgroups is denied, `groups` cannot be used with it, otherwise
+    /// `SpawnError::GroupsInUserNs` is returned.
+    ///
+    /// Without privilege in the parent user namespace, the maps can only map
+    /// the euid and egid of the parent.
+    pub fn unshare_user(&mut self, uid_map: &[(u32, u32, u32)], gid_map: &[(u32, u32, u32)])
+        -> &mut Self
+    {
+        self.user_ns = Some(IdMaps {
+            uid_map: uid_map.to_vec(),
+            gid_map: gid_map.to_vec(),
+        });
+        self
+    }
+
+    /// Join the network namespace referred to by `fd` in the child, like
+    /// `nsenter --net`.
+    ///
+    /// `setns` is called in the callback before `execve`, so `fd` is usable
+    /// even if it is `O_CLOEXEC`, in which case it is not leaked to the program
+    /// executed.
+    ///
+    /// To join multiple namespaces, the user namespace must be joined before
+    /// the others.
+    pub fn netns(&mut self, fd: FdBox) -> &mut Self {
+        self.netns = Some(fd);
+        self
+    }
+
+    /// Let the program executed inherit `fd` with the same fd number by
+    /// clearing `FD_CLOEXEC` on it in the child.
+    ///
+    /// `fd` must not be 0, 1 or 2, otherwise it might be replaced by stdio of
+    /// the child.
+    ///
+    /// In debug builds, any fd other than stdio and the ones passed to
+    /// `keep_fd` that is not `O_CLOEXEC` is reported to stderr of the parent
+    /// on spawn as it is leaked into the program executed.
+    pub fn keep_fd(&mut self, fd: FdBox) -> &mut Self {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    /// Change the root directory of the child to `dir`.
+    ///
+    /// If this is set, then `current_dir` must be an absolute path without any
+    /// `..` component, otherwise `SpawnError::CwdEscapesChroot` is returned.
+    pub fn chroot(&mut self, dir: &CStr) -> &mut Self {
+        self.chroot = Some(dir.to_owned());
+        self
+    }
+
+    /// Change the working directory of the child to `dir`.
+    ///
+    /// If `chroot` is set, then `dir` is resolved relative to the new root.
+    ///
+    /// Overrides `current_dir_fd`.
+    pub fn current_dir(&mut self, dir: &CStr) -> &mut Self {
+        self.current_dir = Some(dir.to_owned());
+        self.current_dir_fd = None;
+        self
+    }
+
+    /// Change the working directory of the child to the directory referred to
+    /// by `dir` via `fchdir`, so that it cannot be swapped between opening
+    /// and changing directory like a path.
+    ///
+    /// `dir` can be opened with `O_CLOEXEC`, since `fchdir` is called before
+    /// `execve`, so it is not leaked into the program executed.
+    ///
+    /// The fd is not resolved again after `chroot`, thus a directory outside of
+    /// the new root would escape it, so setting both `chroot` and this
+    /// returns `SpawnError::CwdEscapesChroot`.
+    ///
+    /// Overrides `current_dir`.
+    pub fn current_dir_fd(&mut self, dir: FdPathBox) -> &mut Self {
+        self.current_dir_fd = Some(dir);
+        self.current_dir = None;
+        self
+    }
+
+    /// Set the supplementary groups of the child.
+    ///
+    /// The groups of a user can be obtained via `supplementary_groups_for`.
+    ///
+    /// Cannot be used with `unshare_user`, otherwise
+    /// `SpawnError::GroupsInUserNs` is returned.
+    pub fn groups(&mut self, groups: &[gid_t]) -> &mut Self {
+        self.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Set the real, effective and saved gid of the child.
+    pub fn gid(&mut self, gid: gid_t) -> &mut Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the real, effective and saved uid of the child.
+    pub fn uid(&mut self, uid: uid_t) -> &mut Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Overrides `stdin_bytes` and `stdin_memfd`.
+    pub fn stdin(&mut self, stdin: Stdio) -> &mut Self {
+        self.stdin = stdin;
+        self.stdin_bytes = None;
+        self.stdin_memfd = None;
+        self
+    }
+
+    /// Set stdin to `Stdio::Piped` and feed `bytes` to it, after which the
+    /// write end is closed to signal EOF.
+    ///
+    /// `bytes` is only written by `output`, `spawn_and_wait` and
+    /// `spawn_and_wait_async`, concurrently with the capture of output and the
+    /// wait for the child, so that they never deadlock.
+    /// With `spawn` or `spawn_with_pidfd`, the caller has to write it via
+    /// `Child::take_stdin`.
+    ///
+    /// If the child exits without consuming all of `bytes`, then the rest is
+    /// discarded and it is not considered an error.
+    /// This relies on `SIGPIPE` being ignored in the parent, which is the
+    /// default for rust programs.
+    pub fn stdin_bytes(&mut self, bytes: Vec<u8>) -> &mut Self {
+        self.stdin = Stdio::Piped;
+        self.stdin_bytes = Some(bytes);
+        self.stdin_memfd = None;
+        self
+    }
+
+    /// Set stdin of the child to a memfd containing `data`, which is sealed
+    /// against writing and shrinking, with its offset at 0.
+    ///
+    /// Unlike `stdin_bytes`, nothing has to be written after spawning, so it
+    /// works with `spawn` as well and can never deadlock, and the child sees
+    /// a regular file that is seekable and never blocks.
+    ///
+    /// A new memfd is created for every spawn, so that children do not share
+    /// the offset. Since `data` is copied into memory for every spawn, a pipe
+    /// via `stdin_bytes` is more practical for large inputs, while this is
+    /// meant for small ones.
+    ///
+    /// Failure to create the memfd is reported as `SpawnError::Syscall`.
+    ///
+    /// Overrides `stdin` and `stdin_bytes`.
+    pub fn stdin_memfd(&mut self, data: &[u8]) -> &mut Self {
+        self.stdin = Stdio::Inherit;
+        self.stdin_bytes = None;
+        self.stdin_memfd = Some(data.to_vec());
+        self
+    }
+
+    fn get_stdin_bytes(&self) -> &[u8] {
+        self.stdin_bytes.as_deref().unwrap_or(&[])
+    }
+
+    pub fn stdout(&mut self, stdout: Stdio) -> &mut Self {
+        self.stdout = stdout;
+        self
+    }
+
+    /// Overrides `stderr_to_stdout`.
+    pub fn stderr(&mut self, stderr: Stdio) -> &mut Self {
+        self.stderr = stderr;
+        self.stderr_to_stdout = false;
+        self
+    }
+
+    /// Redirect stderr of the child to its stdout, like `2>&1` in shell, so
+    /// that both are written in order to the same target, e.g. one pipe.
+    ///
+    /// In the child, fd 2 is duplicated from the same source as fd 1, thus it
+    /// works with any `stdout` configuration.
+    ///
+    /// It overrides the previous `stderr` and is overridden by the next one.
+    ///
+    /// `Output::stderr` collected by `Command::output` is empty since
+    /// everything is merged into `Output::stdout`.
+    pub fn stderr_to_stdout(&mut self) -> &mut Self {
+        self.stderr = Stdio::Inherit;
+        self.stderr_to_stdout = true;
+        self
+    }
+
+    /// Redirect both stdout and stderr of the child to `fd`, e.g. a log file,
+    /// same as `stdout(Stdio::Fd(fd))` followed by `stderr_to_stdout`.
+    ///
+    /// Fd 1 and 2 of the child share the open file description of `fd`, thus
+    /// share the same file offset, so stdout and stderr of the child never
+    /// overwrite each other.
+    /// However, other writers of the same file with their own open file
+    /// description, or an `lseek` on the shared one, would overwrite the output
+    /// unless `fd` is opened with `O_APPEND`.
+    ///
+    /// It is overridden by the next `stdout` or `stderr`.
+    pub fn log_to(&mut self, fd: FdBox) -> &mut Self {
+        self.stdout(Stdio::Fd(fd)).stderr_to_stdout()
+    }
+
+    /// Set the soft limit of `RLIMIT_NOFILE` of the child to `min(n, hard limit)`,
+    /// which requires no privilege.
+    ///
+    /// On failure, `SpawnError::Child` with `SpawnStage::Rlimit` is returned.
+    ///
+    /// Setting it below the number of fds currently open is fine, as the fds
+    /// already open are not affected, but the child cannot open new fds until
+    /// the number of fds open drops below the new limit.
+    pub fn max_open_files(&mut self, n: u64) -> &mut Self {
+        self.max_open_files = Some(n);
+        self
+    }
+
+    /// Set the soft limit of `RLIMIT_CPU` of the child to `min(limit, hard limit)`,
+    /// rounded up to seconds, which requires no privilege.
+    ///
+    /// Once the child consumes `limit` of CPU time, it receives `SIGXCPU`,
+    /// which terminates it by default, and then `SIGXCPU` every second until
+    /// it reaches the hard limit, where it is killed by `SIGKILL`.
+    /// Use `ExitInfo::exceeded_cpu_limit` to check for it after reaping.
+    ///
+    /// On failure, `SpawnError::Child` with `SpawnStage::Rlimit` is returned.
+    pub fn cpu_time_limit(&mut self, limit: Duration) -> &mut Self {
+        self.cpu_time_limit = Some(limit);
+        self
+    }
+
+    /// Reset the limits of all resources of the child, except for those in
+    /// `kept`, to the following conservative defaults of (soft, hard):
+    ///  - `RLIMIT_CORE`: (0, 0), so no core file is dumped
+    ///  - `RLIMIT_MEMLOCK`: (8 MiB, 8 MiB)
+    ///  - `RLIMIT_MSGQUEUE`: (800 KiB, 800 KiB)
+    ///  - `RLIMIT_NICE` and `RLIMIT_RTPRIO`: (0, 0)
+    ///  - `RLIMIT_NOFILE`: (1024, 524288)
+    ///  - `RLIMIT_STACK`: (8 MiB, unlimited)
+    ///  - the others are unlimited, since there is no sane default for them,
+    ///    e.g. a low `RLIMIT_CPU` or `RLIMIT_AS` kills or breaks any program,
+    ///    so use `cpu_time_limit` or `prlimit` in `pre_exec` with care instead.
+    ///
+    /// The defaults are capped by the hard limits inherited from the parent,
+    /// so the limits are never raised above them, which requires no privilege.
+    /// The soft limits might be raised up to the hard limits though, e.g.
+    /// a soft `RLIMIT_AS` lowered in the parent is reset.
+    ///
+    /// It is done before dropping privileges via `groups`, `gid` and `uid`,
+    /// and `max_open_files` and `cpu_time_limit` are applied afterwards, so
+    /// they are still capped by the reset hard limits.
+    ///
+    /// On failure, `SpawnError::Child` with `SpawnStage::Rlimit` is returned.
+    pub fn reset_rlimits_except(&mut self, kept: &[PrlimitResource]) -> &mut Self {
+        self.reset_rlimits_except = Some(kept.to_vec());
+        self
+    }
+
+    /// Set the OOM score adjustment of the child by writing `score` to
+    /// `/proc/self/oom_score_adj`, where a higher score makes the child more
+    /// likely to be killed by the OOM killer.
+    ///
+    /// `score` must be in -1000..=1000, otherwise
+    /// `SpawnError::OomScoreAdjOutOfRange` is returned on spawn.
+    ///
+    /// Lowering it below the value inherited from the parent requires
+    /// `CAP_SYS_RESOURCE`, otherwise `SpawnError::Child` with
+    /// `SpawnStage::OomScoreAdj` and `EACCES` is returned.
+    pub fn oom_score_adj(&mut self, score: i32) -> &mut Self {
+        self.oom_score_adj = Some(score);
+        self
+    }
+
+    /// Mount a fresh procfs at `path` in the child, so that tools like `ps`
+    /// see the view of the pid namespace of the child.
+    ///
+    /// The child first enters a new mount namespace via
+    /// `unshare(CLONE_NEWNS)` and makes all the mounts private recursively,
+    /// so that the new mount does not propagate back to the mount namespace
+    /// of the parent, then mounts procfs with `MS_NOSUID`, `MS_NODEV` and
+    /// `MS_NOEXEC`.
+    /// `path` is resolved before `chroot`.
+    ///
+    /// This requires `CAP_SYS_ADMIN` in the user namespace of the child, e.g.
+    /// via `unshare_user`.
+    ///
+    /// procfs reflects the pid namespace of the process mounting it, so the
+    /// child must already be pid 1 of a new pid namespace, which `avfork`
+    /// cannot create.
+    /// Otherwise, `SpawnError::Child` with `SpawnStage::PidNs` and `EINVAL`
+    /// is returned instead of mounting a procfs of the wrong pid namespace.
+    ///
+    /// On failure, `SpawnError::Child` with `SpawnStage::MountProc` is returned.
+    pub fn mount_proc_at(&mut self, path: &CStr) -> &mut Self {
+        self.mount_proc_at = Some(path.to_owned());
+        self
+    }
+
+    /// Make the child the leader of a new process group via `setpgid(0, 0)`,
+    /// so that on timeout, `SIGKILL` is sent to the whole process group via
+    /// `kill(-pgid, SIGKILL)`, including the descendants of the child.
+    ///
+    /// The pgid is available via `Child::get_pgid`.
+    ///
+    /// Descendants that escape the process group by calling `setsid` or
+    /// `setpgid` themselves are not killed.
+    pub fn new_process_group(&mut self) -> &mut Self {
+        self.new_process_group = true;
+        self
+    }
+
+    /// Run the child in a new session via `setsid`, which detaches it from the
+    /// controlling terminal of the parent.
+    ///
+    /// The child also becomes the leader of a new process group, same as
+    /// `new_process_group`.
+    pub fn setsid(&mut self) -> &mut Self {
+        self.setsid = true;
+        self
+    }
+
+    /// Make the terminal on stdin of the child its controlling terminal via
+    /// `ioctl(STDIN, TIOCSCTTY, 0)`, after `setsid` and the redirection of
+    /// stdin.
+    ///
+    /// Without it, a child that is put into a new session via `setsid` can
+    /// still use the terminal inherited, but it has no controlling terminal,
+    /// thus job control and signals like `SIGINT` from the terminal do not work.
+    ///
+    /// Only a session leader without a controlling terminal can acquire one,
+    /// so this requires `setsid` to be set as well, otherwise `EPERM` is
+    /// returned.
+    ///
+    /// The terminal is never stolen, so `EPERM` is also returned if it is
+    /// still the controlling terminal of another session, e.g. the session of
+    /// the parent.
+    /// Thus it is most useful with stdin redirected to the slave end of a pty
+    /// allocated by the parent via `Stdio::Fd`, which then becomes the
+    /// controlling terminal of the child.
+    pub fn inherit_controlling_tty(&mut self) -> &mut Self {
+        self.inherit_controlling_tty = true;
+        self
+    }
+
+    /// Leave the disposition of `SIGPIPE` of the child untouched, so that it
+    /// and the program executed inherit the one of the parent, e.g. `SIG_IGN`
+    /// for rust programs.
+    ///
+    /// By default, the child ignores `SIGPIPE` before applying any setting,
+    /// so that it is not killed by writing to a pipe whose reader has gone,
+    /// e.g. when synchronizing with the parent, which it cannot handle.
+    /// The ignore only protects the child until `pre_exec`: it is restored to
+    /// `SIG_DFL` right before `pre_exec`, since most programs, e.g. those using
+    /// glibc stdio, expect to be terminated by `SIGPIPE` instead of checking
+    /// `EPIPE` on every write.
+    /// Thus `pre_exec` can still set the disposition of `SIGPIPE` for the
+    /// program, e.g. via `syscall::signal_ignore`.
+    pub fn inherit_sigpipe(&mut self) -> &mut Self {
+        self.inherit_sigpipe = true;
+        self
+    }
+
+    /// Call `f(state)` in the child right before `execve`.
+    ///
+    /// If it fails, then `SpawnError::Child` with `SpawnStage::PreExec` is
+    /// returned.
+    ///
+    /// `f` runs in the child that shares memory with the parent, thus it must
+    /// not allocate, free or lock, e.g. creating or dropping a `String` or
+    /// `Vec`, which might deadlock or corrupt the heap of the parent.
+    /// It should only use functions in module `syscall`.
+    ///
+    /// To make such mistakes less likely, `f` must be `Copy`, which means it
+    /// cannot capture anything that owns heap memory, and `state` must be
+    /// `AsyncSignalSafe`.
+    pub fn pre_exec<S, F>(&mut self, state: S, f: F) -> &mut Self
+        where S: AsyncSignalSafe + 'static,
+              F: Fn(S) -> Result<(), SyscallError> + Copy + Send + Sync + 'static
+    {
+        self.pre_exec.push(PreExecHook(Box::new(move || f(state))));
+        self
+    }
+
+    /// Create a readiness pipe, so that the program can notify the parent
+    /// once it is initialized, e.g. listening on its socket.
+    ///
+    /// The write end is inherited by the program across `execve` and its fd
+    /// number is passed via the environment variable `READY_FD_ENV`, which
+    /// overrides any inherited or set via `env`.
+    /// The program signals its readiness by writing one byte to it, e.g. via
+    /// `Fd::signal_ready`.
+    ///
+    /// The parent waits for it via `Child::wait_ready` or the read end
+    /// available via `Child::take_readiness_fd`, which observes EOF without
+    /// any byte if the program exited or closed the write end before
+    /// signaling its readiness.
+    pub fn readiness_fd(&mut self) -> &mut Self {
+        self.readiness_fd = true;
+        self
+    }
+
+    /// Set the signal mask of the child to `mask` right before `execve`,
+    /// instead of the signal mask of the parent.
+    ///
+    /// The signal mask is preserved across `execve`, and most programs expect
+    /// to start with an empty mask, so blocking signals like `SIGTERM` or
+    /// `SIGCHLD` might break them, e.g. they might hang instead of terminating.
+    ///
+    /// The mask can be built via `sigemptyset` and `sigaddset` in module
+    /// `syscall`.
+    ///
+    /// While spawning, the parent blocks all signals and the child starts
+    /// with all signals blocked, then the child sets its mask to `mask` (or
+    /// the mask of the parent if unset) as the last step before `execve`,
+    /// after which the parent restores its own mask. The signal mask is per
+    /// thread, thus the mask set by the child never affects the parent.
+    pub fn signal_mask(&mut self, mask: sigset_t) -> &mut Self {
+        self.signal_mask = Some(mask);
+        self
+    }
+
+    /// Stop the child via `SIGSTOP` right before `execve`, so that a debugger
+    /// or profiler can attach to it before it runs any of the program.
+    ///
+    /// The child still runs on memory of the parent before `execve`, so the
+    /// spawn cannot return while it is stopped. Instead, once the child is
+    /// stopped, `on_stop` is called with its pid in the parent, then the
+    /// parent resumes it via `SIGCONT` and waits for `execve` as usual.
+    /// If `on_stop` fails, then the child is killed and the error is returned
+    /// as `SpawnError::Syscall`.
+    ///
+    /// The stop is observed via `waitid(WSTOPPED | WNOWAIT)`, so it is not
+    /// consumed and `on_stop` can still observe it via `waitid`, e.g. before
+    /// `PTRACE_SEIZE` or `PTRACE_ATTACH`. `SigChldFd` and `BlockingSigChld`
+    /// only reap terminated children, thus they do not interfere with it.
+    ///
+    /// The classic alternative is `PTRACE_TRACEME` in `pre_exec`, which makes
+    /// the child stop with `SIGTRAP` after `execve`, but then the tracer has
+    /// to be the thread that spawns it.
+    ///
+    /// If `on_stop` panics, then the child is killed and waited before the
+    /// panic is propagated.
+    ///
+    /// `on_stop` must not wait for the spawn to return, otherwise it
+    /// deadlocks.
+    pub fn stop_on_exec<F>(&mut self, on_stop: F) -> &mut Self
+        where F: Fn(pid_t) -> Result<(), SyscallError> + Send + Sync + 'static
+    {
+        self.stop_on_exec = Some(StopHook(Box::new(on_stop)));
+        self
+    }
+
+    /// Reset the attributes of the child that are inherited from the thread
+    /// calling spawn, instead of the process, which is surprising when
+    /// spawning from e.g. a tokio worker thread:
+    ///  - the signal mask is set to empty instead of the mask of the calling
+    ///    thread, unless `signal_mask` is set,
+    ///  - the scheduling policy is set to `SCHED_OTHER` via
+    ///    `sched_setscheduler`, which fails with `EPERM` if the calling thread
+    ///    is `SCHED_IDLE` and `RLIMIT_NICE` does not permit leaving it.
+    ///
+    /// On failure, `SpawnError::Child` with `SpawnStage::PreExec` is returned.
+    ///
+    /// The other per-thread attributes are still inherited, since resetting
+    /// them requires privileges or is not supported:
+    ///  - the nice value, which can only be lowered with `CAP_SYS_NICE`,
+    ///  - the CPU affinity, the I/O priority and the timer slack.
+    ///
+    /// The parent-death signal is never inherited, as it is cleared for the
+    /// child by the kernel, but if it is set in `pre_exec`, then it is sent
+    /// once the thread that spawned the child exits, not the process.
+    pub fn reset_thread_attributes(&mut self) -> &mut Self {
+        self.reset_thread_attributes = true;
+        self
+    }
+
+    /// Kill the child with `SIGKILL` if it does not terminate within `timeout`
+    /// after it called `execve`, in which case `SpawnError::TimedOut` is
+    /// returned.
+    ///
+    /// If `new_process_group` or `setsid` is set, then the whole process group
+    /// is killed.
+    ///
+    /// Only used by `spawn_and_wait` and `spawn_and_wait_async`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Check the configuration without forking and return the first problem.
+    ///
+    /// Only the checks that are safe to do in the parent are performed:
+    ///  - interior nul byte passed to `arg_str`, `env_str` or `env_keep`;
+    ///  - key containing `=` passed to `env_keep`;
+    ///  - range of `oom_score_adj`;
+    ///  - `current_dir` that might escape `chroot`;
+    ///  - fd passed via `keep_fd` that is overwritten when redirecting stdio;
+    ///  - `groups` set together with `unshare_user`;
+    ///  - existence of the executable via `faccessat2`, which is skipped if
+    ///    `chroot` is set or on kernel without `faccessat2`.
+    ///
+    /// `spawn` and the other methods spawning the child perform all the
+    /// checks above internally except for the existence of the executable,
+    /// which is left to `execve` in the child and reported as
+    /// `SpawnError::Child` at `SpawnStage::Exec`, so this can be used to
+    /// report it before spawning as `SpawnError::ProgramNotFound`.
+    ///
+    /// The child can still fail even if this returns `Ok(())`, since it might
+    /// run with different credentials and the filesystem can be changed in
+    /// the meantime.
+    pub fn validate(&self) -> Result<(), SpawnError> {
+        self.validate_impl(false)?;
+
+        if self.chroot.is_none() && self.exe.is_none() {
+            self.check_program().map_err(SpawnError::ProgramNotFound)?;
+        }
+
+        Ok(())
+    }
+
+    ///  * `capture` - passed to `prepare_stdio`
+    fn validate_impl(&self, capture: bool) -> Result<(), SpawnError> {
+        if let Some(err) = &self.nul_error {
+            return Err(SpawnError::InteriorNul(err.clone()));
+        }
+
+        if let Some(key) = &self.invalid_env_key {
+            return Err(SpawnError::InvalidEnvKey(key.clone()));
+        }
+
+        if let Some(score) = self.oom_score_adj {
+            if !(-1000..=1000).contains(&score) {
+                return Err(SpawnError::OomScoreAdjOutOfRange(score));
+            }
+        }
+
+        if self.chroot.is_some() && self.current_dir_fd.is_some() {
+            return Err(SpawnError::CwdEscapesChroot);
+        }
+
+        if let (Some(_), Some(dir)) = (&self.chroot, &self.current_dir) {
+            let dir = dir.to_bytes();
+
+            let is_absolute = dir.first() == Some(&b'/');
+            let has_dotdot = dir
+                .split(|byte| *byte == b'/')
+                .any(|component| component == b"..");
+
+            if !is_absolute || has_dotdot {
+                return Err(SpawnError::CwdEscapesChroot);
+            }
+        }
+
+        if let Some(fd) = self.find_stdio_conflict(capture) {
+            return Err(SpawnError::StdioFdConflict(fd));
+        }
+
+        if self.groups.is_some() && self.user_ns.is_some() {
+            return Err(SpawnError::GroupsInUserNs);
+        }
+
+        if self.ld_preload && self.chroot.is_none() && self.is_setid_program()? {
+            return Err(SpawnError::PreloadIgnored);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the executable is setuid, or setgid, i.e. has both setgid
+    /// and group execute bits set.
+    ///
+    /// Returns `false` if the executable is a relative path and `current_dir`
+    /// or `current_dir_fd` is set, since it is resolved in the child.
+    fn is_setid_program(&self) -> Result<bool, SyscallError> {
+        let mask = StatxMask::STATX_TYPE | StatxMask::STATX_MODE;
+
+        let stx = if let Some(exe) = &self.exe {
+            statx(**exe, cstr!(""), ExecveAtFlags::AT_EMPTY_PATH, mask)?
+        } else if self.needs_search() {
+            let path_env = get_path_env();
+            let paths = self.split_path_env(&path_env);
+            let path = self.get_execvel_candidate(&paths)?.resolve()?;
+
+            statx(AT_FDCWD, &path, ExecveAtFlags::NONE, mask)?
+        } else {
+            let is_relative = self.path.to_bytes().first() != Some(&b'/');
+            let changes_dir = self.current_dir.is_some() || self.current_dir_fd.is_some();
+            if is_relative && changes_dir {
+                return Ok(false);
+            }
+
+            statx(AT_FDCWD, &self.path, ExecveAtFlags::NONE, mask)?
+        };
+
+        Ok(stx.get_mode().map_or(false, |mode| {
+            let mode = Mode::from_bits_truncate(mode.into());
+
+            // Without S_IXGRP, S_ISGID means mandatory locking instead.
+            mode.contains(Mode::S_ISUID) || mode.contains(Mode::S_ISGID | Mode::S_IXGRP)
+        }))
+    }
+
+    /// Returns the first fd passed via `keep_fd` that is overwritten when
+    /// redirecting stdio in the child.
+    ///
+    /// `Stdio::Fd` never conflicts since `redirect_stdio` duplicates sources
+    /// that would be overwritten beforehand.
+    fn find_stdio_conflict(&self, capture: bool) -> Option<c_int> {
+        let configs = [&self.stdin, &self.stdout, &self.stderr];
+
+        // Whether fd `i` is replaced when redirecting stdio `i`
+        let is_replaced = |i: c_int| match configs[i as usize] {
+            Stdio::Inherit if i == 0 && self.stdin_memfd.is_some() => true,
+            Stdio::Inherit if i == 2 && self.stderr_to_stdout => true,
+            Stdio::Inherit => capture && i != 0,
+            Stdio::Fd(fd) => fd.get_fd() != i,
+            Stdio::Null | Stdio::Piped => true,
+        };
+
+        self.keep_fds
+            .iter()
+            .map(|fd| fd.get_fd())
+            .find(|fd| (0..3).contains(fd) && is_replaced(*fd))
+    }
+
+    /// Check that the executable exists, searching `$PATH` if `needs_search`.
+    ///
+    /// Only `ENOENT` and `ENOTDIR` are reported, any other error, e.g. `EACCES`
+    /// or `EPERM` from seccomp, is left for `execve` in the child to decide.
+    ///
+    /// Relative paths are not checked if `current_dir` or `current_dir_fd` is
+    /// set, since they are resolved in the child after chdir.
+    fn check_program(&self) -> Result<(), SyscallError> {
+        let exists = |path: &CStr| {
+            let is_relative = path.to_bytes().first() != Some(&b'/');
+            let changes_dir = self.current_dir.is_some() || self.current_dir_fd.is_some();
+            if is_relative && changes_dir {
+                return Ok(());
+            }
+
+            match faccessat2(AT_FDCWD, path, AccessCheck::F_OK, FaccessFlags::NONE) {
+                Err(err) if err.get_errno() == libc::ENOENT || err.get_errno() == libc::ENOTDIR =>
+                    Err(err),
+                // e.g. EACCES since the child might have different credentials
+                _ => Ok(()),
+            }
+        };
+
+        if !self.needs_search() {
+            return exists(self.path.as_c_str());
+        }
+
+        let path_env = get_path_env();
+        let paths = self.split_path_env(&path_env);
+        self.get_execvel_candidate(&paths)?;
+
+        let found = paths.iter().any(|dir| {
+            let mut path = Vec::with_capacity(dir.len() + 1 + self.path.to_bytes().len());
+            path.extend_from_slice(dir.as_bytes());
+            path.push(b'/');
+            path.extend_from_slice(self.path.to_bytes());
+
+            match CString::new(path) {
+                Ok(path) => exists(path.as_c_str()).is_ok(),
+                // Entries of $PATH with nul byte are never tried by the child
+                Err(_) => false,
+            }
+        });
+
+        if found {
+            Ok(())
+        } else {
+            Err(SyscallError::new(libc::ENOENT as u32))
+        }
+    }
+
+    /// Runs in the child.
+    ///
+    /// Apply the settings in the order documented in `Command` and then `execve`.
+    fn child_main(&self, prepared: &Prepared, err_fd: Fd, old_sigset: &sigset_t)
+        -> Result<Infallible, (SpawnStage, SyscallError)>
+    {
+        if !self.inherit_sigpipe {
+            signal_ignore(Signal::SIGPIPE).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        if let Some(go) = prepared.cgroup_go {
+            wait_for_go(unsafe { Fd::from_raw(go) }).map_err(at(SpawnStage::Cgroup))?;
+        }
+
+        if let Some(sync) = &prepared.user_ns_sync {
+            unshare(CloneFlags::CLONE_NEWUSER).map_err(at(SpawnStage::UserNs))?;
+            sync.notify_parent().map_err(at(SpawnStage::UserNs))?;
+        }
+
+        if let Some(fd) = &self.netns {
+            setns(fd, CloneFlags::CLONE_NEWNET).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        if let Some(score) = self.oom_score_adj {
+            write_oom_score_adj(score).map_err(at(SpawnStage::OomScoreAdj))?;
+        }
+
+        if let Some(path) = &self.mount_proc_at {
+            if getpid() != 1 {
+                return Err((SpawnStage::PidNs, SyscallError::new(libc::EINVAL as u32)));
+            }
+            mount_proc(path).map_err(at(SpawnStage::MountProc))?;
+        }
+
+        if let Some(dir) = &self.chroot {
+            chroot(dir).map_err(at(SpawnStage::Chroot))?;
+        }
+        match (&self.chroot, &self.current_dir, &self.current_dir_fd) {
+            (_, _, Some(dir)) => dir.fchdir(),
+            (_, Some(dir), None) => chdir(dir),
+            (Some(_), None, None) => chdir(cstr!("/")),
+            (None, None, None) => Ok(()),
+        }.map_err(at(SpawnStage::Chdir))?;
+
+        if let Some(kept) = &self.reset_rlimits_except {
+            let resources = PrlimitResource::ALL
+                .iter()
+                .filter(|resource| !kept.contains(*resource));
+            for resource in resources {
+                let (soft, hard) = conservative_rlimit(*resource);
+
+                let mut limit = prlimit(*resource, None).map_err(at(SpawnStage::Rlimit))?;
+                limit.rlim_max = hard.min(limit.rlim_max);
+                limit.rlim_cur = soft.min(limit.rlim_max);
+                prlimit(*resource, Some(&limit)).map_err(at(SpawnStage::Rlimit))?;
+            }
+        }
+
+        if let Some(groups) = &self.groups {
+            setgroups(groups).map_err(at(SpawnStage::SetGroups))?;
+        }
+        if let Some(gid) = self.gid {
+            setresgid(gid, gid, gid).map_err(at(SpawnStage::SetGid))?;
+        }
+        if let Some(uid) = self.uid {
+            setresuid(uid, uid, uid).map_err(at(SpawnStage::SetUid))?;
+        }
+
+        if let Some(n) = self.max_open_files {
+            let resource = PrlimitResource::RLIMIT_NOFILE;
+
+            let mut limit = prlimit(resource, None).map_err(at(SpawnStage::Rlimit))?;
+            limit.rlim_cur = n.min(limit.rlim_max);
+            prlimit(resource, Some(&limit)).map_err(at(SpawnStage::Rlimit))?;
+        }
+        if let Some(limit) = self.cpu_time_limit {
+            let resource = PrlimitResource::RLIMIT_CPU;
+            let secs = limit.as_secs() + (limit.subsec_nanos() != 0) as u64;
+
+            let mut limit = prlimit(resource, None).map_err(at(SpawnStage::Rlimit))?;
+            limit.rlim_cur = secs.min(limit.rlim_max);
+            prlimit(resource, Some(&limit)).map_err(at(SpawnStage::Rlimit))?;
+        }
+
+        let stdio = |i: usize| prepared.stdio[i].map(|fd| unsafe { Fd::from_raw(fd) });
+        let (stdin, stdout, stderr) = (stdio(0), stdio(1), stdio(2));
+        redirect_stdio(stdin.as_ref(), stdout.as_ref(), stderr.as_ref())
+            .map_err(at(SpawnStage::DupStdio))?;
+        for fd in &self.keep_fds {
+            fd.set_cloexec(false).map_err(at(SpawnStage::DupStdio))?;
+        }
+
+        if self.setsid {
+            setsid().map_err(at(SpawnStage::PreExec))?;
+        } else if self.new_process_group {
+            // setsid fails with EPERM if the caller is already a group leader
+            setpgid(0, 0).map_err(at(SpawnStage::PreExec))?;
+        }
+        if self.inherit_controlling_tty {
+            STDIN.set_controlling_tty(false).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        if self.reset_thread_attributes {
+            sched_setscheduler(0, &SchedPolicy::SCHED_OTHER).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        // Restored before pre_exec, so that the disposition set by it is kept.
+        if !self.inherit_sigpipe {
+            signal_default(Signal::SIGPIPE).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        for hook in &self.pre_exec {
+            (hook.0)().map_err(at(SpawnStage::PreExec))?;
+        }
+
+        let empty_sigset = sigemptyset();
+        let sigset = match &self.signal_mask {
+            Some(mask) => mask,
+            None if self.reset_thread_attributes => &empty_sigset,
+            None => old_sigset,
+        };
+        sigprocmask(SigprocmaskHow::SIG_SETMASK, Some(sigset))
+            .map_err(at(SpawnStage::PreExec))?;
+
+        if cfg!(debug_assertions) {
+            audit_fd_leaks(err_fd, &self.keep_fds);
+        }
+
+        // Done after audit_fd_leaks, which would report it otherwise.
+        if let Some(fd) = prepared.ready_fd {
+            unsafe { Fd::from_raw(fd) }.set_cloexec(false).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        if self.stop_on_exec.is_some() {
+            report_stop(err_fd);
+            tgkill(getpid(), gettid(), Signal::SIGSTOP).map_err(at(SpawnStage::PreExec))?;
+        }
+
+        let err = match (&self.exe, &prepared.candidate) {
+            (Some(exe), _) => {
+                let flags = ExecveAtFlags::AT_EMPTY_PATH;
+                execveat(**exe, cstr!(""), &prepared.argv, &prepared.envp, flags)
+            },
+            (None, Some(candidate)) => execvel(candidate, &prepared.argv, &prepared.envp),
+            (None, None) => execve(prepared.path, &prepared.argv, &prepared.envp),
+        };
+        Err((SpawnStage::Exec, err))
+    }
+
+    fn get_env_key(entry: &[u8]) -> &[u8] {
+        match entry.iter().position(|byte| *byte == b'=') {
+            Some(pos) => &entry[..pos],
+            None => entry,
+        }
+    }
+
+    fn build_envs(&self) -> Vec<CString> {
+        let mut envs = Vec::new();
+
+        if !self.env_clear || !self.env_keep.is_empty() {
+            for (key, val) in std::env::vars_os() {
+                let key = key.as_bytes();
+
+                let is_kept = !self.env_clear || self.env_keep
+                    .iter()
+                    .any(|kept| kept.as_bytes() == key);
+                if !is_kept {
+                    continue;
+                }
+
+                let is_overwritten = self.envs
+                    .iter()
+                    .any(|entry| Command::get_env_key(entry.to_bytes()) == key);
+                if is_overwritten {
+                    continue;
+                }
+
+                let mut entry = Vec::with_capacity(key.len() + 1 + val.len());
+                entry.extend_from_slice(key);
+                entry.push(b'=');
+                entry.extend_from_slice(val.as_bytes());
+
+                // Environment variables cannot contain any nul byte
+                envs.push(unsafe { CString::from_vec_unchecked(entry) });
+            }
+        }
+
+        envs.extend(self.envs.iter().cloned());
+        envs
+    }
+
+    fn needs_search(&self) -> bool {
+        self.search_path && !self.path.to_bytes().contains(&b'/')
+    }
+
+    /// Split `$PATH`, where an empty entry means the current working directory.
+    ///
+    /// Entries that are too long to be joined with `path` are skipped.
+    fn split_path_env<'a>(&self, path_env: &'a str) -> Vec<&'a str> {
+        let filename_sz = self.path.to_bytes().len();
+
+        path_env
+            .split(':')
+            .map(|path| if path.is_empty() { "." } else { path })
+            .filter(|path| path.len() + filename_sz + 2 <= PATH_MAX)
+            .collect()
+    }
+
+    fn get_execvel_candidate<'a>(&'a self, paths: &'a [&'a str])
+        -> Result<ExecvelCandidate<'a>, SyscallError>
+    {
+        let filename = match Filename::new(&self.path) {
+            Some(filename) => filename,
+            None if self.path.to_bytes().is_empty() =>
+                return Err(SyscallError::new(libc::ENOENT as u32)),
+            None => return Err(SyscallError::new(libc::ENAMETOOLONG as u32)),
+        };
+
+        ExecvelCandidate::new(filename, paths)
+            .ok_or_else(|| SyscallError::new(libc::ENOENT as u32))
+    }
+
+    /// Open the fds for stdin, stdout and stderr of the child.
+    ///
+    ///  * `capture` - if true, stdout and stderr configured as `Stdio::Inherit`
+    ///    are piped instead.
+    fn prepare_stdio(&self, capture: bool) -> Result<PreparedStdio, SyscallError> {
+        let mut prepared = PreparedStdio {
+            fds: [None; 3],
+            child_ends: Vec::new(),
+            parent_ends: [None, None, None],
+        };
+
+        let configs = [&self.stdin, &self.stdout, &self.stderr];
+        for (i, config) in configs.iter().enumerate() {
+            let config = match config {
+                Stdio::Inherit if i == 0 && self.stdin_memfd.is_some() => {
+                    let memfd = sealed_memfd(self.stdin_memfd.as_deref().unwrap())?;
+                    prepared.fds[0] = Some(memfd.get_fd());
+                    prepared.child_ends.push(memfd);
+                    continue
+                },
+                Stdio::Inherit if i == 2 && self.stderr_to_stdout => continue,
+                Stdio::Inherit if capture && i != 0 => &Stdio::Piped,
+                config => config,
+            };
+
+            let child_end = match config {
+                Stdio::Inherit => continue,
+                Stdio::Fd(fd) => {
+                    prepared.fds[i] = Some(fd.get_fd());
+                    continue
+                },
+                Stdio::Null => {
+                    prepared.fds[i] = Some(dev_null(i != 0)?.get_fd());
+                    continue
+                },
+                Stdio::Piped => {
+                    let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC)?;
+                    let (child_end, parent_end) = if i == 0 {
+                        (read_end, write_end)
+                    } else {
+                        (write_end, read_end)
+                    };
+                    prepared.parent_ends[i] = Some(parent_end);
+                    child_end
+                },
+            };
+
+            prepared.fds[i] = Some(child_end.get_fd());
+            prepared.child_ends.push(child_end);
+        }
+
+        // Stdio is redirected simultaneously, so stderr takes the source of
+        // stdout instead of fd 1.
+        if self.stderr_to_stdout {
+            prepared.fds[2] = Some(prepared.fds[1].unwrap_or(1));
+        }
+
+        Ok(prepared)
+    }
+
+    /// Spawn the child using a stack from `StacksQueue` and wait for it to
+    /// either `execve` or exit.
+    ///
+    ///  * `capture` - passed to `prepare_stdio`
+    fn spawn_impl(&self, capture: bool) -> Result<Child, SpawnError> {
+        let mut stack = StacksQueue::get();
+        let result = match StacksQueue::reserve(&mut stack, STACK_SZ, CALLBACK_OBJ_SZ) {
+            Ok(mut allocator) => self.spawn_on(&mut allocator, capture),
+            Err(err) => Err(err.into()),
+        };
+        StacksQueue::put(stack);
+
+        result
+    }
+
+    /// Same as `spawn_impl`, but the child uses the stack of `allocator`,
+    /// which must be reserved with `STACK_SZ` and `CALLBACK_OBJ_SZ`.
+    fn spawn_on(&self, allocator: &mut StackObjectAllocator, capture: bool)
+        -> Result<Child, SpawnError>
+    {
+        self.validate_impl(capture)?;
+
+        let PreparedStdio { fds, child_ends, parent_ends } = self.prepare_stdio(capture)?;
+
+        let (user_ns_sync, user_ns_parent) = match &self.user_ns {
+            Some(maps) => {
+                let (sync, parent) = UserNsSync::new(maps)?;
+                (Some(sync), Some(parent))
+            },
+            None => (None, None),
+        };
+
+        // (read end for the child, write end for the parent)
+        let cgroup_sync = match &self.cgroup {
+            Some(_) => Some(FdBox::pipe2(FdFlags::O_CLOEXEC)?),
+            None => None,
+        };
+
+        // (read end for the parent, write end for the child)
+        let (ready_r, ready_w) = if self.readiness_fd {
+            let (ready_r, ready_w) = FdBox::pipe2(FdFlags::O_CLOEXEC)?;
+            (Some(ready_r), Some(ready_w))
+        } else {
+            (None, None)
+        };
+
+        let mut envs = self.build_envs();
+        if let Some(ready_w) = &ready_w {
+            let key = READY_FD_ENV.as_bytes();
+            envs.retain(|entry| Command::get_env_key(entry.to_bytes()) != key);
+            envs.push(CString::new(format!("{}={}", READY_FD_ENV, ready_w.get_fd())).unwrap());
+        }
+
+        let argv: Vec<*const c_char> = self.args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain(once(std::ptr::null()))
+            .collect();
+        let envp: Vec<*const c_char> = envs
+            .iter()
+            .map(|env| env.as_ptr())
+            .chain(once(std::ptr::null()))
+            .collect();
+
+        let path_env = if self.needs_search() {
+            Some(get_path_env())
+        } else {
+            None
+        };
+        let paths = path_env.as_deref().map(|path_env| self.split_path_env(path_env));
+        let candidate = match &paths {
+            Some(paths) => Some(self.get_execvel_candidate(paths)?),
+            None => None,
+        };
+
+        let prepared = Prepared {
+            path: self.path.as_c_str(),
+            candidate,
+            // Both argv and envp are terminated by null
+            argv: unsafe { CStrArray::from_raw(&argv) },
+            envp: unsafe { CStrArray::from_raw(&envp) },
+            stdio: fds,
+            user_ns_sync: user_ns_sync.as_ref().map(UserNsSync::get_child_fds),
+            cgroup_go: cgroup_sync.as_ref().map(|(go_r, _)| go_r.get_fd()),
+            ready_fd: ready_w.as_ref().map(|ready_w| ready_w.get_fd()),
+        };
+        let prepared = &prepared;
+
+        let callback = move |fd: Fd, old_sigset: &mut sigset_t| -> c_int {
+            let (stage, err) = match self.child_main(prepared, fd, old_sigset) {
+                Ok(infallible) => match infallible {},
+                Err(err) => err,
+            };
+            report_error(fd, stage, err)
+        };
+
+        let result = spawn_on_stack(allocator, callback, |pid| {
+            // Close the child ends so that EOF is observed if the child exits.
+            drop(user_ns_sync);
+            drop(ready_w);
+
+            if let (Some(cgroup), Some((go_r, go_w))) = (&self.cgroup, cgroup_sync) {
+                drop(go_r);
+
+                // On failure, go_w is closed without writing, so that the
+                // child observes EOF and exits.
+                move_into_cgroup(cgroup, pid)?;
+                go_w.write_all(b"g")?;
+            }
+
+            match user_ns_parent {
+                Some(parent) => parent.write_maps(pid),
+                None => Ok(()),
+            }
+        }, self.stop_on_exec.as_ref());
+
+        // Close the child ends of the pipes in the parent, so that EOF can be
+        // observed once the child exits.
+        drop(child_ends);
+
+        let [stdin, stdout, stderr] = parent_ends;
+        let mut child = Child::new(result?, None);
+        if self.new_process_group || self.setsid {
+            child.pgid = Some(child.pid);
+        }
+        child.stdin = stdin;
+        child.stdout = stdout;
+        child.stderr = stderr;
+        child.readiness = ready_r;
+
+        Ok(child)
+    }
+
+    /// Spawn the child and returns once the child called `execve` successfully.
+    pub fn spawn(&self) -> Result<Child, SpawnError> {
+        self.spawn_impl(false)
+    }
+
+    /// Same as `spawn`, except that the returned `Child` also holds a pidfd
+    /// obtained via `pidfd_open` right after the child called `execve`.
+    ///
+    /// There is a small window between the fork and `pidfd_open` in which a
+    /// fast-exiting child might already be reaped by the `SigChldFd`.
+    /// In that case, `ESRCH` is ignored and the returned `Child` holds no pidfd,
+    /// while its exit status is still available via `Child::wait`, since it is
+    /// already captured by the `SigChldFd`.
+    ///
+    /// Within the same window, the pid might even be reused by another
+    /// process, so the pidfd is checked to refer to a child of the calling
+    /// process via `waitid(P_PIDFD, ..., WNOHANG | WNOWAIT)`, otherwise
+    /// `ECHILD` is returned.
+    /// The check cannot tell if the pid is reused by another child of the
+    /// calling process, so `spawn_with_pidfd` should not be used together with
+    /// `SigChldFd` when that matters, and it is skipped on linux < 5.4, which
+    /// does not support `P_PIDFD`.
+    pub fn spawn_with_pidfd(&self) -> Result<Child, SpawnError> {
+        let mut child = self.spawn_impl(false)?;
+
+        child.pidfd = match open_child_pidfd(child.pid) {
+            Ok(pidfd) => Some(pidfd),
+            Err(err) if err.get_errno() == libc::ESRCH => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(child)
+    }
+
+    /// Spawn the child, block until it terminates and reap it, honoring
+    /// `timeout`, without any reaper like `SigChldFd` or async runtime, which
+    /// is the simplest way to run a program to completion.
+    ///
+    /// The child is waited for via its pidfd, which is not prone to pid reuse,
+    /// and the timeout is implemented by `ppoll` on the pidfd.
+    /// On kernels without pidfd, i.e. `pidfd_open` (linux 5.3) or
+    /// `waitid(P_PIDFD, ...)` (linux 5.4), it falls back to
+    /// `waitid(P_PID, ...)`, which is not prone to pid reuse either as long as
+    /// nothing else reaps the child, but then `timeout` cannot be implemented,
+    /// so `ENOSYS` is returned before spawning if it is set.
+    ///
+    /// Same as `Child::wait_via_pidfd`, it should not be used together with
+    /// `SigChldFd`, which might reap the child first, causing `ECHILD` to be
+    /// returned.
+    ///
+    /// If stdin is `Stdio::Piped`, then `stdin_bytes`, if any, is written to
+    /// it without blocking while waiting, and then it is closed, thus the
+    /// timeout also covers a child that never reads its stdin.
+    ///
+    /// `SpawnError::UnreadPipe` is returned if stdout or stderr is
+    /// `Stdio::Piped`, since nobody reads them.
+    pub fn spawn_and_wait(&self) -> Result<ExitInfo, SpawnError> {
+        self.check_no_unread_pipe()?;
+
+        let has_pidfd = has_pidfd();
+        if !has_pidfd && self.timeout.is_some() {
+            return Err(SyscallError::new(libc::ENOSYS as u32).into());
+        }
+
+        let mut child = self.spawn()?;
+        if has_pidfd {
+            child.pidfd = match open_child_pidfd(child.pid) {
+                Ok(pidfd) => Some(pidfd),
+                // The child is already reaped by someone else
+                Err(err) if err.get_errno() == libc::ESRCH =>
+                    return Err(SyscallError::new(libc::ECHILD as u32).into()),
+                Err(err) => return Err(err.into()),
+            };
+        }
+
+        if !self.feed_stdin_until_exit(&mut child)? {
+            // Reap the child even if kill fails, so that it is not leaked.
+            let killed = child.kill_on_timeout();
+            let info = child.reap()?;
+            killed?;
+            return Err(SpawnError::TimedOut { pid: child.pid, info });
+        }
+
+        Ok(child.reap()?)
+    }
+
+    fn check_no_unread_pipe(&self) -> Result<(), SpawnError> {
+        match (&self.stdout, &self.stderr) {
+            (Stdio::Piped, _) | (_, Stdio::Piped) => Err(SpawnError::UnreadPipe),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `stdin_bytes` to the stdin pipe of `child`, if any, without
+    /// blocking until its pidfd becomes readable, i.e. the child terminates,
+    /// or `timeout` expires.
+    ///
+    /// If `child` has no pidfd, then it returns once everything is written.
+    ///
+    /// The pipe is closed once everything is written or the child closes its
+    /// end, so that the child reads EOF.
+    ///
+    /// Returns false if timed out.
+    fn feed_stdin_until_exit(&self, child: &mut Child) -> Result<bool, SyscallError> {
+        let pidfd = child.get_pidfd();
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut stdin = child.stdin.take();
+        if let Some(stdin) = &stdin {
+            stdin.set_nonblocking(true)?;
+        }
+        let mut bytes = self.get_stdin_bytes();
+
+        loop {
+            if bytes.is_empty() || stdin.is_none() {
+                stdin = None;
+
+                if pidfd.is_none() {
+                    return Ok(true);
+                }
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) => Some(timeout),
+                    None => return Ok(false),
+                },
+                None => None,
+            };
+
+            let mut pollfds = [
+                // pidfd becomes readable once the child terminates
+                libc::pollfd {
+                    fd: pidfd.map_or(-1, |pidfd| pidfd.get_fd()),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: stdin.as_ref().map_or(-1, |stdin| stdin.get_fd()),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                },
+            ];
+            if ppoll(&mut pollfds, timeout)? == 0 {
+                return Ok(false);
+            }
+            if pollfds[0].revents != 0 {
+                return Ok(true);
+            }
+
+            if let Some(stdin) = &stdin {
+                match stdin.write(bytes) {
+                    Ok(cnt) => bytes = &bytes[cnt..],
+                    // The child closed its stdin
+                    Err(err) if err.get_errno() == libc::EPIPE => bytes = &[],
+                    Err(err) if err.get_errno() == libc::EAGAIN => (),
+                    Err(err) if err.get_errno() == libc::EINTR => (),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+
+    /// Async version of `spawn_and_wait`.
+    ///
+    /// The timeout is implemented by racing the readiness of the pidfd against
+    /// a timer.
+    ///
+    /// Must be called within the context of a tokio runtime with time enabled.
+    #[cfg(feature = "async")]
+    pub async fn spawn_and_wait_async(&self) -> Result<ExitInfo, SpawnError> {
+        self.check_no_unread_pipe()?;
+
+        let mut child = self.spawn_with_pidfd()?;
+
+        let stdin = child.stdin.take();
+        let child = &child;
+        let wait = async move {
+            let (_, info) = tokio::try_join!(
+                write_all_and_close(stdin, self.get_stdin_bytes()),
+                child.wait_via_pidfd()
+            )?;
+            Ok::<_, SyscallError>(info)
+        };
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(wait.await?),
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(result) => Ok(result?),
+            Err(_elapsed) => {
+                // Reap the child even if kill fails, so that it is not leaked.
+                let killed = child.kill_on_timeout();
+                let info = child.wait_via_pidfd().await?;
+                killed?;
+                Err(SpawnError::TimedOut { pid: child.pid, info })
+            },
+        }
+    }
+
+    /// Spawn the child, collect everything it writes to stdout and stderr and
+    /// wait for it to terminate.
+    ///
+    /// stdout and stderr are piped unless they are configured explicitly, in
+    /// which case the corresponding field of `Output` is empty.
+    ///
+    /// If stdin is `Stdio::Piped`, then `stdin_bytes`, if any, is written to it
+    /// and then it is closed.
+    #[cfg(feature = "async")]
+    pub async fn output(&self, sigchld: &SigChldFd) -> Result<Output, SpawnError> {
+        let mut child = self.spawn_impl(true)?;
+
+        let (_, stdout, stderr) = tokio::try_join!(
+            write_all_and_close(child.stdin.take(), self.get_stdin_bytes()),
+            read_to_end(child.stdout.take()),
+            read_to_end(child.stderr.take())
+        )?;
+
+        let status = child.wait(sigchld).await.map_err(|err| match err.get_errno() {
+            libc::ECANCELED => SpawnError::ReaperShutdown,
+            _ => SpawnError::Syscall(err),
+        })?;
+
+        Ok(Output { status, stdout, stderr })
+    }
+}
+
+/// Write `bytes` to the parent end of a pipe, then close it.
+///
+/// `EPIPE` is ignored, since the child might exit without consuming all of the
+/// input.
+#[cfg(feature = "async")]
+async fn write_all_and_close(fd: Option<FdBox>, mut bytes: &[u8]) -> Result<(), SyscallError> {
+    let fd = match fd {
+        Some(fd) => fd,
+        None => return Ok(()),
+    };
+    let fd = fd.into_async(Interest::WRITABLE)?;
+
+    while !bytes.is_empty() {
+        let result = fd.try_io(Interest::WRITABLE, |fd| {
+            autorestart!({
+                fd.write(bytes)
+            })
+        }).await;
+
+        match result {
+            Ok(cnt) => bytes = &bytes[cnt..],
+            Err(err) if err.raw_os_error() == Some(libc::EPIPE) => break,
+            Err(err) => return Err(to_syscall_error(err)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read from the parent end of a pipe until EOF.
+#[cfg(feature = "async")]
+async fn read_to_end(fd: Option<FdBox>) -> Result<Vec<u8>, SyscallError> {
+    let mut out = Vec::new();
+
+    let fd = match fd {
+        Some(fd) => fd,
+        None => return Ok(out),
+    };
+    let fd = fd.into_async(Interest::READABLE)?;
+
+    let mut buf = [0 as u8; 4096];
+    loop {
+        let cnt = fd.try_io(Interest::READABLE, |fd| {
+            autorestart!({
+                fd.read(&mut buf)
+            })
+        }).await.map_err(to_syscall_error)?;
+
+        if cnt == 0 {
+            break Ok(out);
+        }
+        out.extend_from_slice(&buf[..cnt]);
+    }
+}
+
+macro_rules! child_pipe {
+    ( $( #[$attr:meta] )* $name:ident ) => {
+        $( #[$attr] )*
+        #[derive(Debug)]
+        pub struct $name(FdBox);
+        impl $name {
+            pub fn get_fd(&self) -> Fd {
+                *self.0
+            }
+
+            pub fn into_fd(self) -> FdBox {
+                self.0
+            }
+        }
+    };
+    ( $( #[$attr:meta] )* $name:ident: Read ) => {
+        child_pipe!($( #[$attr] )* $name);
+        impl Read for $name {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                Ok(autorestart!({ self.0.read(buf) })?)
+            }
+        }
+    };
+    ( $( #[$attr:meta] )* $name:ident: Write ) => {
+        child_pipe!($( #[$attr] )* $name);
+        impl Write for $name {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(autorestart!({ self.0.write(buf) })?)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+
+child_pipe!(
+    /// Blocking writer to stdin of the child, returned by `Child::take_stdin`.
+    ///
+    /// `EINTR` is retried automatically.
+    ///
+    /// The pipe is closed on drop, so that the child reads EOF.
+    ChildStdin: Write
+);
+child_pipe!(
+    /// Blocking reader of stdout of the child, returned by `Child::take_stdout`.
+    ///
+    /// `EINTR` is retried automatically.
+    ChildStdout: Read
+);
+child_pipe!(
+    /// Blocking reader of stderr of the child, returned by `Child::take_stderr`.
+    ///
+    /// `EINTR` is retried automatically.
+    ChildStderr: Read
+);
+
+/// Output of a terminated child collected by `Command::output`.
+#[derive(Debug)]
+pub struct Output {
+    pub status: ExitInfo,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+impl Output {
+    /// Decode stdout as UTF-8, replacing invalid sequences with `U+FFFD`.
+    pub fn stdout_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    /// Decode stderr as UTF-8, replacing invalid sequences with `U+FFFD`.
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+
+    /// Same as `stdout_lossy`, but with a single trailing newline stripped,
+    /// like shell's `$(...)`.
+    pub fn stdout_trimmed(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(strip_newline(&self.stdout))
+    }
+
+    /// Same as `stderr_lossy`, but with a single trailing newline stripped,
+    /// like shell's `$(...)`.
+    pub fn stderr_trimmed(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(strip_newline(&self.stderr))
+    }
+}
+
+fn strip_newline(bytes: &[u8]) -> &[u8] {
+    match bytes.last() {
+        Some(b'\n') => &bytes[..bytes.len() - 1],
+        _ => bytes,
+    }
+}
+
+/// Compute the supplementary groups of user `uid` for `Command::groups`,
+/// like `getgrouplist` but without glibc.
+///
+/// The name and primary gid of `uid` are looked up in `/etc/passwd`, then
+/// every group in `/etc/group` listing the name as a member is added.
+/// Only these local files are read via raw syscalls, users and groups from
+/// LDAP or any other NSS backend are not found.
+///
+/// The primary gid is stored in `buf[0]`, followed by the other groups
+/// without duplicates.
+///
+/// Returns the number of groups stored in `buf`.
+///
+/// # Errors
+///
+///  * `ENOENT` - `uid` is not found in `/etc/passwd`.
+///  * `ERANGE` - `buf` is too small to hold all the groups.
+pub fn supplementary_groups_for(uid: uid_t, buf: &mut [gid_t]) -> Result<usize, SyscallError> {
+    let passwd = read_file(cstr!("/etc/passwd"))?;
+    let (name, primary_gid) = split_db_lines(&passwd)
+        .find_map(|fields| {
+            // name:password:uid:gid:gecos:dir:shell
+            match (fields.get(0), parse_id(fields.get(2)), parse_id(fields.get(3))) {
+                (Some(name), Some(id), Some(gid)) if id == uid => Some((*name, gid)),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| SyscallError::new(libc::ENOENT as u32))?;
+
+    let mut cnt = 0;
+    let mut push = |gid: gid_t| {
+        if buf[..cnt].contains(&gid) {
+            return Ok(());
+        }
+        match buf.get_mut(cnt) {
+            Some(slot) => *slot = gid,
+            None => return Err(SyscallError::new(libc::ERANGE as u32)),
+        }
+        cnt += 1;
+        Ok(())
+    };
+
+    push(primary_gid)?;
+
+    let group = read_file(cstr!("/etc/group"))?;
+    for fields in split_db_lines(&group) {
+        // name:password:gid:member1,member2,...
+        let gid = match parse_id(fields.get(2)) {
+            Some(gid) => gid,
+            None => continue,
+        };
+        let is_member = fields
+            .get(3)
+            .map(|members| members.split(|byte| *byte == b',').any(|member| member == name))
+            .unwrap_or(false);
+        if is_member {
+            push(gid)?;
+        }
+    }
+
+    Ok(cnt)
+}
+
+fn read_file(pathname: &CStr) -> Result<Vec<u8>, SyscallError> {
+    let fd = FdBox::openat(AT_FDCWD, pathname, AccessMode::O_RDONLY, FdFlags::O_CLOEXEC)?;
+
+    let mut content = Vec::new();
+    let mut buf = [0 as u8; 4096];
+    loop {
+        let cnt = autorestart!({
+            fd.read(&mut buf)
+        })?;
+        if cnt == 0 {
+            break Ok(content);
+        }
+        content.extend_from_slice(&buf[..cnt]);
+    }
+}
+
+/// Split the lines of `/etc/passwd` or `/etc/group` into fields, skipping
+/// empty lines, comments and NIS entries starting with '+' or '-'.
+fn split_db_lines(content: &[u8]) -> impl Iterator<Item = Vec<&[u8]>> {
+    content
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !matches!(line.first(), None | Some(b'#') | Some(b'+') | Some(b'-')))
+        .map(|line| line.split(|byte| *byte == b':').collect())
+}
+
+fn parse_id(field: Option<&&[u8]>) -> Option<u32> {
+    std::str::from_utf8(field?).ok()?.parse().ok()
+}
+
+/// Spawn each of `commands` via `Command::spawn`, using a single stack taken
+/// from the pool for all of them, so that they can be waited via `reaper`.
+///
+/// The object arena of the stack is reset between spawns via
+/// `StackObjectAllocator::reset`.
+///
+/// argv and envp are built on the heap of the parent, thus the stack does not
+/// need to be sized for the largest argv.
+///
+/// If `reaper` is already shut down, then nothing is spawned and
+/// `SpawnError::ReaperShutdown` is returned for each command, since the
+/// children could never be waited.
+///
+/// Returns the result of each command in the same order.
+#[cfg(feature = "async")]
+pub fn spawn_batch(commands: &[Command], reaper: &SigChldFd) -> Vec<Result<Child, SpawnError>> {
+    if reaper.is_shutdown() {
+        return commands.iter().map(|_| Err(SpawnError::ReaperShutdown)).collect();
+    }
+
+    let mut stack = StacksQueue::get();
+    let results = match StacksQueue::reserve(&mut stack, STACK_SZ, CALLBACK_OBJ_SZ) {
+        Ok(mut allocator) => commands
+            .iter()
+            .map(|command| command.spawn_on(&mut allocator, false))
+            .collect(),
+        Err(err) => commands
+            .iter()
+            .map(|_| Err(SyscallError::new(err.get_errno() as u32).into()))
+            .collect(),
+    };
+    StacksQueue::put(stack);
+
+    results
+}
+
+/// Block until any of `children` terminates or `timeout` expires by waiting
+/// on their pidfds via `EpollReactor`, then reap it via `waitid(P_PIDFD, ...)`,
+/// without any async runtime or `SIGCHLD` machinery.
+///
+/// All of `children` must be spawned via `spawn_with_pidfd`, otherwise `EBADF`
+/// is returned.
+///
+/// Same as `Child::wait_via_pidfd`, it should not be used together with
+/// `SigChldFd` or `BlockingSigChld`.
+///
+///  * `timeout` - If `None`, then wait indefinitely.
+///
+/// Returns the index of the child reaped in `children` and its exit info, or
+/// `None` if timed out.
+pub fn wait_any(children: &[Child], timeout: Option<Duration>)
+    -> Result<Option<(usize, ExitInfo)>, SyscallError>
+{
+    let reactor = EpollReactor::new()?;
+    for (i, child) in children.iter().enumerate() {
+        let pidfd = child.get_pidfd().ok_or_else(|| SyscallError::new(libc::EBADF as u32))?;
+        reactor.add(&pidfd, EpollEvents::EPOLLIN, i as u64)?;
+    }
+
+    let mut events = [EpollEvent::default(); 1];
+    if reactor.wait(&mut events, timeout)? == 0 {
+        return Ok(None);
+    }
+
+    let i = events[0].get_token() as usize;
+    let child = &children[i];
+    let info = child.reap_via_pidfd(child.get_pidfd().unwrap())?;
+
+    Ok(Some((i, info)))
+}
+
+/// Arguments of `execve` prepared in the parent.
+struct Prepared<'a> {
+    path: &'a CStr,
+    /// Set if `path` is searched in `$PATH`
+    candidate: Option<ExecvelCandidate<'a>>,
+    argv: CStrArray<'a>,
+    envp: CStrArray<'a>,
+    /// fds to be dup'ed onto stdin, stdout and stderr in the child.
+    stdio: [Option<c_int>; 3],
+    /// Child ends of `UserNsSync`
+    user_ns_sync: Option<UserNsChildFds>,
+    /// Read end of the pipe to wait for the parent to write `cgroup.procs`
+    cgroup_go: Option<c_int>,
+    /// Write end of the readiness pipe
+    ready_fd: Option<c_int>,
+}
+
+#[derive(Debug, Clone)]
+struct IdMaps {
+    uid_map: Vec<(u32, u32, u32)>,
+    gid_map: Vec<(u32, u32, u32)>,
+}
+
+/// Child ends of the pipes of `UserNsSync`.
+struct UserNsChildFds {
+    /// Write end of the pipe to notify the parent.
+    ready: c_int,
+    /// Read end of the pipe to wait for the parent.
+    go: c_int,
+}
+impl UserNsChildFds {
+    /// Runs in the child after `unshare(CLONE_NEWUSER)`.
+    ///
+    /// Notify the parent and wait for it to write the id maps.
+    fn notify_parent(&self) -> Result<(), SyscallError> {
+        let ready = unsafe { Fd::from_raw(self.ready) };
+        let go = unsafe { Fd::from_raw(self.go) };
+
+        ready.write_all(b"r")?;
+        wait_for_go(go)
+    }
+}
+
+/// Runs in the child.
+///
+/// Wait for the parent to write one byte to `go`, or `ECANCELED` if the parent
+/// closed it without writing due to failure.
+fn wait_for_go(go: Fd) -> Result<(), SyscallError> {
+    let mut buf = [0 as u8; 1];
+    let cnt = autorestart!({
+        go.read(&mut buf)
+    })?;
+    match cnt {
+        1 => Ok(()),
+        _ => Err(SyscallError::new(libc::ECANCELED as u32)),
+    }
+}
+
+/// Create a `O_CLOEXEC` memfd containing `data`, sealed against writing and
+/// shrinking, with its offset at 0.
+fn sealed_memfd(data: &[u8]) -> Result<FdBox, SyscallError> {
+    let flags = MemfdFlags::MFD_CLOEXEC | MemfdFlags::MFD_ALLOW_SEALING;
+    let memfd = FdBox::memfd_create(cstr!("avfork-stdin"), flags)?;
+
+    memfd.write_all(data)?;
+    memfd.add_seals(SealFlags::F_SEAL_WRITE | SealFlags::F_SEAL_SHRINK)?;
+    memfd.lseek(std::io::SeekFrom::Start(0))?;
+
+    Ok(memfd)
+}
+
+/// Write `pid` to `cgroup.procs` in the cgroup v2 directory `cgroup`.
+fn move_into_cgroup(cgroup: &FdBox, pid: pid_t) -> Result<(), SyscallError> {
+    // openat accepts any fd referring to a directory as dirfd.
+    let dirfd = unsafe { FdPath::from_raw(cgroup.get_fd()) };
+    let procs = FdBox::openat(dirfd, cstr!("cgroup.procs"), AccessMode::O_WRONLY, FdFlags::O_CLOEXEC)?;
+
+    procs.write_all(pid.to_string().as_bytes())
+}
+
+/// Handshake between the parent and the child for `Command::unshare_user`.
+///
+/// Holds the child ends of the pipes, which are closed in the parent right
+/// after the fork.
+struct UserNsSync {
+    ready: FdBox,
+    go: FdBox,
+}
+impl UserNsSync {
+    fn new(maps: &IdMaps) -> Result<(UserNsSync, UserNsParent), SyscallError> {
+        let (ready_r, ready_w) = FdBox::pipe2(FdFlags::O_CLOEXEC)?;
+        let (go_r, go_w) = FdBox::pipe2(FdFlags::O_CLOEXEC)?;
+
+        let sync = UserNsSync { ready: ready_w, go: go_r };
+        let parent = UserNsParent { ready: ready_r, go: go_w, maps: maps.clone() };
+
+        Ok((sync, parent))
+    }
+
+    fn get_child_fds(&self) -> UserNsChildFds {
+        UserNsChildFds {
+            ready: self.ready.get_fd(),
+            go: self.go.get_fd(),
+        }
+    }
+}
+
+/// Parent ends of the pipes of `UserNsSync`.
+struct UserNsParent {
+    ready: FdBox,
+    go: FdBox,
+    maps: IdMaps,
+}
+impl UserNsParent {
+    /// Wait for the child to unshare its user namespace, then write the maps.
+    ///
+    /// On failure, `self.go` is closed without writing, so that the child
+    /// observes EOF and exits.
+    fn write_maps(self, pid: pid_t) -> Result<(), SyscallError> {
+        let mut buf = [0 as u8; 1];
+        let cnt = autorestart!({
+            self.ready.read(&mut buf)
+        })?;
+        if cnt == 0 {
+            // The child exited before unshare, its error would be reported
+            // via the CLOEXEC pipe.
+            return Ok(());
+        }
+
+        deny_setgroups(pid)?;
+        write_uid_map(pid, &self.maps.uid_map)?;
+        write_gid_map(pid, &self.maps.gid_map)?;
+
+        self.go.write_all(b"g")
+    }
+}
+
+/// Returned by `Command::prepare_stdio`.
+struct PreparedStdio {
+    /// Passed to the child via `Prepared::stdio`
+    fds: [Option<c_int>; 3],
+    /// fds opened for the child, which must be kept open until the child
+    /// called `execve`.
+    child_ends: Vec<FdBox>,
+    /// Parent ends of the pipes for `Stdio::Piped`.
+    parent_ends: [Option<FdBox>; 3],
+}
+
+/// Size of the error reported via `report_error`: (stage, errno)
+const ERROR_REPORT_SZ: usize = 2 * mem::size_of::<c_int>();
+
+/// Used in place of the stage by `audit_fd_leaks`, followed by the leaked fd
+/// instead of errno.
+const FD_LEAK_REPORT: c_int = -1;
+
+/// Used in place of the stage right before the child stops itself for
+/// `Command::stop_on_exec`, followed by 0 instead of errno.
+const STOP_REPORT: c_int = -2;
+
+/// Used in place of the stage by the `SIGSEGV` handler installed by
+/// `catch_stack_overflow`, followed by the size of the stack instead of errno.
+const STACK_OVERFLOW_REPORT: c_int = -3;
+
+/// Runs in the child.
+///
+/// Notify the parent that the child is going to stop itself.
+fn report_stop(fd: Fd) {
+    let mut buf = [0 as u8; ERROR_REPORT_SZ];
+    buf[..mem::size_of::<c_int>()].copy_from_slice(&STOP_REPORT.to_ne_bytes());
+
+    let _ = fd.write(&buf);
+}
+
+/// Runs in the `SIGSEGV` handler of the child.
+///
+/// Notify the parent that the child overflowed its stack of `stack_sz` bytes.
+#[cfg(debug_assertions)]
+fn report_stack_overflow(fd: Fd, stack_sz: usize) {
+    let mut buf = [0 as u8; ERROR_REPORT_SZ];
+
+    let (code_buf, sz_buf) = buf.split_at_mut(mem::size_of::<c_int>());
+    code_buf.copy_from_slice(&STACK_OVERFLOW_REPORT.to_ne_bytes());
+    sz_buf.copy_from_slice(&(stack_sz as c_int).to_ne_bytes());
+
+    let _ = fd.write(&buf);
+}
+
+/// Size of the alternate signal stack the `SIGSEGV` handler installed by
+/// `catch_stack_overflow` runs on.
+const ALT_STACK_SZ: usize = 16 * 1024;
+
+/// Read by the `SIGSEGV` handler installed by `catch_stack_overflow`.
+///
+/// The child shares the memory with the parent and the other children, so it
+/// is put right above the alternate signal stack, where the handler finds it
+/// via `get_sigaltstack`, instead of a global.
+#[cfg(debug_assertions)]
+#[repr(C)]
+struct OverflowContext {
+    err_fd: Fd,
+    stack_lo: usize,
+    stack_hi: usize,
+}
+
+#[cfg(debug_assertions)]
+#[repr(C, align(16))]
+struct AltStack {
+    stack: [u8; ALT_STACK_SZ],
+    context: OverflowContext,
+}
+
+/// Only used in debug builds.
+///
+/// Wrap `callback` so that it handles `SIGSEGV` on an alternate signal stack
+/// in the child, which reports a fault within or right below `stack` via
+/// the CLOEXEC pipe before the child is killed by `SIGSEGV`.
+///
+/// Failure to install the handler is ignored, in which case an overflow is
+/// not detected.
+#[cfg(debug_assertions)]
+fn catch_stack_overflow<Func>(stack: Range<usize>, callback: Func)
+    -> impl Fn(Fd, &mut sigset_t) -> c_int
+    where Func: Fn(Fd, &mut sigset_t) -> c_int
+{
+    move |err_fd: Fd, old_sigset: &mut sigset_t| -> c_int {
+        let mut alt_stack = AltStack {
+            stack: [0; ALT_STACK_SZ],
+            context: OverflowContext {
+                err_fd,
+                stack_lo: stack.start,
+                stack_hi: stack.end,
+            },
+        };
+
+        // alt_stack outlives the callback, which either calls execve or exits.
+        let installed = unsafe { set_sigaltstack(&mut alt_stack.stack) }.is_ok()
+            && sigaction_on_altstack(Signal::SIGSEGV, on_sigsegv).is_ok();
+
+        // SIGSEGV caused by a fault while blocked kills the child without
+        // running the handler.
+        if installed {
+            let mut set = sigemptyset();
+            sigaddset(&mut set, Signal::SIGSEGV);
+            let _ = sigprocmask(SigprocmaskHow::SIG_UNBLOCK, Some(&set));
+        }
+
+        callback(err_fd, old_sigset)
+    }
+}
+
+/// `SIGSEGV` handler installed by `catch_stack_overflow`, which runs in the
+/// child on the alternate signal stack.
+#[cfg(debug_assertions)]
+extern "C" fn on_sigsegv(_sig: c_int, info: *mut libc::siginfo_t, _ucontext: *mut c_void) {
+    if let Ok(ss) = get_sigaltstack() {
+        let context = unsafe {
+            &*((ss.ss_sp as usize + ss.ss_size) as *const OverflowContext)
+        };
+        let addr = unsafe { (*info).si_addr() } as usize;
+
+        // The stack grows down, so an overflow faults at the page right below
+        // it, or at the guard page within it.
+        let stack_lo = context.stack_lo.saturating_sub(get_pagesz());
+        if (stack_lo..context.stack_hi).contains(&addr) {
+            report_stack_overflow(context.err_fd, context.stack_hi - context.stack_lo);
+        }
+    }
+
+    // The disposition is reset to SIG_DFL by SA_RESETHAND, but SIGSEGV is
+    // blocked while the handler runs.
+    let mut set = sigemptyset();
+    sigaddset(&mut set, Signal::SIGSEGV);
+    let _ = sigprocmask(SigprocmaskHow::SIG_UNBLOCK, Some(&set));
+    let _ = tgkill(getpid(), gettid(), Signal::SIGSEGV);
+
+    syscall::exit(1)
+}
+
+/// Write `stage` and `err` to the write end of the CLOEXEC pipe so that the
+/// parent can retrieve it.
+///
+/// Returns the exit status of the child.
+fn report_error(fd: Fd, stage: SpawnStage, err: SyscallError) -> c_int {
+    let mut buf = [0 as u8; ERROR_REPORT_SZ];
+
+    let (stage_buf, errno_buf) = buf.split_at_mut(mem::size_of::<c_int>());
+    stage_buf.copy_from_slice(&(stage as c_int).to_ne_bytes());
+    errno_buf.copy_from_slice(&err.get_errno().to_ne_bytes());
+
+    let _ = fd.write(&buf);
+    1
+}
+
+/// Only used in debug builds.
+///
+/// Scan `/proc/self/fd` in the child right before `execve` and report every
+/// fd > 2 that is not `O_CLOEXEC` and not in `keep_fds` via `err_fd`, without
+/// allocating.
+///
+/// Failure to scan, e.g. `/proc` is not mounted inside `chroot`, is ignored.
+fn audit_fd_leaks(err_fd: Fd, keep_fds: &[FdBox]) {
+    let flags = FdFlags::O_DIRECTORY | FdFlags::O_CLOEXEC;
+    let dir = match FdBox::openat(AT_FDCWD, cstr!("/proc/self/fd"), AccessMode::O_RDONLY, flags) {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+
+    let mut buf = [0 as u8; 1024];
+    loop {
+        let cnt = match dir.getdents(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(cnt) => cnt,
+        };
+
+        for dirent in Dirents::new(&buf[..cnt]) {
+            let fd = match parse_fd(dirent.get_name().to_bytes()) {
+                Some(fd) if fd > 2 => fd,
+                _ => continue,
+            };
+            if keep_fds.iter().any(|keep_fd| keep_fd.get_fd() == fd) {
+                continue;
+            }
+
+            // dir itself is O_CLOEXEC.
+            if let Ok(false) = unsafe { Fd::from_raw(fd) }.is_cloexec() {
+                let mut report = [0 as u8; ERROR_REPORT_SZ];
+
+                let (stage_buf, fd_buf) = report.split_at_mut(mem::size_of::<c_int>());
+                stage_buf.copy_from_slice(&FD_LEAK_REPORT.to_ne_bytes());
+                fd_buf.copy_from_slice(&fd.to_ne_bytes());
+
+                let _ = err_fd.write(&report);
+            }
+        }
+    }
+}
+
+/// Returns the (soft, hard) limit of `resource` documented in
+/// `Command::reset_rlimits_except`.
+fn conservative_rlimit(resource: PrlimitResource) -> (u64, u64) {
+    use PrlimitResource::*;
+
+    const MIB: u64 = 1024 * 1024;
+    const UNLIMITED: u64 = libc::RLIM_INFINITY;
+
+    match resource {
+        RLIMIT_CORE => (0, 0),
+        RLIMIT_MEMLOCK => (8 * MIB, 8 * MIB),
+        RLIMIT_MSGQUEUE => (819200, 819200),
+        RLIMIT_NICE | RLIMIT_RTPRIO => (0, 0),
+        RLIMIT_NOFILE => (1024, 524288),
+        RLIMIT_STACK => (8 * MIB, UNLIMITED),
+        _ => (UNLIMITED, UNLIMITED),
+    }
+}
+
+/// Runs in the child.
+///
+/// Mount procfs at `path` in a new private mount namespace.
+fn mount_proc(path: &CStr) -> Result<(), SyscallError> {
+    unshare(CloneFlags::CLONE_NEWNS)?;
+
+    // The mounts might be shared with the mount namespace of the parent,
+    // e.g. by systemd.
+    mount(None, cstr!("/"), None, MountFlags::MS_REC | MountFlags::MS_PRIVATE, None)?;
+
+    let flags = MountFlags::MS_NOSUID | MountFlags::MS_NODEV | MountFlags::MS_NOEXEC;
+    mount(Some(cstr!("proc")), path, Some(cstr!("proc")), flags, None)
+}
+
+/// Runs in the child, thus the score is formatted on the stack.
+fn write_oom_score_adj(score: i32) -> Result<(), SyscallError> {
+    let path = cstr!("/proc/self/oom_score_adj");
+    let fd = FdBox::openat(AT_FDCWD, path, AccessMode::O_WRONLY, FdFlags::O_CLOEXEC)?;
+
+    // "-1000" at most
+    let mut buf = [0 as u8; 8];
+    let mut start = buf.len();
+    let mut n = score.unsigned_abs();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    if score < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+
+    fd.write(&buf[start..])?;
+    Ok(())
+}
+
+/// Parse the name of an entry in `/proc/self/fd`, which is "." or ".." or a
+/// fd in decimal.
+fn parse_fd(name: &[u8]) -> Option<c_int> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut fd: c_int = 0;
+    for byte in name {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        fd = fd.checked_mul(10)?.checked_add((byte - b'0') as c_int)?;
+    }
+    Some(fd)
+}
+
+/// Open the pidfd of `pid` and check that it refers to a child of the calling
+/// process, which is not the case if `pid` is already reaped and reused by
+/// another process, in which case `ECHILD` is returned.
+///
+/// The check is skipped on linux < 5.4, which does not support `P_PIDFD`.
+fn open_child_pidfd(pid: pid_t) -> Result<FdBox, SyscallError> {
+    let pidfd = pidfd_open(pid)?;
+
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    match waitid(libc::P_PIDFD, pidfd.get_fd() as libc::id_t, options) {
+        Err(err) if err.raw_os_error() == Some(libc::ECHILD) =>
+            Err(SyscallError::new(libc::ECHILD as u32)),
+        _ => Ok(pidfd),
+    }
+}
+
+/// Whether pidfd is supported, i.e. linux 5.3+, which is probed once.
+fn has_pidfd() -> bool {
+    static HAS_PIDFD: OnceCell<bool> = OnceCell::new();
+
+    *HAS_PIDFD.get_or_init(|| match pidfd_open(getpid()) {
+        Err(err) => err.get_errno() != libc::ENOSYS,
+        Ok(_) => true,
+    })
+}
+
+/// Run `callback` in the child using the stack of `allocator` and wait for it
+/// to `execve` or exit.
+///
+///  * `after_fork` - called in the parent with the pid of the child right after
+///    the fork, before waiting for the child.
+///    If it fails, then the child is still waited and reaped.
+fn spawn_on_stack<Func, AfterFork>(
+    allocator: &mut StackObjectAllocator,
+    callback: Func,
+    after_fork: AfterFork,
+    on_stop: Option<&StopHook>
+)
+    -> Result<pid_t, SpawnError>
+    where Func: Fn(Fd, &mut sigset_t) -> c_int,
+          AfterFork: FnOnce(pid_t) -> Result<(), SyscallError>
+{
+    // Free the callback of the previous spawn, if any.
+    allocator.reset();
+
+    #[cfg(debug_assertions)]
+    let callback = catch_stack_overflow(allocator.get_stack_range(), callback);
+    check_callback_fits(&callback);
+
+    let callback = match allocator.alloc_obj(callback) {
+        Ok(callback) => callback,
+        Err(_) => unreachable!("checked by CallbackFits"),
+    };
+
+    let (fd, pid) = avfork(allocator, callback.pin())?;
+
+    let after_fork_result = after_fork(pid);
+
+    // The callback and its captured variables must outlive the child until it
+    // called `execve` or exited.
+    let result = wait_for_exec(&fd, pid, on_stop);
+
+    if let Err(err) = after_fork_result {
+        reap(pid);
+        return Err(err.into());
+    }
+    if let Err(err) = result {
+        reap(pid);
+        return Err(err);
+    }
+
+    Ok(pid)
+}
+
+/// Block until the write end of the CLOEXEC pipe is closed.
+///
+/// Leaked fds reported by `audit_fd_leaks` are logged to stderr.
+///
+/// Returns the error reported by the child via `report_error`, if any.
+fn wait_for_exec(fd: &FdBox, pid: pid_t, on_stop: Option<&StopHook>) -> Result<(), SpawnError> {
+    let mut buf = [0 as u8; ERROR_REPORT_SZ];
+    let mut on_stop_err = None;
+
+    loop {
+        let cnt = autorestart!({
+            fd.read(&mut buf)
+        })?;
+
+        if cnt == 0 {
+            break match on_stop_err {
+                Some(err) => Err(SpawnError::Syscall(err)),
+                None => Ok(()),
+            };
+        }
+
+        // Write to pipe with size less than PIPE_BUF is atomic
+        debug_assert_eq!(cnt, buf.len());
+
+        let (stage_buf, errno_buf) = buf.split_at(mem::size_of::<c_int>());
+        let stage = c_int::from_ne_bytes(stage_buf.try_into().unwrap());
+        let errno = c_int::from_ne_bytes(errno_buf.try_into().unwrap());
+
+        if stage == STOP_REPORT {
+            if let Some(on_stop) = on_stop {
+                match panic::catch_unwind(AssertUnwindSafe(|| handle_stop(pid, on_stop))) {
+                    Ok(Ok(())) => (),
+                    // The child is killed, so EOF is observed next.
+                    Ok(Err(err)) => on_stop_err = Some(err),
+                    Err(payload) => {
+                        // The stopped child still runs on the stack and memory
+                        // of the parent, so it must be dead before unwinding.
+                        let _ = kill(pid, Signal::SIGKILL);
+                        wait_for_eof(fd);
+                        reap(pid);
+                        panic::resume_unwind(payload);
+                    },
+                }
+            }
+            continue;
+        }
+
+        if stage == STACK_OVERFLOW_REPORT {
+            break Err(SpawnError::StackOverflow(errno as usize));
+        }
+
+        if stage == FD_LEAK_REPORT {
+            let _ = writeln!(
+                STDERR.clone(),
+                "avfork: fd {} is leaked into child {} since it is not O_CLOEXEC",
+                errno, pid
+            );
+            continue;
+        }
+
+        break Err(SpawnError::Child {
+            stage: SpawnStage::from_raw(stage).unwrap_or(SpawnStage::PreExec),
+            err: SyscallError::new(errno as u32),
+        });
+    }
+}
+
+/// Block until the write end of the CLOEXEC pipe is closed, discarding any
+/// report from the child.
+fn wait_for_eof(fd: &FdBox) {
+    let mut buf = [0 as u8; ERROR_REPORT_SZ];
+
+    while let Ok(cnt) = autorestart!({ fd.read(&mut buf) }) {
+        if cnt == 0 {
+            break;
+        }
+    }
+}
+
+/// Wait for the child to stop for `Command::stop_on_exec`, call `on_stop` and
+/// then resume it.
+///
+/// If `on_stop` fails, then the child is killed instead.
+///
+/// If `on_stop` panics, then the child is left stopped and the caller has to
+/// kill it.
+fn handle_stop(pid: pid_t, on_stop: &StopHook) -> Result<(), SyscallError> {
+    // WEXITED in case the child is killed before it stops, in which case EOF
+    // is observed next.
+    let options = libc::WSTOPPED | libc::WEXITED | libc::WNOWAIT;
+    let siginfo = match waitid(libc::P_PID, pid as libc::id_t, options) {
+        Ok(siginfo) => siginfo.expect("waitid without WNOHANG should not return None"),
+        // ECHILD: already reaped by SigChldFd
+        Err(_) => return Ok(()),
+    };
+    if let WaitStatus::Stopped(_) = ExitInfo::from_siginfo(&siginfo).status() {
+        match (on_stop.0)(pid) {
+            Ok(()) => kill(pid, Signal::SIGCONT),
+            Err(err) => {
+                let _ = kill(pid, Signal::SIGKILL);
+                Err(err)
+            },
+        }
+    } else {
+        Ok(())
+    }
+}
+
+/// Release the zombie of a child that failed to `execve`.
+///
+/// If the child is already reaped by `SigChldFd`, then this is a no-op.
+fn reap(pid: pid_t) {
+    unsafe {
+        libc::waitpid(pid, std::ptr::null_mut(), 0);
+    }
+}
+
+/// What to do with the child when `Child` is dropped before it is waited.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Do nothing, the child would become a zombie once it exits unless there
+    /// is a reaper, e.g. `SigChldFd`, running.
+    #[default]
+    Leak,
+    /// Reap the child if it has already exited via `waitid(WNOHANG)`, without
+    /// blocking.
+    ///
+    /// If it is still running, then the child would become a zombie once it
+    /// exits.
+    Reap,
+    /// Send the signal to the child, check `Child::kill_on_drop`.
+    Kill(Signal),
+}
+
+/// Strategy of reaping children, so that supervisor code can be written
+/// once and work with any of `BlockingSigChld` and `PidfdReaper`.
+pub trait Reaper {
+    /// Block until `child` terminates and reap it.
+    fn wait_child(&self, child: &Child) -> Result<ExitInfo, SyscallError>;
+}
+
+#[cfg(feature = "async")]
+pub type ReaperFuture<'a> = Pin<Box<dyn Future<Output = Result<ExitInfo, SyscallError>> + Send + 'a>>;
+
+/// Async version of `Reaper`, implemented by `SigChldFd` and `PidfdReaper`.
+#[cfg(feature = "async")]
+pub trait AsyncReaper {
+    /// Wait for `child` to terminate and reap it.
+    fn wait_child<'a>(&'a self, child: &'a Child) -> ReaperFuture<'a>;
+}
+
+/// Reap the child via its pidfd without any `SIGCHLD` machinery, which
+/// requires the child to be spawned via `spawn_with_pidfd`, otherwise `EBADF`
+/// is returned.
+///
+/// Same as `Child::wait_via_pidfd`, it should not be used together with
+/// `SigChldFd` or `BlockingSigChld`, which might reap the child first, causing
+/// `ECHILD` to be returned.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PidfdReaper;
+
+impl Reaper for PidfdReaper {
+    fn wait_child(&self, child: &Child) -> Result<ExitInfo, SyscallError> {
+        match child.get_pidfd() {
+            Some(pidfd) => child.reap_via_pidfd(pidfd),
+            None => Err(SyscallError::new(libc::EBADF as u32)),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncReaper for PidfdReaper {
+    fn wait_child<'a>(&'a self, child: &'a Child) -> ReaperFuture<'a> {
+        Box::pin(child.wait_via_pidfd())
+    }
+}
+
+/// Block via `waitid(P_PID, ...)` on the child, instead of draining the
+/// signalfd, so that other children are left for `BlockingSigChld::reap`.
+///
+/// `ECHILD` is returned if the child is already reaped by
+/// `BlockingSigChld::reap` or `BlockingSigChld::wait`.
+impl Reaper for BlockingSigChld {
+    fn wait_child(&self, child: &Child) -> Result<ExitInfo, SyscallError> {
+        let siginfo = waitid(libc::P_PID, child.id() as libc::id_t, libc::WEXITED)
+            .map_err(to_syscall_error)?
+            .expect("waitid without WNOHANG should not return None");
+
+        Ok(ExitInfo::from_siginfo(&siginfo))
+    }
+}
+
+/// `ECANCELED` is returned if `SigChldFd` is shut down before the child
+/// terminates.
+#[cfg(feature = "async")]
+impl AsyncReaper for SigChldFd {
+    fn wait_child<'a>(&'a self, child: &'a Child) -> ReaperFuture<'a> {
+        Box::pin(async move {
+            self.wait(child.id())
+                .await
+                .ok_or_else(|| SyscallError::new(libc::ECANCELED as u32))
+        })
+    }
+}
+
+/// Handle of a spawned child.
+///
+/// What happens to the child when it is dropped without waiting is decided
+/// by `DropPolicy`, which defaults to `DropPolicy::Leak`.
+pub struct Child {
+    pid: pid_t,
+    pidfd: Option<FdBox>,
+    pgid: Option<pid_t>,
+    drop_policy: DropPolicy,
+    waited: AtomicBool,
+
+    stdin: Option<FdBox>,
+    stdout: Option<FdBox>,
+    stderr: Option<FdBox>,
+    readiness: Option<FdBox>,
+}
+impl Child {
+    fn new(pid: pid_t, pidfd: Option<FdBox>) -> Child {
+        Child {
+            pid,
+            pidfd,
+            pgid: None,
+            drop_policy: DropPolicy::Leak,
+            waited: AtomicBool::new(false),
+
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            readiness: None,
+        }
+    }
+
+    /// Returns the pid of the child.
+    pub fn id(&self) -> pid_t {
+        self.pid
+    }
+
+    /// Returns the pidfd of the child if it is spawned via `spawn_with_pidfd`
+    /// and has not been reaped at that time.
+    pub fn get_pidfd(&self) -> Option<Fd> {
+        self.pidfd.as_ref().map(|pidfd| **pidfd)
+    }
+
+    /// Returns the pgid of the process group led by the child if it is spawned
+    /// with `Command::new_process_group` or `Command::setsid`, which can be
+    /// signaled via `kill(-pgid, sig)`.
+    pub fn get_pgid(&self) -> Option<pid_t> {
+        self.pgid
+    }
+
+    /// Send `SIGKILL` to the process group of the child if it leads one,
+    /// otherwise to the child only.
+    ///
+    /// `ESRCH` is ignored, since the child might have exited in the meantime,
+    /// and it must be reaped afterwards anyway.
+    fn kill_on_timeout(&self) -> Result<(), SyscallError> {
+        let result = match (self.pgid, &self.pidfd) {
+            (Some(pgid), _) => kill(-pgid, Signal::SIGKILL),
+            (None, Some(pidfd)) => pidfd_send_signal(pidfd, Signal::SIGKILL),
+            (None, None) => kill(self.pid, Signal::SIGKILL),
+        };
+
+        match result {
+            Err(err) if err.get_errno() == libc::ESRCH => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Take the write end of the pipe connected to stdin of the child if it is
+    /// configured as `Stdio::Piped`.
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.stdin.take().map(ChildStdin)
+    }
+
+    /// Take the read end of the pipe connected to stdout of the child if it is
+    /// configured as `Stdio::Piped`.
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.stdout.take().map(ChildStdout)
+    }
+
+    /// Take the read end of the pipe connected to stderr of the child if it is
+    /// configured as `Stdio::Piped`.
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.stderr.take().map(ChildStderr)
+    }
+
+    /// Take the read end of the readiness pipe if the child is spawned with
+    /// `Command::readiness_fd`.
+    pub fn take_readiness_fd(&mut self) -> Option<FdBox> {
+        self.readiness.take()
+    }
+
+    /// Block until the program spawned with `Command::readiness_fd` signals
+    /// its readiness.
+    ///
+    /// Returns `false` if the program exited or closed the write end without
+    /// signaling it.
+    ///
+    /// `EBADF` is returned if the child is not spawned with
+    /// `Command::readiness_fd` or the read end is taken.
+    pub fn wait_ready(&self) -> Result<bool, SyscallError> {
+        let fd = match &self.readiness {
+            Some(fd) => fd,
+            None => return Err(SyscallError::new(libc::EBADF as u32)),
+        };
+
+        let mut buf = [0 as u8; 1];
+        let cnt = autorestart!({
+            fd.read(&mut buf)
+        })?;
+        Ok(cnt == 1)
+    }
+
+    /// Send `sig` to the child when `Child` is dropped before `wait` returns.
+    ///
+    /// The signal is sent via the pidfd if there is one, otherwise via `kill`,
+    /// which might hit another process if the child has exited and the pid is
+    /// reused.
+    ///
+    /// Note that the killed child would still become a zombie unless a reaper
+    /// is active.
+    ///
+    /// Same as `set_drop_policy(DropPolicy::Kill(sig))`.
+    pub fn kill_on_drop(&mut self, sig: Signal) -> &mut Self {
+        self.set_drop_policy(DropPolicy::Kill(sig))
+    }
+
+    /// Undo `kill_on_drop`, which sets the drop policy to `DropPolicy::Leak`.
+    ///
+    /// If the child has already exited, then this is a no-op since no signal
+    /// would be sent to it anyway.
+    pub fn cancel_kill_on_drop(&mut self) -> &mut Self {
+        self.set_drop_policy(DropPolicy::Leak)
+    }
+
+    /// Set what to do with the child when `Child` is dropped before `wait`
+    /// returns.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) -> &mut Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    pub fn get_drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Wait for the child to terminate and reap it via `reaper`.
+    ///
+    /// If `reaper` is `SigChldFd`, then `ECANCELED` is returned if it is shut
+    /// down before the child terminates.
+    #[cfg(feature = "async")]
+    pub async fn wait<R: AsyncReaper>(&self, reaper: &R) -> Result<ExitInfo, SyscallError> {
+        let info = reaper.wait_child(self).await?;
+        self.waited.store(true, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// Blocking version of `wait`.
+    pub fn wait_blocking<R: Reaper>(&self, reaper: &R) -> Result<ExitInfo, SyscallError> {
+        let info = reaper.wait_child(self)?;
+        self.waited.store(true, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// Wait for the child to terminate by polling its pidfd, then reap it via
+    /// `waitid(P_PIDFD, ...)`, without any `SIGCHLD` machinery.
+    ///
+    /// It requires the child to be spawned via `spawn_with_pidfd`, otherwise
+    /// `EBADF` is returned.
+    ///
+    /// It should not be used together with `SigChldFd`, which might reap the
+    /// child first, causing `ECHILD` to be returned.
+    ///
+    /// Requires linux 5.4.
+    #[cfg(feature = "async")]
+    pub async fn wait_via_pidfd(&self) -> Result<ExitInfo, SyscallError> {
+        let pidfd = match &self.pidfd {
+            Some(pidfd) => **pidfd,
+            None => return Err(SyscallError::new(libc::EBADF as u32)),
+        };
+
+        let async_pidfd = AsyncFd::with_interest(pidfd, Interest::READABLE)
+            .map_err(to_syscall_error)?;
+
+        // pidfd becomes readable once the child terminates
+        let _guard = async_pidfd.readable().await.map_err(to_syscall_error)?;
+
+        self.reap_via_pidfd(pidfd)
+    }
+
+    /// Block until the child terminates and reap it via `waitid(P_PIDFD, ...)`.
+    fn reap_via_pidfd(&self, pidfd: Fd) -> Result<ExitInfo, SyscallError> {
+        let siginfo = waitid(libc::P_PIDFD, pidfd.get_fd() as libc::id_t, libc::WEXITED)
+            .map_err(to_syscall_error)?
+            .expect("waitid without WNOHANG should not return None");
+
+        self.waited.store(true, Ordering::Relaxed);
+
+        Ok(ExitInfo::from_siginfo(&siginfo))
+    }
+
+    /// Block until the child terminates and reap it via its pidfd if any,
+    /// otherwise or if `P_PIDFD` is not supported (linux < 5.4), via
+    /// `waitid(P_PID, ...)`.
+    fn reap(&self) -> Result<ExitInfo, SyscallError> {
+        let pidfd = match self.get_pidfd() {
+            Some(pidfd) => pidfd,
+            None => return self.reap_via_pid(),
+        };
+
+        match self.reap_via_pidfd(pidfd) {
+            Err(err) if err.get_errno() == libc::EINVAL => self.reap_via_pid(),
+            result => result,
+        }
+    }
+
+    /// Block until the child terminates and reap it via `waitid(P_PID, ...)`.
+    fn reap_via_pid(&self) -> Result<ExitInfo, SyscallError> {
+        let siginfo = waitid(libc::P_PID, self.pid as libc::id_t, libc::WEXITED)
+            .map_err(to_syscall_error)?
+            .expect("waitid without WNOHANG should not return None");
+
+        self.waited.store(true, Ordering::Relaxed);
+
+        Ok(ExitInfo::from_siginfo(&siginfo))
+    }
+}
+impl fmt::Display for Child {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "child[pid={}]", self.pid)
+    }
+}
+impl fmt::Debug for Child {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Child")
+            .field("pid", &self.pid)
+            .field("pgid", &self.pgid)
+            .field("has_pidfd", &self.pidfd.is_some())
+            .field("has_stdin", &self.stdin.is_some())
+            .field("has_stdout", &self.stdout.is_some())
+            .field("has_stderr", &self.stderr.is_some())
+            .field("has_readiness_fd", &self.readiness.is_some())
+            .field("drop_policy", &self.drop_policy)
+            .field("waited", &self.waited.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.waited.load(Ordering::Relaxed) {
+            return;
+        }
+
+        match self.drop_policy {
+            DropPolicy::Leak => (),
+            DropPolicy::Reap => {
+                let options = libc::WEXITED | libc::WNOHANG;
+                let pid_result = || waitid(libc::P_PID, self.pid as libc::id_t, options);
+
+                // Prefer the pidfd, which is not prone to pid reuse.
+                // ECHILD: already reaped by SigChldFd
+                let _ = match &self.pidfd {
+                    Some(pidfd) => match waitid(libc::P_PIDFD, pidfd.get_fd() as libc::id_t, options) {
+                        // P_PIDFD is not supported (linux < 5.4)
+                        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => pid_result(),
+                        result => result,
+                    },
+                    None => pid_result(),
+                };
+            },
+            DropPolicy::Kill(sig) => {
+                // The child might have already exited, in which case ESRCH is
+                // returned.
+                let _ = match &self.pidfd {
+                    Some(pidfd) => pidfd_send_signal(pidfd, sig),
+                    None => kill(self.pid, sig),
+                };
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::*;
+    use crate::utility::tests::{assert_no_fd_leak, assert_no_fd_leak_except};
+
+    /// Returns every `Stdio` for stdio `i`.
+    fn all_stdio(i: usize) -> Vec<Stdio> {
+        let fd = dev_null(i != 0).unwrap().dup(true).unwrap();
+        vec![Stdio::Inherit, Stdio::Null, Stdio::Piped, Stdio::Fd(fd)]
+    }
+
+    fn child_fds(child: &Child) -> Vec<c_int> {
+        [&child.pidfd, &child.stdin, &child.stdout, &child.stderr, &child.readiness]
+            .iter()
+            .filter_map(|fd| fd.as_ref().map(|fd| fd.get_fd()))
+            .collect()
+    }
+
+    #[test]
+    fn test_spawn_echo() {
+        let mut command = Command::new(cstr!("/bin/echo"));
+        command.arg(cstr!("hello")).stdout(Stdio::Piped);
+
+        let mut child = command.spawn_with_pidfd().unwrap();
+        let mut stdout = Vec::new();
+        child.take_stdout().unwrap().read_to_end(&mut stdout).unwrap();
+
+        assert_eq!(child.wait_blocking(&PidfdReaper).unwrap().status(), WaitStatus::Exited(0));
+        assert_eq!(stdout, b"hello\n");
+    }
+
+    /// `SigChldFd` reaps every child of the process, so it is run in a forked
+    /// process to not interfere with other tests.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_output() {
+        use crate::utility::tests::run;
+
+        assert_eq!(run(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            let output = runtime.block_on(async {
+                let (sigchld, _handle) = SigChldFd::new().unwrap();
+
+                let mut command = Command::new(cstr!("/bin/echo"));
+                command.arg(cstr!("hello")).stdout(Stdio::Piped);
+                command.output(&sigchld).await
+            });
+
+            match output {
+                Ok(output) if output.status.status() == WaitStatus::Exited(0) => {
+                    if output.stdout != b"hello\n" {
+                        crate::errx!(1, "Unexpected stdout {:?}", output.stdout_lossy());
+                    }
+                },
+                result => crate::errx!(1, "Unexpected result {:?}", result),
+            }
+        }), 0);
+    }
+
+    #[test]
+    fn test_output_lossy() {
+        let output = Output {
+            status: ExitInfo::from_siginfo(&unsafe { mem::zeroed() }),
+            stdout: b"hello\n".to_vec(),
+            stderr: b"\xffworld\n\n".to_vec(),
+        };
+
+        assert_eq!(output.stdout_lossy(), "hello\n");
+        assert_eq!(output.stdout_trimmed(), "hello");
+        assert_eq!(output.stderr_lossy(), "\u{FFFD}world\n\n");
+        assert_eq!(output.stderr_trimmed(), "\u{FFFD}world\n");
+    }
+
+    /// Swapping stdout and stderr requires the dup3 onto them to be done as if
+    /// they were simultaneous.
+    #[test]
+    fn test_spawn_swap_stdio() {
+        use crate::utility::tests::run;
+
+        let (out_r, out_w) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        let (err_r, err_w) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        assert_eq!(run(|| {
+            mem::forget(out_w.dup3(1, FdFlags::empty()).unwrap());
+            mem::forget(err_w.dup3(2, FdFlags::empty()).unwrap());
+
+            let mut command = Command::new(cstr!("/bin/sh"));
+            command
+                .arg(cstr!("-c"))
+                .arg(cstr!("printf 1 && printf 2 >&2"))
+                .stdout(Stdio::Fd(STDERR))
+                .stderr(Stdio::Fd(STDOUT));
+
+            if command.spawn_and_wait().unwrap().status() != WaitStatus::Exited(0) {
+                crate::errx!(1, "Unexpected exit status");
+            }
+        }), 0);
+
+        drop(out_w);
+        drop(err_w);
+
+        let mut buf = [0 as u8; 2];
+        assert_eq!(out_r.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'2');
+        assert_eq!(err_r.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'1');
+    }
+
+    #[test]
+    fn test_wait_blocking() {
+        let exit_with = |code: &CStr| {
+            let mut command = Command::new(cstr!("/bin/sh"));
+            command.arg(cstr!("-c")).arg(code);
+            command
+        };
+
+        let child = exit_with(cstr!("exit 2")).spawn_with_pidfd().unwrap();
+        assert_eq!(child.wait_blocking(&PidfdReaper).unwrap().status(), WaitStatus::Exited(2));
+
+        let sigchld = BlockingSigChld::new().unwrap();
+
+        let child = exit_with(cstr!("exit 3")).spawn().unwrap();
+        assert_eq!(child.wait_blocking(&PidfdReaper).unwrap_err().get_errno(), libc::EBADF);
+        assert_eq!(child.wait_blocking(&sigchld).unwrap().status(), WaitStatus::Exited(3));
+        assert_eq!(child.wait_blocking(&sigchld).unwrap_err().get_errno(), libc::ECHILD);
+    }
+
+    /// Same as `test_output`, it is run in a forked process.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_wait_sigchldfd() {
+        use crate::utility::tests::run;
+
+        assert_eq!(run(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async {
+                let (sigchld, _handle) = SigChldFd::new().unwrap();
+
+                let mut command = Command::new(cstr!("/bin/sh"));
+                command.arg(cstr!("-c")).arg(cstr!("exit 2"));
+                let child = command.spawn().unwrap();
+                match child.wait(&*sigchld).await {
+                    Ok(info) if info.status() == WaitStatus::Exited(2) => (),
+                    result => crate::errx!(1, "Unexpected result {:?}", result),
+                }
+
+                sigchld.shutdown();
+
+                let mut command = Command::new(cstr!("/bin/sleep"));
+                command.arg(cstr!("10"));
+                let child = command.spawn().unwrap();
+                match child.wait(&*sigchld).await {
+                    Err(err) if err.get_errno() == libc::ECANCELED => (),
+                    result => crate::errx!(1, "Unexpected result {:?}", result),
+                }
+                child.kill_on_timeout().unwrap();
+            });
+        }), 0);
+    }
+
+    /// Same as `test_output`, it is run in a forked process.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_spawn_batch() {
+        use crate::utility::tests::run;
+
+        assert_eq!(run(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async {
+                let (sigchld, _handle) = SigChldFd::new().unwrap();
+
+                let commands: Vec<_> = (0..3)
+                    .map(|i| {
+                        let mut command = Command::new(cstr!("/bin/sh"));
+                        command.arg(cstr!("-c")).arg_str(&format!("exit {}", i));
+                        command
+                    })
+                    .collect();
+
+                let results = spawn_batch(&commands, &sigchld);
+                for (i, result) in results.into_iter().enumerate() {
+                    let status = result.unwrap().wait(&*sigchld).await.unwrap().status();
+                    if status != WaitStatus::Exited(i as c_int) {
+                        crate::errx!(1, "Unexpected status {:?} of command {}", status, i);
+                    }
+                }
+
+                sigchld.shutdown();
+                for result in spawn_batch(&commands, &sigchld) {
+                    if !matches!(result, Err(SpawnError::ReaperShutdown)) {
+                        crate::errx!(1, "Unexpected result {:?}", result);
+                    }
+                }
+            });
+        }), 0);
+    }
+
+    #[test]
+    fn test_reset_rlimits_except() {
+        use crate::utility::tests::run;
+
+        let spawn = |kept: &[PrlimitResource], script: &CStr| {
+            let mut command = Command::new(cstr!("/bin/sh"));
+            command.arg(cstr!("-c")).arg(script).reset_rlimits_except(kept);
+            command.spawn_and_wait().unwrap().status()
+        };
+
+        // Run in a forked process, since the limits of the parent are changed.
+        assert_eq!(run(|| {
+            let mut limit = prlimit(PrlimitResource::RLIMIT_NOFILE, None).unwrap();
+            limit.rlim_cur = 100;
+            prlimit(PrlimitResource::RLIMIT_NOFILE, Some(&limit)).unwrap();
+
+            let script = cstr!(
+                "grep -q '^Max core file size  *0  *0 ' /proc/self/limits && \
+                 grep -q '^Max open files  *1024 ' /proc/self/limits"
+            );
+            if spawn(&[], script) != WaitStatus::Exited(0) {
+                crate::errx!(1, "Limits are not reset");
+            }
+
+            let script = cstr!("grep -q '^Max open files  *100 ' /proc/self/limits");
+            if spawn(&[PrlimitResource::RLIMIT_NOFILE], script) != WaitStatus::Exited(0) {
+                crate::errx!(1, "Kept limit is reset");
+            }
+        }), 0);
+    }
+
+    #[test]
+    fn test_env_keep() {
+        let path = std::env::var("PATH").unwrap();
+
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command
+            .arg(cstr!("-c"))
+            .arg_str(&format!(
+                "[ \"$PATH\" = '{}' ] && [ -z \"${{AVFORK_MISSING+x}}\" ] && \
+                 [ \"$HOME\" = /overridden ]",
+                path
+            ))
+            .env_clear()
+            .env_keep(&["PATH", "AVFORK_MISSING", "HOME"])
+            .env(cstr!("HOME"), cstr!("/overridden"));
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(0));
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.env_keep(&["A=B"]);
+        assert_matches!(command.spawn(), Err(SpawnError::InvalidEnvKey(key)) if key == "A=B");
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.env_keep(&["A\0B"]);
+        assert_matches!(command.spawn(), Err(SpawnError::InteriorNul(_)));
+    }
+
+    #[test]
+    fn test_groups_with_unshare_user() {
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.groups(&[0]).unshare_user(&[(0, 0, 1)], &[(0, 0, 1)]);
+        assert_matches!(command.validate(), Err(SpawnError::GroupsInUserNs));
+        assert_matches!(command.spawn(), Err(SpawnError::GroupsInUserNs));
+    }
+
+    #[test]
+    fn test_spawn_exit_code() {
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command.arg(cstr!("-c")).arg(cstr!("exit 3"));
+
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(3));
+    }
+
+    #[test]
+    fn test_spawn_killed_by_signal() {
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command.arg(cstr!("-c")).arg(cstr!("kill -TERM $$"));
+
+        let info = command.spawn_and_wait().unwrap();
+        assert_eq!(info.status(), WaitStatus::Signaled { sig: Signal::SIGTERM, core_dumped: false });
+        assert_eq!(info.get_term_sig(), Some(libc::SIGTERM));
+        assert_eq!(info.get_exit_status(), None);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_stack_overflow() {
+        fn overflow(_: ()) -> Result<(), SyscallError> {
+            let buf = [1 as u8; 2 * STACK_SZ];
+            unsafe { std::ptr::read_volatile(&buf[0]) };
+            Ok(())
+        }
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.pre_exec((), overflow);
+        assert_matches!(
+            command.spawn(),
+            Err(SpawnError::StackOverflow(stack_sz)) if stack_sz >= STACK_SZ
+        );
+    }
+
+    #[test]
+    fn test_spawn_and_wait_stdin_timeout() {
+        // The child never reads stdin, which is larger than the pipe buffer.
+        let mut command = Command::new(cstr!("/bin/sleep"));
+        command
+            .arg(cstr!("10"))
+            .stdin_bytes(vec![0; 1024 * 1024])
+            .timeout(Duration::from_millis(100));
+        assert_matches!(command.spawn_and_wait(), Err(SpawnError::TimedOut { .. }));
+
+        let mut command = Command::new(cstr!("/bin/cat"));
+        command.stdin_bytes(vec![0; 1024 * 1024]).stdout(Stdio::Null);
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(0));
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.stdout(Stdio::Piped);
+        assert_matches!(command.spawn_and_wait(), Err(SpawnError::UnreadPipe));
+    }
+
+    #[test]
+    fn test_spawn_and_wait_timeout() {
+        let mut command = Command::new(cstr!("/bin/sleep"));
+        command.arg(cstr!("10")).timeout(Duration::from_millis(10));
+        assert_matches!(
+            command.spawn_and_wait(),
+            Err(SpawnError::TimedOut { pid, info })
+                if pid > 0
+                    && info.status() == WaitStatus::Signaled { sig: Signal::SIGKILL, core_dumped: false }
+        );
+    }
+
+    #[test]
+    fn test_sigpipe() {
+        // Whether SIGPIPE is ignored, i.e. bit 13 of SigIgn
+        let script = cstr!("grep -q '^SigIgn:[[:space:]]*[0-9a-f]*[13579bdf][0-9a-f]\\{3\\}$' /proc/self/status");
+
+        // The test harness ignores SIGPIPE, which is restored to SIG_DFL.
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command.arg(cstr!("-c")).arg(script);
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(1));
+
+        // The disposition set by pre_exec is kept.
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command.arg(cstr!("-c")).arg(script).pre_exec(Signal::SIGPIPE, signal_ignore);
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(0));
+
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command.arg(cstr!("-c")).arg(script).inherit_sigpipe();
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_spawn_nonexistent() {
+        let command = Command::new(cstr!("/nonexistent/avfork-test"));
+        assert_matches!(
+            command.validate(),
+            Err(SpawnError::ProgramNotFound(err)) if err.get_errno() == libc::ENOENT
+        );
+        assert_matches!(
+            command.spawn(),
+            Err(SpawnError::Child { stage: SpawnStage::Exec, err })
+                if err.get_errno() == libc::ENOENT
+        );
+
+        let command = Command::new(cstr!("/bin/true/avfork-test"));
+        assert_matches!(
+            command.validate(),
+            Err(SpawnError::ProgramNotFound(err)) if err.get_errno() == libc::ENOTDIR
+        );
+
+        // Relative path is only resolved in the child after chdir.
+        let mut command = Command::new(cstr!("./avfork-test-nonexistent"));
+        command.current_dir(cstr!("/"));
+        assert_matches!(
+            command.spawn(),
+            Err(SpawnError::Child { stage: SpawnStage::Exec, err })
+                if err.get_errno() == libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn test_mount_proc_at_requires_pid_ns() {
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.mount_proc_at(cstr!("/proc"));
+        assert_matches!(
+            command.spawn(),
+            Err(SpawnError::Child { stage: SpawnStage::PidNs, err })
+                if err.get_errno() == libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_drop_policy_reap() {
+        let mut child = Command::new(cstr!("/bin/true")).spawn_with_pidfd().unwrap();
+        child.set_drop_policy(DropPolicy::Reap);
+        let pid = child.id();
+
+        // Wait for it to exit without reaping it.
+        let pidfd = child.get_pidfd().unwrap();
+        let options = libc::WEXITED | libc::WNOWAIT;
+        waitid(libc::P_PIDFD, pidfd.get_fd() as libc::id_t, options).unwrap();
+
+        drop(child);
+        assert_eq!(
+            waitid(libc::P_PID, pid as libc::id_t, libc::WEXITED | libc::WNOHANG)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ECHILD)
+        );
+    }
+
+    #[test]
+    fn test_readiness_fd() {
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command
+            .arg(cstr!("-c"))
+            .arg(cstr!("printf r >&\"$AVFORK_READY_FD\" && exec sleep 10"))
+            .readiness_fd();
+
+        let child = command.spawn_with_pidfd().unwrap();
+        assert!(child.wait_ready().unwrap());
+        child.kill_on_timeout().unwrap();
+        child.wait_blocking(&PidfdReaper).unwrap();
+
+        // Exits without signaling its readiness
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.readiness_fd();
+
+        let child = command.spawn_with_pidfd().unwrap();
+        assert!(!child.wait_ready().unwrap());
+        assert_eq!(child.wait_blocking(&PidfdReaper).unwrap().status(), WaitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_stop_on_exec() {
+        use std::sync::Arc;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        let stopped2 = stopped.clone();
+        command.stop_on_exec(move |pid| {
+            let options = libc::WSTOPPED | libc::WNOHANG | libc::WNOWAIT;
+            let siginfo = waitid(libc::P_PID, pid as libc::id_t, options)
+                .map_err(to_syscall_error)?
+                .unwrap();
+            let status = ExitInfo::from_siginfo(&siginfo).status();
+            stopped2.store(status == WaitStatus::Stopped(Signal::SIGSTOP), Ordering::Relaxed);
+            Ok(())
+        });
+
+        let child = command.spawn_with_pidfd().unwrap();
+        let info = child.wait_blocking(&PidfdReaper).unwrap();
+
+        assert!(stopped.load(Ordering::Relaxed));
+        assert_eq!(info.status(), WaitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_stop_on_exec_failure() {
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.stop_on_exec(|_pid| Err(SyscallError::new(libc::EPERM as u32)));
+        assert_matches!(
+            command.spawn(),
+            Err(SpawnError::Syscall(err)) if err.get_errno() == libc::EPERM
+        );
+
+        let mut command = Command::new(cstr!("/bin/true"));
+        command.stop_on_exec(|_pid| panic!("on_stop panicked"));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| command.spawn())).is_err());
+    }
+
+    #[test]
+    fn test_reset_thread_attributes() {
+        use crate::syscall::sigaddset;
+
+        let handle = std::thread::spawn(|| {
+            let mut set = sigemptyset();
+            sigaddset(&mut set, Signal::SIGUSR1);
+            sigprocmask(SigprocmaskHow::SIG_BLOCK, Some(&set)).unwrap();
+
+            let mut command = Command::new(cstr!("/bin/sh"));
+            command
+                .arg(cstr!("-c"))
+                .arg(cstr!("grep -q '^SigBlk:[[:space:]]*0*$' /proc/self/status"))
+                .reset_thread_attributes();
+
+            let child = command.spawn_with_pidfd().unwrap();
+            child.wait_blocking(&PidfdReaper).unwrap()
+        });
+
+        assert_eq!(handle.join().unwrap().status(), WaitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_ld_preload() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut command = Command::new(cstr!("true"));
+        command.ld_preload(cstr!("libavfork-test.so"));
+        command.validate().unwrap();
+
+        let validate_with_mode = |mode| {
+            let path = format!("/tmp/avfork-test-setid-{}-{:o}", std::process::id(), mode);
+            std::fs::write(&path, b"").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+
+            let mut command = Command::new(&CString::new(path.clone()).unwrap());
+            command.ld_preload(cstr!("libavfork-test.so"));
+            let result = command.validate();
+            std::fs::remove_file(&path).unwrap();
+
+            result
+        };
+
+        assert_matches!(validate_with_mode(0o4755), Err(SpawnError::PreloadIgnored));
+        assert_matches!(validate_with_mode(0o2755), Err(SpawnError::PreloadIgnored));
+        // setgid without group execute bit means mandatory locking
+        assert_matches!(validate_with_mode(0o2745), Ok(()));
+    }
+
+    #[test]
+    fn test_log_to() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let flags = FdFlags::O_CLOEXEC | FdFlags::O_APPEND;
+        let mode = Mode::S_IRUSR | Mode::S_IWUSR;
+        let file = FdBox::creatat(*tmp, cstr!("."), true, flags, true, false, mode).unwrap();
+
+        let mut command = Command::new(cstr!("/bin/sh"));
+        command
+            .arg(cstr!("-c"))
+            .arg(cstr!("echo out; echo err >&2; echo out"))
+            .log_to(file.dup(true).unwrap());
+        assert_eq!(command.spawn_and_wait().unwrap().status(), WaitStatus::Exited(0));
+
+        let mut buf = [0 as u8; 32];
+        file.lseek(std::io::SeekFrom::Start(0)).unwrap();
+        let cnt = file.read(&mut buf).unwrap();
+        assert_eq!(&buf[..cnt], b"out\nerr\nout\n");
+    }
+
+    #[test]
+    fn test_spawn_no_fd_leak() {
+        // Opened on first use and cached
+        dev_null(false).unwrap();
+        dev_null(true).unwrap();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    let command = || {
+                        let mut command = Command::new(cstr!("/bin/true"));
+                        command
+                            .stdin(all_stdio(0).swap_remove(i))
+                            .stdout(all_stdio(1).swap_remove(j))
+                            .stderr(all_stdio(2).swap_remove(k));
+                        command
+                    };
+
+                    assert_no_fd_leak(|| {
+                        let child = command().spawn_with_pidfd().unwrap();
+                        child.wait_blocking(&PidfdReaper).unwrap();
+                    });
 
+                    assert_no_fd_leak_except(|| command().spawn().unwrap(), child_fds);
+                }
+            }
+        }
+    }
+}
@@ -26,9 +26,26 @@ mod binding {
         Ok(fd as c_int)
     }
 
-    pub fn dup(oldfd: c_int) -> Result<c_int, SyscallError> {
-        let fd = toResult(unsafe { psys_dup(oldfd) } as i64)?;
-        Ok(fd as c_int)
+    pub fn fcntl(fd: c_int, cmd: c_int, arg: u64) -> Result<c_int, SyscallError> {
+        let ret = toResult(unsafe { psys_fcntl(fd, cmd, arg) } as i64)?;
+        Ok(ret as c_int)
+    }
+
+    pub fn prctl(option: c_int, arg2: u64, arg3: u64, arg4: u64, arg5: u64)
+        -> Result<c_int, SyscallError>
+    {
+        let ret = toResult(unsafe { psys_prctl(option, arg2, arg3, arg4, arg5) } as i64)?;
+        Ok(ret as c_int)
+    }
+
+    /// Returns the lowest available fd that is >= 0
+    pub fn dup(oldfd: c_int, cloexec: bool) -> Result<c_int, SyscallError> {
+        let cmd = if cloexec {
+            libc::F_DUPFD_CLOEXEC
+        } else {
+            libc::F_DUPFD
+        };
+        fcntl(oldfd, cmd, 0)
     }
     pub fn dup3(oldfd: c_int, newfd: c_int, flags: FdFlags)
         -> Result<c_int, SyscallError>
@@ -38,11 +55,16 @@ mod binding {
     }
 }
 
-use std::ops::Deref;
-pub use std::os::raw::{c_void, c_int, c_long, c_char};
+use std::ops::{Deref, DerefMut, Range};
+use std::convert::TryInto;
+
+use once_cell::sync::OnceCell;
+pub use std::os::raw::{c_void, c_int, c_uint, c_long, c_short, c_char};
 pub use std::ffi::CStr;
+use std::ffi::CString;
 use std::io::{Write, Read};
 use std::hint::unreachable_unchecked;
+use std::time::Duration;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 pub use binding::{sigset_t, pid_t, uid_t, gid_t};
@@ -90,6 +112,7 @@ bitflags! {
         const O_APPEND = libc::O_APPEND;
         const O_TRUNC = libc::O_TRUNC;
         const O_CLOEXEC = libc::O_CLOEXEC;
+        const O_DIRECTORY = libc::O_DIRECTORY;
 
         const O_ASYNC = libc::O_ASYNC;
         const O_DSYNC = libc::O_DSYNC;
@@ -142,6 +165,32 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct MsgFlags: c_int {
+        const NONE = 0;
+        /// Enable non-blocking operation for this call only.
+        const MSG_DONTWAIT = libc::MSG_DONTWAIT;
+        /// Do not generate `SIGPIPE` if the peer on a stream-oriented socket
+        /// has closed the connection.
+        const MSG_NOSIGNAL = libc::MSG_NOSIGNAL;
+        /// Return data from the beginning of the receive queue without removing
+        /// it from the queue.
+        const MSG_PEEK = libc::MSG_PEEK;
+        /// Block until the full request is satisfied.
+        const MSG_WAITALL = libc::MSG_WAITALL;
+    }
+}
+bitflags! {
+    pub struct SpliceFlags: c_uint {
+        /// Attempt to move pages instead of copying.
+        const SPLICE_F_MOVE = libc::SPLICE_F_MOVE;
+        /// Do not block on I/O.
+        const SPLICE_F_NONBLOCK = libc::SPLICE_F_NONBLOCK;
+        /// More data will be coming in a subsequent splice.
+        const SPLICE_F_MORE = libc::SPLICE_F_MORE;
+    }
+}
+
 macro_rules! impl_AsRawFd_for {
     ($t: ident) => (
         impl AsRawFd for $t {
@@ -188,6 +237,43 @@ impl FdBox {
         FdBox::openat_impl(dirfd, pathname, (accMode as i32) | flags.bits, 0)
     }
 
+    /// Same as `openat` with `O_NOFOLLOW`, except that `OpenError::SymlinkRejected`
+    /// is returned if the last component of `pathname` is a symlink.
+    ///
+    /// The only portable signal of that is `ELOOP`, which is also returned
+    /// if there are too many symlinks while resolving the other components,
+    /// so on `ELOOP`, the last component is checked via `statx` with
+    /// `AT_SYMLINK_NOFOLLOW`.
+    /// If it is replaced between `openat` and `statx`, then the result of the
+    /// check might be wrong, but the symlink is never followed either way.
+    ///
+    /// Symlinks in the other components are still followed.
+    pub fn openat_nosymlink(dirfd: FdPath, pathname: &CStr, accMode: AccessMode, flags: FdFlags)
+        -> Result<FdBox, OpenError>
+    {
+        let err = match FdBox::openat(dirfd, pathname, accMode, flags | FdFlags::O_NOFOLLOW) {
+            Ok(fd) => return Ok(fd),
+            Err(err) => err,
+        };
+        if err.get_errno() != libc::ELOOP {
+            return Err(OpenError::Syscall(err));
+        }
+
+        let flags = ExecveAtFlags::AT_SYMLINK_NOFOLLOW;
+        let mask = StatxMask::STATX_TYPE | StatxMask::STATX_MODE;
+        let is_symlink = statx(dirfd, pathname, flags, mask)
+            .ok()
+            .and_then(|stx| stx.get_mode())
+            .map(|mode| FileType::from_mode(mode as u32) == FileType::Symlink)
+            .unwrap_or(false);
+
+        if is_symlink {
+            Err(OpenError::SymlinkRejected)
+        } else {
+            Err(OpenError::Syscall(err))
+        }
+    }
+
     /// Open existing file.
     ///
     ///  * `dirfd` - can be `AT_FDCWD`
@@ -222,6 +308,66 @@ impl FdBox {
         FdBox::openat_impl(dirfd, pathname, flags, mode.bits)
     }
 
+    /// Open an anonymous, readable and writable regular file in directory `dir`
+    /// using `O_TMPFILE | O_RDWR | O_CLOEXEC`.
+    ///
+    ///  * `dir` - must refer to a directory, can be `AT_FDCWD`
+    ///
+    /// The returned file has no name: it is removed automatically once the last
+    /// fd referring to it is closed, unless it is given a name via
+    /// `link_tmpfile` before that.
+    ///
+    /// Not all filesystems support `O_TMPFILE`, in which case `EOPNOTSUPP` is
+    /// returned.
+    ///
+    /// Check manpage for openat for more documentation.
+    pub fn tmpfile(dir: FdPath, mode: Mode) -> Result<FdBox, SyscallError> {
+        FdBox::creatat(dir, cstr!("."), true, FdFlags::O_CLOEXEC, true, false, mode)
+    }
+
+    /// Give the anonymous file returned by `tmpfile` the name `pathname` in
+    /// `dir`, so that it is kept after the fd is closed.
+    ///
+    ///  * `dir` - can be `AT_FDCWD`, must be on the same filesystem as the file
+    ///
+    /// `pathname` must not exist, otherwise `EEXIST` is returned, so the file
+    /// is never observed under its name before it is completely written.
+    ///
+    /// The file is linked via its `/proc/self/fd` entry, which does not require
+    /// `CAP_DAC_READ_SEARCH` unlike `AT_EMPTY_PATH`, thus `/proc` must be
+    /// mounted.
+    ///
+    /// Check manpage for linkat for more documentation.
+    pub fn link_tmpfile(&self, dir: FdPath, pathname: &CStr) -> Result<(), SyscallError> {
+        let mut buf = [0 as u8; 32];
+        let cap = buf.len() - 1;
+
+        let len = {
+            // Leave the last byte as the nul terminator
+            let mut cursor = &mut buf[..cap];
+            if write!(cursor, "/proc/self/fd/{}", self.get_fd()).is_err() {
+                return Err(SyscallError::new(libc::ENAMETOOLONG as u32));
+            }
+            cap - cursor.len()
+        };
+
+        // buf is zero-initialized, so it is nul-terminated
+        let proc_path = CStr::from_bytes_with_nul(&buf[..=len])
+            .map_err(|_| SyscallError::new(libc::EINVAL as u32))?;
+
+        toResult(unsafe {
+            binding::psys_linkat(
+                AT_FDCWD.get_fd(),
+                proc_path.as_ptr(),
+                dir.get_fd(),
+                pathname.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW
+            )
+        } as i64)?;
+
+        Ok(())
+    }
+
     /// Returns (read end, write end)
     ///
     /// Check manpage for pipe2 for more documentation.
@@ -233,6 +379,89 @@ impl FdBox {
 
         Ok(unsafe {( FdBox::from_raw(pipefd[0]), FdBox::from_raw(pipefd[1]) )})
     }
+
+    /// Returns a pair of connected sockets, which can be used for bidirectional
+    /// communication between the parent and the child.
+    ///
+    /// Check manpage for socketpair for more documentation.
+    pub fn socketpair(domain: SockDomain, sock_type: SockType, cloexec: bool, nonblock: bool)
+        -> Result<(FdBox, FdBox), SyscallError>
+    {
+        let mut sock_type = sock_type as c_int;
+        if cloexec {
+            sock_type |= libc::SOCK_CLOEXEC;
+        }
+        if nonblock {
+            sock_type |= libc::SOCK_NONBLOCK;
+        }
+
+        #[allow(clippy::unnecessary_cast)]
+        let mut sv = [-1 as c_int; 2];
+
+        toResult(unsafe {
+            binding::psys_socketpair(domain as c_int, sock_type, 0, sv.as_mut_ptr())
+        } as i64)?;
+
+        Ok(unsafe {( FdBox::from_raw(sv[0]), FdBox::from_raw(sv[1]) )})
+    }
+
+    /// Create an anonymous file that lives in memory, which is opened for
+    /// both reading and writing.
+    ///
+    /// `name` is only used as the target of `/proc/self/fd/*` for debugging,
+    /// e.g. `memfd:name`, and multiple memfds can have the same name.
+    ///
+    /// Seals can only be added via `Fd::add_seals` if it is created with
+    /// `MemfdFlags::MFD_ALLOW_SEALING`.
+    ///
+    /// Check manpage for memfd_create for more documentation.
+    pub fn memfd_create(name: &CStr, flags: MemfdFlags) -> Result<FdBox, SyscallError> {
+        let fd = toResult(unsafe {
+            binding::psys_memfd_create(name.as_ptr(), flags.bits)
+        } as i64)?;
+        Ok(unsafe { FdBox::from_raw(fd as c_int) })
+    }
+}
+
+bitflags! {
+    pub struct MemfdFlags: c_uint {
+        const MFD_CLOEXEC = libc::MFD_CLOEXEC;
+        /// Allow sealing operations on the memfd.
+        const MFD_ALLOW_SEALING = libc::MFD_ALLOW_SEALING;
+    }
+}
+
+bitflags! {
+    pub struct SealFlags: c_int {
+        /// Prevent further seals from being added.
+        const F_SEAL_SEAL = libc::F_SEAL_SEAL;
+        /// Prevent the file from shrinking.
+        const F_SEAL_SHRINK = libc::F_SEAL_SHRINK;
+        /// Prevent the file from growing.
+        const F_SEAL_GROW = libc::F_SEAL_GROW;
+        /// Prevent the content of the file from being modified.
+        const F_SEAL_WRITE = libc::F_SEAL_WRITE;
+    }
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug)]
+pub enum SockDomain {
+    AF_UNIX = libc::AF_UNIX,
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug)]
+pub enum SockType {
+    /// Sequenced, reliable, two-way, connection-based byte streams.
+    SOCK_STREAM = libc::SOCK_STREAM,
+    /// Connectionless, unreliable messages of a fixed maximum length.
+    SOCK_DGRAM = libc::SOCK_DGRAM,
+    /// Sequenced, reliable, two-way, connection-based datagrams of fixed
+    /// maximum length.
+    SOCK_SEQPACKET = libc::SOCK_SEQPACKET,
 }
 impl Drop for FdBox {
     fn drop(&mut self) {
@@ -288,177 +517,1778 @@ impl Fd {
             binding::psys_write(self.get_fd(), buf_ptr, buf_len)
         })? as usize)
     }
-}
-/// impl Write for Fd so that write!, writeln! and other methods that
-/// requires trait Write can be called upon it.
-impl Write for Fd {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        Ok(Fd::write(self, buf)?)
-    }
 
-    fn flush(&mut self) -> std::io::Result<()> {
+    /// Set or clear `O_NONBLOCK` on the open file description referred to by
+    /// the fd, which is shared among all of its duplicates.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    pub fn set_nonblocking(&self, nonblock: bool) -> Result<(), SyscallError> {
+        let flags = binding::fcntl(self.get_fd(), libc::F_GETFL, 0)?;
+        let flags = if nonblock {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        binding::fcntl(self.get_fd(), libc::F_SETFL, flags as u64)?;
         Ok(())
     }
-}
-/// impl Read for Fd so that any method that requires trait Write can be called upon it.
-impl Read for Fd {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        Ok(Fd::read(self, buf)?)
-    }
-}
-
-// Here it relies on the compiler to check that i32 == c_int
-#[repr(i32)]
-#[derive(Copy, Clone, Debug)]
-pub enum FdPathMode {
-    anyPath   = 0,
-    directory = libc::O_DIRECTORY,
-    symlink   = libc::O_NOFOLLOW,
-}
 
-#[derive(Debug)]
-pub struct FdPathBox {
-    fd: FdPath,
-}
-impl FromRaw for FdPathBox {
-    /// # Safety
-    ///  * `fd` - must be a valid fd that is opened with `O_PATH`
-    unsafe fn from_raw(fd: c_int) -> FdPathBox {
-        FdPathBox { fd: FdPath::from_raw(fd) }
-    }
-}
-impl_AsRawFd_for!(FdPathBox);
-impl FdPathBox {
-    pub fn openat(dirfd: FdPath, pathname: &CStr, mode: FdPathMode, cloexec: bool)
-        -> Result<FdPathBox, SyscallError>
-    {
+    /// Set or clear `FD_CLOEXEC` on the fd, which is not shared with its
+    /// duplicates.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    pub fn set_cloexec(&self, cloexec: bool) -> Result<(), SyscallError> {
+        let flags = binding::fcntl(self.get_fd(), libc::F_GETFD, 0)?;
         let flags = if cloexec {
-            libc::O_CLOEXEC
+            flags | libc::FD_CLOEXEC
         } else {
-            0
-        } | libc::O_PATH | mode as c_int;
-
-        Ok(unsafe {
-            FdPathBox::from_raw(binding::openat_wrapper(dirfd, pathname, flags, 0)?)
-        })
+            flags & !libc::FD_CLOEXEC
+        };
+        binding::fcntl(self.get_fd(), libc::F_SETFD, flags as u64)?;
+        Ok(())
     }
-}
-impl Deref for FdPathBox {
-    type Target = FdPath;
 
-    fn deref(&self) ->&Self::Target {
-        &self.fd
+    /// Write one byte to the fd, which is the write end of a readiness pipe,
+    /// to notify the reader that the writer is initialized.
+    ///
+    /// A program spawned with `process::Command::readiness_fd` finds the fd
+    /// number in the environment variable `process::READY_FD_ENV`.
+    ///
+    /// `EPIPE` is returned if the read end is closed.
+    pub fn signal_ready(&self) -> Result<(), SyscallError> {
+        self.write_all(b"r")
     }
-}
-impl Drop for FdPathBox {
-    fn drop(&mut self) {
-        let ret = unsafe {
-            binding::psys_close(self.get_fd()) as i64
-        };
 
-        if cfg!(debug_assertions) {
-            expect!(toResult(ret), "Failed to release the FdPathBox {:#?}", self);
-        }
+    /// Whether `FD_CLOEXEC` is set on the fd.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    pub fn is_cloexec(&self) -> Result<bool, SyscallError> {
+        let flags = binding::fcntl(self.get_fd(), libc::F_GETFD, 0)?;
+        Ok((flags & libc::FD_CLOEXEC) != 0)
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub struct FdPath {
-    fd: c_int,
-}
-impl FromRaw for FdPath {
-    /// # Safety
-    ///  * `fd` - must be a valid fd that is opened with `O_PATH`
-    unsafe fn from_raw(fd: c_int) -> FdPath {
-        FdPath { fd }
+    /// Add `seals` to the memfd referred to by the fd, which must be created
+    /// with `MemfdFlags::MFD_ALLOW_SEALING`, otherwise `EPERM` is returned.
+    ///
+    /// `SealFlags::F_SEAL_WRITE` fails with `EBUSY` if the memfd has any
+    /// writable shared mapping.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    pub fn add_seals(&self, seals: SealFlags) -> Result<(), SyscallError> {
+        binding::fcntl(self.get_fd(), libc::F_ADD_SEALS, seals.bits as u64)?;
+        Ok(())
     }
-}
-impl FdBasicOp for FdPath {
-    type BoxedFd = FdPathBox;
 
-    fn get_fd(&self) -> c_int {
-        self.fd
+    /// Reposition the offset of the open file description referred to by
+    /// the fd, which is shared among all of its duplicates.
+    ///
+    /// Returns the new offset from the start of the file.
+    ///
+    /// Check manpage for lseek for more documentation.
+    pub fn lseek(&self, pos: std::io::SeekFrom) -> Result<u64, SyscallError> {
+        let (offset, whence) = match pos {
+            std::io::SeekFrom::Start(offset) => (offset as i64, libc::SEEK_SET),
+            std::io::SeekFrom::End(offset) => (offset, libc::SEEK_END),
+            std::io::SeekFrom::Current(offset) => (offset, libc::SEEK_CUR),
+        };
+        toResult(unsafe { binding::psys_lseek(self.get_fd(), offset, whence) } as i64)
     }
-}
-impl_AsRawFd_for!(FdPath);
-impl FdPath {
-    /// Pre condition: self is opened in dir mode
-    /// Check manpage for fchdir for more documentation.
-    pub fn fchdir(&self) -> Result<(), SyscallError> {
-        let fd = self.get_fd();
 
-        toResult(unsafe { binding::psys_fchdir(fd) } as i64)?;
+    /// Read directory entries of the directory referred to by the fd into
+    /// `buffer`, which can then be iterated via `Dirents::new`.
+    ///
+    /// Returns 0 on end of directory.
+    ///
+    /// Check manpage for getdents64 for more documentation.
+    pub fn getdents(&self, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        let buf_ptr = buffer.as_mut_ptr() as *mut c_void;
+        let buf_len = buffer.len() as u64;
+        Ok(toResult(unsafe {
+            binding::psys_getdents64(self.get_fd(), buf_ptr, buf_len)
+        })? as usize)
+    }
 
+    /// Make the terminal referred to by the fd the controlling terminal of the
+    /// calling process via `ioctl(fd, TIOCSCTTY, steal)`.
+    ///
+    /// The calling process must be a session leader without a controlling
+    /// terminal, e.g. right after `setsid`, otherwise `EPERM` is returned.
+    ///
+    ///  * `steal` - if true and the terminal is already the controlling
+    ///    terminal of another session, then it is stolen from that session,
+    ///    which requires `CAP_SYS_ADMIN`.
+    ///
+    /// Check manpage for ioctl_tty for more documentation.
+    pub fn set_controlling_tty(&self, steal: bool) -> Result<(), SyscallError> {
+        toResult(unsafe {
+            binding::psys_ioctl(self.get_fd(), libc::TIOCSCTTY as _, steal as u64)
+        } as i64)?;
         Ok(())
     }
-}
-
-pub trait FromRaw {
-    /// # Safety
-    ///  * `fd` - must be a valid fd
-    unsafe fn from_raw(fd: c_int) -> Self;
-}
-pub trait FdBasicOp {
-    type BoxedFd: FromRaw;
 
-    fn get_fd(&self) -> c_int;
+    /// Read from a non-blocking fd, retrying on `EINTR`.
+    ///
+    /// Returns:
+    ///  - `Ok(None)` if no data is available now (`EAGAIN`),
+    ///  - `Ok(Some(0))` on EOF, e.g. the write end of the pipe is closed,
+    ///  - `Ok(Some(n))` if n bytes is read.
+    pub fn read_nonblocking(&self, buffer: &mut [u8]) -> Result<Option<usize>, SyscallError> {
+        match autorestart(|| self.read(buffer)) {
+            Ok(cnt) => Ok(Some(cnt)),
+            Err(err) if err.get_errno() == libc::EAGAIN => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-    /// Check manpage for dup3 for more documentation.
-    fn dup3(&self, newfd: c_int, flags: FdFlags) -> Result<Self::BoxedFd, SyscallError> {
-        use binding::dup3;
+    /// Same as `write`, but `self` must be a socket.
+    ///
+    /// Passing `MsgFlags::MSG_NOSIGNAL` prevents `SIGPIPE` from being generated
+    /// when the peer has closed the connection, `EPIPE` is returned instead.
+    ///
+    /// Check manpage for send for more documentation.
+    pub fn send(&self, buffer: &[u8], flags: MsgFlags) -> Result<usize, SyscallError> {
+        let buf_ptr = buffer.as_ptr() as *const c_void;
+        let buf_len = buffer.len() as u64;
+        Ok(toResult(unsafe {
+            binding::psys_sendto(
+                self.get_fd(), buf_ptr, buf_len, flags.bits,
+                std::ptr::null(), 0
+            )
+        } as i64)? as usize)
+    }
 
-        Ok(unsafe { Self::BoxedFd::from_raw(dup3(self.get_fd(), newfd, flags)?) })
+    /// Same as `read`, but `self` must be a socket.
+    ///
+    /// Check manpage for recv for more documentation.
+    pub fn recv(&self, buffer: &mut [u8], flags: MsgFlags) -> Result<usize, SyscallError> {
+        let buf_ptr = buffer.as_mut_ptr() as *mut c_void;
+        let buf_len = buffer.len() as u64;
+        Ok(toResult(unsafe {
+            binding::psys_recvfrom(
+                self.get_fd(), buf_ptr, buf_len, flags.bits,
+                std::ptr::null_mut(), std::ptr::null_mut()
+            )
+        } as i64)? as usize)
     }
 
-    /// Check manpage for dup for more documentation.
-    fn dup(&self) -> Result<Self::BoxedFd, SyscallError> {
-        use binding::dup;
+    /// Block until any of `events` is available on the fd.
+    ///
+    ///  * `timeout` - If `None`, then wait indefinitely.
+    ///    It is restarted from scratch if interrupted by a signal.
+    ///
+    /// Returns the events that are available, which is empty if timed out.
+    pub(crate) fn poll(&self, events: c_short, timeout: Option<Duration>)
+        -> Result<c_short, SyscallError>
+    {
+        let mut pollfds = [libc::pollfd {
+            fd: self.get_fd(),
+            events,
+            revents: 0
+        }];
+
+        ppoll(&mut pollfds, timeout)?;
 
-        Ok(unsafe { Self::BoxedFd::from_raw(dup(self.get_fd())?) })
+        Ok(pollfds[0].revents)
     }
-}
 
-pub const AT_FDCWD: FdPath = FdPath { fd: binding::AT_FDCWD };
-pub const STDOUT: Fd = Fd { fd: 1 };
-pub const STDERR: Fd = Fd { fd: 2 };
+    /// Same as `write_all`, but `SIGXFSZ` is ignored first via `signal_ignore`,
+    /// so that writing beyond `RLIMIT_FSIZE` fails with `EFBIG` instead of
+    /// killing the calling process.
+    ///
+    /// `RLIMIT_FSIZE` can be lowered via `prlimit`, after which the bytes
+    /// written up to the limit are kept and `EFBIG` is returned for the rest.
+    ///
+    /// The disposition is changed for the whole process and is preserved
+    /// across `execve`.
+    pub fn write_all_catch_fsize(&self, buffer: &[u8]) -> Result<(), SyscallError> {
+        signal_ignore(Signal::SIGXFSZ)?;
+        self.write_all(buffer)
+    }
 
-/// Check manpage for chdir for more documentation.
-pub fn chdir(pathname: &CStr) -> Result<(), SyscallError>
-{
-    let pathname = pathname.as_ptr();
-    toResult(unsafe { binding::psys_chdir(pathname) as i64 })?;
-    Ok(())
-}
+    /// Write all of `buffer`, retrying on partial write and `EINTR`.
+    ///
+    /// If the fd is non-blocking, then it waits for the fd to become writable
+    /// on `EAGAIN`.
+    pub fn write_all(&self, mut buffer: &[u8]) -> Result<(), SyscallError> {
+        while !buffer.is_empty() {
+            match self.write(buffer) {
+                Ok(cnt) => buffer = &buffer[cnt..],
+                Err(err) if err.get_errno() == libc::EINTR => continue,
+                Err(err) if err.get_errno() == libc::EAGAIN => {
+                    self.poll(libc::POLLOUT, None)?;
+                },
+                Err(err) => return Err(err),
+            };
+        }
 
-pub fn get_pagesz() -> usize {
-    unsafe { binding::psys_get_pagesz() as usize }
-}
+        Ok(())
+    }
 
-pub fn setresuid(ruid: uid_t, euid: uid_t, suid: uid_t) -> Result<(), SyscallError> {
-    unsafe {
-        toResult(binding::psys_setresuid(ruid, euid, suid) as i64)?;
+    /// Move at most `len` bytes from `self` to `out` without copying between
+    /// kernel address space and user address space.
+    ///
+    /// One of `self` and `out` must be a pipe, otherwise `EINVAL` is returned.
+    ///
+    /// Returns number of bytes moved, 0 on EOF.
+    ///
+    /// Check manpage for splice for more documentation.
+    pub fn splice(&self, out: &Fd, len: usize, flags: SpliceFlags)
+        -> Result<usize, SyscallError>
+    {
+        Ok(toResult(unsafe {
+            binding::psys_splice(
+                self.get_fd(),
+                std::ptr::null_mut(),
+                out.get_fd(),
+                std::ptr::null_mut(),
+                len as u64,
+                flags.bits
+            )
+        } as i64)? as usize)
+    }
+
+    /// Duplicate at most `len` bytes from pipe `self` to pipe `out` without
+    /// consuming them, so a subsequent `splice` or `read` on `self` still sees
+    /// the same data.
+    ///
+    /// Both `self` and `out` must be pipes, otherwise `EINVAL` is returned.
+    ///
+    /// If `SpliceFlags::SPLICE_F_NONBLOCK` is passed, then `EAGAIN` is returned
+    /// instead of blocking when `self` is empty or `out` is full, though
+    /// it still might block if the fds themselves are blocking.
+    ///
+    /// Returns number of bytes duplicated, 0 if there is no data to duplicate
+    /// and the write end of `self` is closed.
+    ///
+    /// Check manpage for tee for more documentation.
+    pub fn tee(&self, out: &Fd, len: usize, flags: SpliceFlags)
+        -> Result<usize, SyscallError>
+    {
+        Ok(toResult(unsafe {
+            binding::psys_tee(self.get_fd(), out.get_fd(), len as u64, flags.bits)
+        } as i64)? as usize)
+    }
+
+    /// Copy everything from `self` to `dst` until EOF.
+    ///
+    /// `splice` is preferred if one of them is a pipe according to `fstat_type`,
+    /// otherwise it falls back to `read` into a buffer on stack and `write_all`
+    /// to `dst`.
+    ///
+    /// If `self` or `dst` is non-blocking, then it would wait for them to become
+    /// ready on `EAGAIN`, thus a slow `dst` would only slows down the copy.
+    ///
+    /// Returns number of bytes copied.
+    ///
+    /// # Errors
+    ///
+    /// If `dst` is a pipe or socket whose read end is closed, then `EPIPE` is
+    /// returned, which the caller can treat as a clean termination.
+    /// Note that unless `SIGPIPE` is ignored or blocked, the process would be
+    /// killed by `SIGPIPE` before `EPIPE` can be returned.
+    pub fn copy_all_to(&self, dst: &Fd) -> Result<u64, SyscallError> {
+        const SPLICE_CHUNK_SZ: usize = 1 << 16;
+
+        let mut total: u64 = 0;
+
+        let has_pipe = self.fstat_type()? == FileType::Fifo || dst.fstat_type()? == FileType::Fifo;
+
+        if has_pipe {
+            loop {
+                let result = autorestart(|| {
+                    self.splice(dst, SPLICE_CHUNK_SZ, SpliceFlags::SPLICE_F_MOVE)
+                });
+
+                match result {
+                    Ok(0) => return Ok(total),
+                    Ok(cnt) => total += cnt as u64,
+                    // Neither of them is pipe, or the fd does not support splice
+                    Err(err) if err.get_errno() == libc::EINVAL => break,
+                    // Let the read/write loop below handles the non-blocking fds
+                    Err(err) if err.get_errno() == libc::EAGAIN => break,
+                    Err(err) => return Err(err),
+                };
+            }
+        }
+
+        let mut buffer = [0 as u8; 4096];
+
+        loop {
+            let cnt = match self.read(&mut buffer) {
+                Ok(0) => return Ok(total),
+                Ok(cnt) => cnt,
+                Err(err) if err.get_errno() == libc::EINTR => continue,
+                Err(err) if err.get_errno() == libc::EAGAIN => {
+                    self.poll(libc::POLLIN, None)?;
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+
+            dst.write_all(&buffer[..cnt])?;
+            total += cnt as u64;
+        }
+    }
+
+    /// Read into `buf` at `offset` of a file opened with `O_DIRECT`, bypassing
+    /// the page cache.
+    ///
+    /// `offset` and the length of `buf` must be multiples of `dio_alignment`,
+    /// otherwise `EINVAL` is returned.
+    ///
+    /// Returns number of bytes read, 0 on EOF.
+    ///
+    /// Check manpage for pread and open for more documentation.
+    pub fn read_direct(&self, buf: &mut AlignedBuf, offset: u64) -> Result<usize, SyscallError> {
+        Ok(toResult(unsafe {
+            binding::psys_pread64(
+                self.get_fd(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u64,
+                offset as i64
+            )
+        } as i64)? as usize)
+    }
+
+    /// Write `buf` at `offset` of a file opened with `O_DIRECT`, bypassing
+    /// the page cache.
+    ///
+    /// `offset` and the length of `buf` must be multiples of `dio_alignment`,
+    /// otherwise `EINVAL` is returned.
+    ///
+    /// Returns number of bytes written.
+    ///
+    /// Check manpage for pwrite and open for more documentation.
+    pub fn write_direct(&self, buf: &AlignedBuf, offset: u64) -> Result<usize, SyscallError> {
+        Ok(toResult(unsafe {
+            binding::psys_pwrite64(
+                self.get_fd(),
+                buf.as_ptr() as *const c_void,
+                buf.len() as u64,
+                offset as i64
+            )
+        } as i64)? as usize)
+    }
+
+    /// Returns the alignment required for direct I/O on the file, which is the
+    /// larger one of the memory alignment and the offset alignment reported by
+    /// `statx` with `STATX_DIOALIGN`.
+    ///
+    /// Returns `EOPNOTSUPP` if the kernel (requires linux 6.1) or the filesystem
+    /// does not report it, and `EINVAL` if direct I/O is not supported on the
+    /// file.
+    pub fn dio_alignment(&self) -> Result<usize, SyscallError> {
+        // statx accepts any fd as long as AT_EMPTY_PATH is passed.
+        let fd = unsafe { FdPath::from_raw(self.get_fd()) };
+        let stx = statx(fd, cstr!(""), ExecveAtFlags::AT_EMPTY_PATH, StatxMask::STATX_DIOALIGN)?;
+
+        let (mem_align, offset_align) = match stx.get_dio_align() {
+            Some(align) => align,
+            None => return Err(SyscallError::new(libc::EOPNOTSUPP as u32)),
+        };
+
+        let align = mem_align.max(offset_align) as usize;
+        if align == 0 {
+            Err(SyscallError::new(libc::EINVAL as u32))
+        } else {
+            Ok(align)
+        }
+    }
+}
+
+/// A buffer that is aligned to page size, which satisfies the alignment
+/// required by direct I/O.
+///
+/// It is allocated via anonymous `mmap`.
+#[derive(Debug)]
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+impl AlignedBuf {
+    /// Allocate a zeroed buffer of `len` bytes.
+    ///
+    /// `len` should be a multiple of `Fd::dio_alignment` for the buffer to be
+    /// usable for direct I/O.
+    pub fn new(len: usize) -> Result<AlignedBuf, SyscallError> {
+        if len == 0 {
+            return Err(SyscallError::new(libc::EINVAL as u32));
+        }
+
+        let addr = toResult(unsafe {
+            binding::psys_mmap(
+                std::ptr::null_mut(),
+                len as u64,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0
+            )
+        } as i64)?;
+
+        Ok(AlignedBuf { ptr: addr as usize as *mut u8, len })
+    }
+}
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe {
+            binding::psys_munmap(self.ptr as *mut c_void, self.len as u64);
+        }
+    }
+}
+
+bitflags! {
+    /// Fields of `Statx` to be requested or actually returned.
+    pub struct StatxMask: u32 {
+        const STATX_TYPE = libc::STATX_TYPE;
+        const STATX_MODE = libc::STATX_MODE;
+        const STATX_NLINK = libc::STATX_NLINK;
+        const STATX_UID = libc::STATX_UID;
+        const STATX_GID = libc::STATX_GID;
+        const STATX_ATIME = libc::STATX_ATIME;
+        const STATX_MTIME = libc::STATX_MTIME;
+        const STATX_CTIME = libc::STATX_CTIME;
+        const STATX_INO = libc::STATX_INO;
+        const STATX_SIZE = libc::STATX_SIZE;
+        const STATX_BLOCKS = libc::STATX_BLOCKS;
+        /// All of the above
+        const STATX_BASIC_STATS = libc::STATX_BASIC_STATS;
+        /// Requires linux 4.11
+        const STATX_BTIME = libc::STATX_BTIME;
+        /// Requires linux 5.8
+        const STATX_MNT_ID = 0x00001000;
+        /// Requires linux 6.1
+        const STATX_DIOALIGN = 0x00002000;
+    }
+}
+
+/// `struct statx_timestamp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct StatxTimestamp {
+    tv_sec: i64,
+    tv_nsec: u32,
+    __reserved: i32,
+}
+impl StatxTimestamp {
+    /// Seconds since the Epoch, which is negative for time before the Epoch.
+    pub fn get_sec(&self) -> i64 {
+        self.tv_sec
+    }
+
+    /// Nanoseconds since `get_sec`.
+    pub fn get_nsec(&self) -> u32 {
+        self.tv_nsec
+    }
+}
+
+/// `struct statx` used by the kernel, which includes fields that are newer
+/// than the one in libc.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct RawStatx {
+    stx_mask: u32,
+    stx_blksize: u32,
+    stx_attributes: u64,
+    stx_nlink: u32,
+    stx_uid: u32,
+    stx_gid: u32,
+    stx_mode: u16,
+    __spare0: [u16; 1],
+    stx_ino: u64,
+    stx_size: u64,
+    stx_blocks: u64,
+    stx_attributes_mask: u64,
+    stx_atime: StatxTimestamp,
+    stx_btime: StatxTimestamp,
+    stx_ctime: StatxTimestamp,
+    stx_mtime: StatxTimestamp,
+    stx_rdev_major: u32,
+    stx_rdev_minor: u32,
+    stx_dev_major: u32,
+    stx_dev_minor: u32,
+    stx_mnt_id: u64,
+    stx_dio_mem_align: u32,
+    stx_dio_offset_align: u32,
+    __spare3: [u64; 12],
+}
+
+/// Returned by `statx`.
+///
+/// The kernel might return fewer fields than requested, e.g. `btime` is not
+/// supported by every filesystem, so accessors of fields selected by
+/// `StatxMask` return `None` if the field is not returned.
+#[derive(Copy, Clone, Debug)]
+pub struct Statx {
+    stx: RawStatx,
+}
+impl Statx {
+    fn get_if(&self, mask: StatxMask) -> Option<&RawStatx> {
+        if self.get_mask().contains(mask) {
+            Some(&self.stx)
+        } else {
+            None
+        }
+    }
+
+    /// Fields that are actually returned.
+    pub fn get_mask(&self) -> StatxMask {
+        StatxMask::from_bits_truncate(self.stx.stx_mask)
+    }
+
+    /// Preferred block size for I/O, which is always returned.
+    pub fn get_blksize(&self) -> u32 {
+        self.stx.stx_blksize
+    }
+
+    /// File type and mode, requires `STATX_TYPE | STATX_MODE`.
+    pub fn get_mode(&self) -> Option<u16> {
+        self.get_if(StatxMask::STATX_TYPE | StatxMask::STATX_MODE).map(|stx| stx.stx_mode)
+    }
+
+    pub fn get_nlink(&self) -> Option<u32> {
+        self.get_if(StatxMask::STATX_NLINK).map(|stx| stx.stx_nlink)
+    }
+
+    pub fn get_uid(&self) -> Option<uid_t> {
+        self.get_if(StatxMask::STATX_UID).map(|stx| stx.stx_uid)
+    }
+
+    pub fn get_gid(&self) -> Option<gid_t> {
+        self.get_if(StatxMask::STATX_GID).map(|stx| stx.stx_gid)
+    }
+
+    pub fn get_ino(&self) -> Option<u64> {
+        self.get_if(StatxMask::STATX_INO).map(|stx| stx.stx_ino)
+    }
+
+    pub fn get_size(&self) -> Option<u64> {
+        self.get_if(StatxMask::STATX_SIZE).map(|stx| stx.stx_size)
+    }
+
+    /// Number of 512-byte blocks allocated.
+    pub fn get_blocks(&self) -> Option<u64> {
+        self.get_if(StatxMask::STATX_BLOCKS).map(|stx| stx.stx_blocks)
+    }
+
+    pub fn get_atime(&self) -> Option<StatxTimestamp> {
+        self.get_if(StatxMask::STATX_ATIME).map(|stx| stx.stx_atime)
+    }
+
+    /// Time of creation of the file.
+    pub fn get_btime(&self) -> Option<StatxTimestamp> {
+        self.get_if(StatxMask::STATX_BTIME).map(|stx| stx.stx_btime)
+    }
+
+    pub fn get_ctime(&self) -> Option<StatxTimestamp> {
+        self.get_if(StatxMask::STATX_CTIME).map(|stx| stx.stx_ctime)
+    }
+
+    pub fn get_mtime(&self) -> Option<StatxTimestamp> {
+        self.get_if(StatxMask::STATX_MTIME).map(|stx| stx.stx_mtime)
+    }
+
+    /// Id of the mount containing the file.
+    pub fn get_mnt_id(&self) -> Option<u64> {
+        self.get_if(StatxMask::STATX_MNT_ID).map(|stx| stx.stx_mnt_id)
+    }
+
+    /// Returns (memory alignment, file offset alignment) required for direct
+    /// I/O, which are both 0 if direct I/O is not supported on the file.
+    pub fn get_dio_align(&self) -> Option<(u32, u32)> {
+        self.get_if(StatxMask::STATX_DIOALIGN)
+            .map(|stx| (stx.stx_dio_mem_align, stx.stx_dio_offset_align))
+    }
+
+    /// Returns (major, minor) of the device containing the file, which is
+    /// always returned.
+    pub fn get_dev(&self) -> (u32, u32) {
+        (self.stx.stx_dev_major, self.stx.stx_dev_minor)
+    }
+
+    /// Returns (major, minor) of the device the file represents, if it is a
+    /// device file, which is always returned.
+    pub fn get_rdev(&self) -> (u32, u32) {
+        (self.stx.stx_rdev_major, self.stx.stx_rdev_minor)
+    }
+}
+
+/// Get extended metadata of the file.
+///
+///  * `dirfd` - can be `AT_FDCWD`
+///  * `flags` - pass `AT_EMPTY_PATH` with an empty `pathname` to get the
+///    metadata of `dirfd` itself.
+///  * `mask` - the fields requested.
+///
+/// The kernel might return fewer fields than requested in `mask`, so
+/// `Statx::get_mask` must be checked before reading optional fields like
+/// btime, which is done by the accessors of `Statx`.
+///
+/// Requires linux 4.11.
+///
+/// Check manpage for statx for more documentation.
+pub fn statx(dirfd: FdPath, pathname: &CStr, flags: ExecveAtFlags, mask: StatxMask)
+    -> Result<Statx, SyscallError>
+{
+    let mut stx = std::mem::MaybeUninit::<RawStatx>::zeroed();
+
+    toResult(unsafe {
+        binding::psys_statx(
+            dirfd.get_fd(),
+            pathname.as_ptr(),
+            flags.bits,
+            mask.bits,
+            stx.as_mut_ptr() as *mut c_void
+        )
+    } as i64)?;
+
+    Ok(Statx { stx: unsafe { stx.assume_init() } })
+}
+/// impl Write for Fd so that write!, writeln! and other methods that
+/// requires trait Write can be called upon it.
+impl Write for Fd {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(Fd::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+/// impl Read for Fd so that any method that requires trait Write can be called upon it.
+impl Read for Fd {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(Fd::read(self, buf)?)
+    }
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug)]
+pub enum FdPathMode {
+    anyPath   = 0,
+    directory = libc::O_DIRECTORY,
+    symlink   = libc::O_NOFOLLOW,
+}
+
+/// Returned by `FdBox::openat_nosymlink`.
+#[derive(Debug)]
+pub enum OpenError {
+    /// The last component of the path is a symlink.
+    SymlinkRejected,
+    Syscall(SyscallError),
+}
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::SymlinkRejected => write!(f, "The last component of the path is a symlink"),
+            OpenError::Syscall(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FdPathBox {
+    fd: FdPath,
+}
+impl FromRaw for FdPathBox {
+    /// # Safety
+    ///  * `fd` - must be a valid fd that is opened with `O_PATH`
+    unsafe fn from_raw(fd: c_int) -> FdPathBox {
+        FdPathBox { fd: FdPath::from_raw(fd) }
+    }
+}
+impl_AsRawFd_for!(FdPathBox);
+impl FdPathBox {
+    pub fn openat(dirfd: FdPath, pathname: &CStr, mode: FdPathMode, cloexec: bool)
+        -> Result<FdPathBox, SyscallError>
+    {
+        let flags = if cloexec {
+            libc::O_CLOEXEC
+        } else {
+            0
+        } | libc::O_PATH | mode as c_int;
+
+        Ok(unsafe {
+            FdPathBox::from_raw(binding::openat_wrapper(dirfd, pathname, flags, 0)?)
+        })
+    }
+}
+impl Deref for FdPathBox {
+    type Target = FdPath;
+
+    fn deref(&self) ->&Self::Target {
+        &self.fd
+    }
+}
+impl Drop for FdPathBox {
+    fn drop(&mut self) {
+        let ret = unsafe {
+            binding::psys_close(self.get_fd()) as i64
+        };
+
+        if cfg!(debug_assertions) {
+            expect!(toResult(ret), "Failed to release the FdPathBox {:#?}", self);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FdPath {
+    fd: c_int,
+}
+impl FromRaw for FdPath {
+    /// # Safety
+    ///  * `fd` - must be a valid fd that is opened with `O_PATH`
+    unsafe fn from_raw(fd: c_int) -> FdPath {
+        FdPath { fd }
+    }
+}
+impl FdBasicOp for FdPath {
+    type BoxedFd = FdPathBox;
+
+    fn get_fd(&self) -> c_int {
+        self.fd
+    }
+}
+impl_AsRawFd_for!(FdPath);
+impl FdPath {
+    /// Pre condition: self is opened in dir mode
+    /// Check manpage for fchdir for more documentation.
+    pub fn fchdir(&self) -> Result<(), SyscallError> {
+        let fd = self.get_fd();
+
+        toResult(unsafe { binding::psys_fchdir(fd) } as i64)?;
+
+        Ok(())
+    }
+}
+
+pub trait FromRaw {
+    /// # Safety
+    ///  * `fd` - must be a valid fd
+    unsafe fn from_raw(fd: c_int) -> Self;
+}
+pub trait FdBasicOp {
+    type BoxedFd: FromRaw;
+
+    fn get_fd(&self) -> c_int;
+
+    /// Check manpage for dup3 for more documentation.
+    fn dup3(&self, newfd: c_int, flags: FdFlags) -> Result<Self::BoxedFd, SyscallError> {
+        use binding::dup3;
+
+        Ok(unsafe { Self::BoxedFd::from_raw(dup3(self.get_fd(), newfd, flags)?) })
+    }
+
+    /// Duplicate the fd onto the lowest available fd number.
+    ///
+    /// Unlike `dup3`, it never closes any existing fd.
+    ///
+    ///  * `cloexec` - if true, `F_DUPFD_CLOEXEC` is used instead of `F_DUPFD`.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    fn dup(&self, cloexec: bool) -> Result<Self::BoxedFd, SyscallError> {
+        use binding::dup;
+
+        Ok(unsafe { Self::BoxedFd::from_raw(dup(self.get_fd(), cloexec)?) })
+    }
+
+    /// Apply or remove an advisory lock on the file referred to by the fd.
+    ///
+    /// The lock is advisory only, it does not prevent I/O by processes that
+    /// do not call `flock`.
+    ///
+    /// The lock is associated with the open file description, so it is shared
+    /// by fds duplicated via `dup`/`dup3` or inherited by the child, but not
+    /// by fds obtained by opening the same file again.
+    ///
+    /// In the non-blocking variants of `FlockOp`, `EWOULDBLOCK` is returned
+    /// if the lock is held by another open file description.
+    ///
+    /// Check manpage for flock for more documentation.
+    fn flock(&self, op: FlockOp) -> Result<(), SyscallError> {
+        toResult(unsafe { binding::psys_flock(self.get_fd(), op as c_int) } as i64)?;
+        Ok(())
+    }
+
+    /// Apply an open file description lock on the bytes in `range` of the file
+    /// referred to by the fd via `F_OFD_SETLK`/`F_OFD_SETLKW`.
+    ///
+    /// Unlike `flock`, it locks a byte range instead of the whole file.
+    /// Unlike the traditional `F_SETLK`, the lock is associated with the open
+    /// file description instead of the process, so it works across threads
+    /// and is not released when any fd of the file is closed by the process.
+    ///
+    ///  * `range` - offsets from the start of the file, where `range.end`
+    ///    being `u64::MAX` means up to the end of the file, however it grows.
+    ///    `EINVAL` is returned if it is empty.
+    ///  * `nonblock` - if true and a conflicting lock is held by another open
+    ///    file description, then `EAGAIN` is returned instead of blocking.
+    ///
+    /// Check manpage for fcntl for more documentation.
+    fn ofd_lock(&self, range: Range<u64>, lock_type: LockType, nonblock: bool)
+        -> Result<(), SyscallError>
+    {
+        let cmd = if nonblock {
+            libc::F_OFD_SETLK
+        } else {
+            libc::F_OFD_SETLKW
+        };
+        ofd_setlk(self.get_fd(), cmd, lock_type as c_short, range)
+    }
+
+    /// Remove the open file description lock on the bytes in `range`, which
+    /// can be only part of a range locked by `ofd_lock`.
+    ///
+    /// Check `ofd_lock` for the meaning of `range`.
+    fn ofd_unlock(&self, range: Range<u64>) -> Result<(), SyscallError> {
+        ofd_setlk(self.get_fd(), libc::F_OFD_SETLK, libc::F_UNLCK as c_short, range)
+    }
+
+    /// Type of the file referred to by the fd, which is cheaper to use than
+    /// `statx` when only the type matters.
+    ///
+    /// Check manpage for fstat for more documentation.
+    fn fstat_type(&self) -> Result<FileType, SyscallError> {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        toResult(unsafe {
+            binding::psys_fstat(self.get_fd(), stat.as_mut_ptr() as *mut c_void)
+        } as i64)?;
+
+        let mode = unsafe { stat.assume_init() }.st_mode;
+        Ok(FileType::from_mode(mode as u32))
+    }
+
+    /// Prefetch `count` bytes starting at `offset` of the file referred to by
+    /// the fd into the page cache.
+    ///
+    /// It is only advisory and returns before the data is read, so it does
+    /// not block on the I/O.
+    ///
+    /// It only works on regular files, for other types of files, e.g. pipes,
+    /// `EINVAL` is returned and nothing is prefetched.
+    ///
+    /// For sequential scans, it pairs well with `posix_fadvise` using
+    /// `POSIX_FADV_SEQUENTIAL`, which enlarges the readahead window for the
+    /// subsequent reads.
+    ///
+    /// Check manpage for readahead for more documentation.
+    fn readahead(&self, offset: u64, count: usize) -> Result<(), SyscallError> {
+        toResult(unsafe {
+            binding::psys_readahead(self.get_fd(), offset as i64, count as u64)
+        } as i64)?;
+        Ok(())
+    }
+
+    /// Schedule writeback of the dirty pages in `offset..offset + nbytes` of
+    /// the file referred to by the fd and/or wait for it.
+    ///
+    ///  * `nbytes` - if 0, then up to the end of the file.
+    ///
+    /// It is only a hint for scheduling writeback, NOT a durability guarantee:
+    /// it neither flushes the metadata of the file nor the disk write cache,
+    /// so the data might still be lost on crash even after it returns.
+    /// Use `fsync` or `fdatasync` if durability is required.
+    ///
+    /// To start writeback now without waiting, use
+    /// `SyncRangeFlags::SYNC_FILE_RANGE_WRITE` alone.
+    /// To wait until the range is written out, use all three flags.
+    ///
+    /// Check manpage for sync_file_range for more documentation.
+    fn sync_file_range(&self, offset: u64, nbytes: u64, flags: SyncRangeFlags)
+        -> Result<(), SyscallError>
+    {
+        toResult(unsafe {
+            binding::psys_sync_file_range(self.get_fd(), offset as i64, nbytes as i64, flags.bits)
+        } as i64)?;
+        Ok(())
+    }
+
+    /// Get the window size of the terminal referred to by the fd via
+    /// `ioctl(fd, TIOCGWINSZ)`.
+    ///
+    /// `ENOTTY` is returned if the fd is not a terminal.
+    ///
+    /// Check manpage for ioctl_tty for more documentation.
+    fn get_winsize(&self) -> Result<WinSize, SyscallError> {
+        let mut winsize = WinSize::default();
+        toResult(unsafe {
+            let arg = &mut winsize as *mut WinSize as u64;
+            binding::psys_ioctl(self.get_fd(), libc::TIOCGWINSZ as _, arg)
+        } as i64)?;
+        Ok(winsize)
+    }
+
+    /// Set the window size of the terminal referred to by the fd via
+    /// `ioctl(fd, TIOCSWINSZ)`, e.g. the pty of the child after the parent
+    /// receives `SIGWINCH`.
+    ///
+    /// If the size changes, then `SIGWINCH` is sent to the foreground process
+    /// group of the terminal.
+    ///
+    /// `ENOTTY` is returned if the fd is not a terminal.
+    ///
+    /// Check manpage for ioctl_tty for more documentation.
+    fn set_winsize(&self, winsize: &WinSize) -> Result<(), SyscallError> {
+        toResult(unsafe {
+            let arg = winsize as *const WinSize as u64;
+            binding::psys_ioctl(self.get_fd(), libc::TIOCSWINSZ as _, arg)
+        } as i64)?;
+        Ok(())
+    }
+
+    /// Append `buf` to the file referred to by the fd, which must be opened
+    /// with `FdFlags::O_APPEND`, otherwise it is written at the current
+    /// offset instead. It is not checked.
+    ///
+    /// With `O_APPEND`, moving the offset to the end of the file and writing
+    /// is done atomically by the kernel, so records appended concurrently by
+    /// multiple processes are never overwritten or interleaved, as long as
+    /// each of them is written by a single `write`.
+    /// `buf` is written by a single `write` unless it is partially written,
+    /// which for regular files only happens on interruption by a signal, on
+    /// `ENOSPC`, or when hitting `RLIMIT_FSIZE`, in which case the rest might
+    /// be interleaved with the records of other writers.
+    ///
+    /// The atomicity only holds on local filesystems: NFS emulates `O_APPEND`
+    /// on the client by writing at the end of the file as cached by the
+    /// client, so appends from different clients might overwrite each other.
+    ///
+    /// Once the file reaches `RLIMIT_FSIZE`, the record is truncated and the
+    /// next `write` raises `SIGXFSZ`, which kills the process by default.
+    /// Use `Fd::write_all_catch_fsize` to get `EFBIG` instead.
+    fn append_all(&self, buf: &[u8]) -> Result<(), SyscallError> {
+        unsafe { Fd::from_raw(self.get_fd()) }.write_all(buf)
+    }
+}
+
+/// Window size of a terminal, same layout as `struct winsize`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    /// Unused by the kernel
+    pub xpixel: u16,
+    /// Unused by the kernel
+    pub ypixel: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    /// Including ttys
+    CharDevice,
+    BlockDevice,
+    /// Pipe or FIFO
+    Fifo,
+    Socket,
+    /// Unrecognized `S_IFMT` bits
+    Unknown,
+}
+impl FileType {
+    /// * `mode` - `st_mode` or `stx_mode`
+    pub fn from_mode(mode: u32) -> FileType {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::Regular,
+            libc::S_IFDIR => FileType::Directory,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFIFO => FileType::Fifo,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+fn ofd_setlk(fd: c_int, cmd: c_int, l_type: c_short, range: Range<u64>)
+    -> Result<(), SyscallError>
+{
+    if range.start >= range.end || range.start > (i64::MAX as u64) {
+        return Err(SyscallError::new(libc::EINVAL as u32));
+    }
+
+    let l_len: libc::off_t = if range.end == u64::MAX {
+        0
+    } else {
+        (range.end - range.start).try_into()
+            .map_err(|_| SyscallError::new(libc::EOVERFLOW as u32))?
+    };
+
+    // l_pid must be 0 for F_OFD_*
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type;
+    lock.l_whence = libc::SEEK_SET as c_short;
+    lock.l_start = range.start as libc::off_t;
+    lock.l_len = l_len;
+
+    binding::fcntl(fd, cmd, &mut lock as *mut libc::flock as u64)?;
+    Ok(())
+}
+
+bitflags! {
+    pub struct SyncRangeFlags: libc::c_uint {
+        /// Wait for writeback of pages in the range that is already submitted
+        /// before writing any.
+        const SYNC_FILE_RANGE_WAIT_BEFORE = libc::SYNC_FILE_RANGE_WAIT_BEFORE;
+        /// Start writeback of the dirty pages in the range that are not
+        /// already submitted.
+        const SYNC_FILE_RANGE_WRITE = libc::SYNC_FILE_RANGE_WRITE;
+        /// Wait for writeback of pages in the range after writing any.
+        const SYNC_FILE_RANGE_WAIT_AFTER = libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    }
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockType {
+    /// Shared lock, the fd must be open for reading
+    Read = libc::F_RDLCK as i32,
+    /// Exclusive lock, the fd must be open for writing
+    Write = libc::F_WRLCK as i32,
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlockOp {
+    Shared = libc::LOCK_SH,
+    SharedNonBlocking = libc::LOCK_SH | libc::LOCK_NB,
+    Exclusive = libc::LOCK_EX,
+    ExclusiveNonBlocking = libc::LOCK_EX | libc::LOCK_NB,
+    /// Remove the lock held by this open file description.
+    Unlock = libc::LOCK_UN,
+}
+
+pub const AT_FDCWD: FdPath = FdPath { fd: binding::AT_FDCWD };
+pub const STDIN: Fd = Fd { fd: 0 };
+pub const STDOUT: Fd = Fd { fd: 1 };
+pub const STDERR: Fd = Fd { fd: 2 };
+
+/// Returns a `O_CLOEXEC` fd of `/dev/null` that is opened on first use and
+/// cached for the rest of the process, so it is never closed.
+///
+///  * `write` - if true, returns the write-only fd, otherwise the read-only
+///    one.
+///
+/// It is safe to use the returned fd in the avfork callback, since it is just
+/// a fd number, e.g. `dup3` it onto stdio, but the first call must be made
+/// in the parent since it might open the file.
+///
+/// If the fd is closed by other code, e.g. via `FdBox::from_raw`, then the
+/// cached fd would refer to whatever file that reuses the fd number.
+pub fn dev_null(write: bool) -> Result<&'static Fd, SyscallError> {
+    static DEV_NULL_R: OnceCell<Fd> = OnceCell::new();
+    static DEV_NULL_W: OnceCell<Fd> = OnceCell::new();
+
+    let (cell, accMode) = if write {
+        (&DEV_NULL_W, AccessMode::O_WRONLY)
+    } else {
+        (&DEV_NULL_R, AccessMode::O_RDONLY)
+    };
+
+    cell.get_or_try_init(|| {
+        let fd = FdBox::openat(AT_FDCWD, cstr!("/dev/null"), accMode, FdFlags::O_CLOEXEC)?;
+
+        // Leak the fd so that it is never closed
+        let raw = *fd;
+        std::mem::forget(fd);
+        Ok(raw)
+    })
+}
+
+/// Block until any of the events in `fds` is available, where entries with
+/// negative fd are ignored.
+///
+///  * `timeout` - If `None`, then wait indefinitely.
+///    It is restarted from scratch if interrupted by a signal.
+///
+/// Returns the number of entries with `revents` set, which is 0 if timed out.
+pub(crate) fn ppoll(fds: &mut [libc::pollfd], timeout: Option<Duration>)
+    -> Result<usize, SyscallError>
+{
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout_ptr: *const c_void = match &timeout {
+        Some(timeout) => to_void_ptr(timeout),
+        None => std::ptr::null()
+    };
+
+    let cnt = autorestart(|| {
+        toResult(unsafe {
+            binding::psys_ppoll(
+                fds.as_mut_ptr() as *mut c_void,
+                fds.len() as _,
+                timeout_ptr,
+                std::ptr::null()
+            )
+        } as i64)
+    })?;
+
+    Ok(cnt as usize)
+}
+
+/// Duplicate `stdin`, `stdout` and `stderr` onto fd 0, 1 and 2 as if they
+/// were done simultaneously, e.g. `redirect_stdio(None, Some(&STDERR),
+/// Some(&STDOUT))` swaps stdout and stderr.
+///
+/// Sources that are one of fd 0, 1 and 2 other than their targets are first
+/// duplicated onto fds >= 3, which are closed before returning, so that no
+/// source is overwritten by `dup3` before it is used.
+///
+/// `None` leaves the fd untouched, otherwise the fd is not `FD_CLOEXEC`
+/// afterwards, even if the source is the fd itself.
+///
+/// It does not allocate, thus it can be used in the avfork callback.
+///
+/// If it fails, then some of the fds might already be replaced.
+pub fn redirect_stdio(stdin: Option<&Fd>, stdout: Option<&Fd>, stderr: Option<&Fd>)
+    -> Result<(), SyscallError>
+{
+    let sources = [stdin, stdout, stderr];
+    let mut temps: [Option<FdBox>; 3] = [None, None, None];
+
+    for (newfd, source) in sources.iter().enumerate() {
+        if let Some(fd) = source {
+            let fd = fd.get_fd();
+            if (0..3).contains(&fd) && fd != newfd as c_int {
+                let temp = binding::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3)?;
+                temps[newfd] = Some(unsafe { FdBox::from_raw(temp) });
+            }
+        }
+    }
+
+    for (newfd, source) in sources.iter().enumerate() {
+        let oldfd = match (&temps[newfd], source) {
+            (Some(temp), _) => temp.get_fd(),
+            (None, Some(fd)) => fd.get_fd(),
+            (None, None) => continue,
+        };
+        let newfd = newfd as c_int;
+
+        if oldfd == newfd {
+            // dup3 fails with EINVAL if oldfd == newfd
+            unsafe { Fd::from_raw(newfd) }.set_cloexec(false)?;
+        } else {
+            binding::dup3(oldfd, newfd, FdFlags::empty())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check manpage for chdir for more documentation.
+pub fn chdir(pathname: &CStr) -> Result<(), SyscallError>
+{
+    let pathname = pathname.as_ptr();
+    toResult(unsafe { binding::psys_chdir(pathname) as i64 })?;
+    Ok(())
+}
+
+bitflags! {
+    pub struct CloneFlags: c_int {
+        /// cgroup namespace
+        const CLONE_NEWCGROUP = libc::CLONE_NEWCGROUP;
+        /// IPC namespace
+        const CLONE_NEWIPC = libc::CLONE_NEWIPC;
+        /// network namespace
+        const CLONE_NEWNET = libc::CLONE_NEWNET;
+        /// mount namespace
+        const CLONE_NEWNS = libc::CLONE_NEWNS;
+        /// pid namespace
+        const CLONE_NEWPID = libc::CLONE_NEWPID;
+        /// user namespace
+        const CLONE_NEWUSER = libc::CLONE_NEWUSER;
+        /// UTS namespace
+        const CLONE_NEWUTS = libc::CLONE_NEWUTS;
+    }
+}
+
+/// Reassociate the calling thread with the namespace referred to by `fd`.
+///
+///  * `fd` - fd opened on one of the `/proc/[pid]/ns/` files or a pidfd.
+///  * `nstype` - the type of namespace `fd` must refer to, empty means any type.
+///
+/// When joining multiple namespaces, the user namespace should be joined first
+/// so that the caller gains the capabilities required to join the others.
+///
+/// Check manpage for setns for more documentation.
+pub fn setns(fd: &Fd, nstype: CloneFlags) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_setns(fd.get_fd(), nstype.bits) as i64 })?;
+    Ok(())
+}
+
+/// Move the calling process into new namespaces specified by `flags`.
+///
+/// `CloneFlags::CLONE_NEWPID` only affects the children created afterwards.
+///
+/// Check manpage for unshare for more documentation.
+pub fn unshare(flags: CloneFlags) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_unshare(flags.bits) as i64 })?;
+    Ok(())
+}
+
+bitflags! {
+    pub struct MountFlags: libc::c_ulong {
+        const MS_RDONLY = libc::MS_RDONLY;
+        const MS_NOSUID = libc::MS_NOSUID;
+        const MS_NODEV = libc::MS_NODEV;
+        const MS_NOEXEC = libc::MS_NOEXEC;
+        const MS_REMOUNT = libc::MS_REMOUNT;
+        const MS_BIND = libc::MS_BIND;
+        const MS_MOVE = libc::MS_MOVE;
+        const MS_REC = libc::MS_REC;
+        const MS_PRIVATE = libc::MS_PRIVATE;
+        const MS_SLAVE = libc::MS_SLAVE;
+        const MS_SHARED = libc::MS_SHARED;
+        const MS_UNBINDABLE = libc::MS_UNBINDABLE;
+    }
+}
+
+/// * `source` - ignored by some filesystems and flags, e.g. `MS_PRIVATE`.
+/// * `fstype` - ignored if `MS_REMOUNT`, `MS_BIND`, `MS_MOVE` or propagation
+///   flags are passed.
+/// * `data` - options specific to `fstype`.
+///
+/// Check manpage for mount for more documentation.
+pub fn mount(
+    source: Option<&CStr>, target: &CStr, fstype: Option<&CStr>,
+    flags: MountFlags, data: Option<&CStr>
+) -> Result<(), SyscallError>
+{
+    let to_ptr = |s: Option<&CStr>| match s {
+        Some(s) => s.as_ptr(),
+        None => std::ptr::null(),
+    };
+
+    toResult(unsafe {
+        binding::psys_mount(
+            to_ptr(source),
+            target.as_ptr(),
+            to_ptr(fstype),
+            flags.bits,
+            to_ptr(data) as *const c_void
+        )
+    } as i64)?;
+    Ok(())
+}
+
+bitflags! {
+    pub struct AccessCheck: c_int {
+        /// Only check for existence of the file
+        const F_OK = libc::F_OK;
+        const R_OK = libc::R_OK;
+        const W_OK = libc::W_OK;
+        const X_OK = libc::X_OK;
+    }
+}
+
+bitflags! {
+    pub struct FaccessFlags: c_int {
+        const NONE = 0;
+        /// Check using the effective uid and gid instead of the real ones.
+        const AT_EACCESS          = libc::AT_EACCESS;
+        const AT_EMPTY_PATH       = libc::AT_EMPTY_PATH;
+        const AT_SYMLINK_NOFOLLOW = libc::AT_SYMLINK_NOFOLLOW;
+    }
+}
+
+/// Check whether the calling process can access `pathname` with `mode`.
+///
+///  * `dirfd` - can be `AT_FDCWD`
+///
+/// Returns `Ok(false)` on `EACCES`.
+///
+/// The `faccessat` syscall does not take any flags, so libc emulates
+/// `AT_EACCESS` in userspace, which is not always right, e.g. when running
+/// setuid, thus `faccessat` should be avoided if `AT_EACCESS` matters.
+///
+/// Requires linux 5.8, otherwise `ENOSYS` is returned, so that the caller can
+/// detect the kernel is too old.
+///
+/// Check manpage for faccessat2 for more documentation.
+pub fn faccessat2(dirfd: FdPath, pathname: &CStr, mode: AccessCheck, flags: FaccessFlags)
+    -> Result<bool, SyscallError>
+{
+    let ret = unsafe {
+        binding::psys_faccessat2(dirfd.get_fd(), pathname.as_ptr(), mode.bits, flags.bits)
+    };
+    match toResult(ret as i64) {
+        Ok(_) => Ok(true),
+        Err(err) if err.get_errno() == libc::EACCES => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn get_monotonic_time() -> Result<Duration, SyscallError> {
+    let mut ts = std::mem::MaybeUninit::<libc::timespec>::uninit();
+    toResult(unsafe {
+        binding::psys_clock_gettime(libc::CLOCK_MONOTONIC, ts.as_mut_ptr() as *mut c_void)
+    } as i64)?;
+
+    let ts = unsafe { ts.assume_init() };
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Sleep for `duration`, resuming with the remaining time if interrupted by
+/// a signal handler.
+///
+/// Check manpage for nanosleep for more documentation.
+pub fn nanosleep(duration: Duration) -> Result<(), SyscallError> {
+    let mut req = libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    };
+    loop {
+        let mut rem = std::mem::MaybeUninit::<libc::timespec>::uninit();
+        let ret = unsafe {
+            binding::psys_nanosleep(
+                &req as *const libc::timespec as *const c_void,
+                rem.as_mut_ptr() as *mut c_void
+            )
+        };
+        match toResult(ret as i64) {
+            Ok(_) => break Ok(()),
+            Err(err) if err.get_errno() == libc::EINTR => req = unsafe { rem.assume_init() },
+            Err(err) => break Err(err),
+        }
+    }
+}
+
+/// Wait for `name` relative to `dirfd` to exist by polling it via `faccessat`
+/// every `interval`, e.g. a unix socket created by another process.
+///
+///  * `dirfd` - can be `AT_FDCWD`
+///
+/// Returns `ETIMEDOUT` if it still does not exist after `timeout`, or any error
+/// other than `ENOENT` returned by `faccessat`, e.g. `EACCES` if a directory
+/// in the path is not searchable.
+///
+/// A `timeout` too large to be added to the current time, e.g. `Duration::MAX`,
+/// means waiting forever.
+///
+/// Returns `EINVAL` if `interval` is zero, which would poll in a busy loop.
+///
+/// Unlike waiting via inotify, it does not allocate any watch, thus it can be
+/// used in the callback of `avfork`.
+pub fn wait_for_path(dirfd: FdPath, name: &CStr, timeout: Duration, interval: Duration)
+    -> Result<(), SyscallError>
+{
+    if interval.is_zero() {
+        return Err(SyscallError::new(libc::EINVAL as u32));
+    }
+
+    let deadline = get_monotonic_time()?.checked_add(timeout);
+
+    loop {
+        let ret = unsafe {
+            binding::psys_faccessat(dirfd.get_fd(), name.as_ptr(), AccessCheck::F_OK.bits)
+        };
+        match toResult(ret as i64) {
+            Ok(_) => break Ok(()),
+            Err(err) if err.get_errno() == libc::ENOENT => (),
+            Err(err) => break Err(err),
+        }
+
+        let sleep = match deadline {
+            Some(deadline) => {
+                let now = get_monotonic_time()?;
+                if now >= deadline {
+                    break Err(SyscallError::new(libc::ETIMEDOUT as u32));
+                }
+                interval.min(deadline - now)
+            },
+            None => interval,
+        };
+        nanosleep(sleep)?;
+    }
+}
+
+/// Open `/proc/{pid}/{file}` write-only, with the path formatted on stack.
+fn open_proc_pid_file(pid: pid_t, file: &str) -> Result<FdBox, SyscallError> {
+    let mut buf = [0 as u8; 64];
+    let cap = buf.len() - 1;
+
+    let len = {
+        // Leave the last byte as the nul terminator
+        let mut cursor = &mut buf[..cap];
+        if write!(cursor, "/proc/{}/{}", pid, file).is_err() {
+            return Err(SyscallError::new(libc::ENAMETOOLONG as u32));
+        }
+        cap - cursor.len()
+    };
+
+    // buf is zero-initialized, so it is nul-terminated
+    let pathname = CStr::from_bytes_with_nul(&buf[..=len])
+        .map_err(|_| SyscallError::new(libc::EINVAL as u32))?;
+
+    FdBox::openat(AT_FDCWD, pathname, AccessMode::O_WRONLY, FdFlags::O_CLOEXEC)
+}
+
+/// Write `entries` to `/proc/{pid}/{file}` in one `write`, which is required by
+/// the kernel.
+fn write_id_map(pid: pid_t, file: &str, entries: &[(u32, u32, u32)])
+    -> Result<(), SyscallError>
+{
+    // The kernel rejects any write that is not less than a page.
+    let mut buf = [0 as u8; 4096];
+
+    let mut cursor = &mut buf[..];
+    for (inside, outside, count) in entries {
+        if writeln!(cursor, "{} {} {}", inside, outside, count).is_err() {
+            return Err(SyscallError::new(libc::EINVAL as u32));
+        }
+    }
+    let len = 4096 - cursor.len();
+
+    let fd = open_proc_pid_file(pid, file)?;
+    let cnt = fd.write(&buf[..len])?;
+    if cnt != len {
+        return Err(SyscallError::new(libc::EINVAL as u32));
+    }
+
+    Ok(())
+}
+
+/// Write the uid mapping of the user namespace that `pid` is in.
+///
+///  * `entries` - each is (first uid inside the namespace, first uid outside,
+///    length of the range).
+///
+/// The map can only be written once, by a process in the user namespace
+/// of `pid` or its parent namespace, e.g. the parent of a child that
+/// called `unshare(CLONE_NEWUSER)`.
+///
+/// It only uses syscalls and the buffers are on the stack, so it can be used
+/// in the avfork callback.
+///
+/// Check manpage for user_namespaces for more documentation.
+pub fn write_uid_map(pid: pid_t, entries: &[(u32, u32, u32)]) -> Result<(), SyscallError> {
+    write_id_map(pid, "uid_map", entries)
+}
+
+/// Same as `write_uid_map`, but for gid.
+///
+/// For unprivileged user namespaces, `deny_setgroups` must be called before
+/// this function, otherwise `EPERM` is returned.
+pub fn write_gid_map(pid: pid_t, entries: &[(u32, u32, u32)]) -> Result<(), SyscallError> {
+    write_id_map(pid, "gid_map", entries)
+}
+
+/// Write "deny" to `/proc/{pid}/setgroups`, which disables `setgroups` in the
+/// user namespace of `pid` permanently.
+///
+/// It must be done before `write_gid_map` for unprivileged user namespaces.
+pub fn deny_setgroups(pid: pid_t) -> Result<(), SyscallError> {
+    let fd = open_proc_pid_file(pid, "setgroups")?;
+    fd.write(b"deny")?;
+    Ok(())
+}
+
+/// Change the root directory of the calling process to `pathname`.
+///
+/// It does not change the current working directory, thus `chdir` should be
+/// called afterwards, otherwise the process can still access files outside of
+/// the new root via relative path.
+///
+/// Requires `CAP_SYS_CHROOT`.
+///
+/// Check manpage for chroot for more documentation.
+pub fn chroot(pathname: &CStr) -> Result<(), SyscallError> {
+    let pathname = pathname.as_ptr();
+    toResult(unsafe { binding::psys_chroot(pathname) as i64 })?;
+    Ok(())
+}
+
+pub fn get_pagesz() -> usize {
+    unsafe { binding::psys_get_pagesz() as usize }
+}
+
+pub fn setresuid(ruid: uid_t, euid: uid_t, suid: uid_t) -> Result<(), SyscallError> {
+    unsafe {
+        toResult(binding::psys_setresuid(ruid, euid, suid) as i64)?;
+    };
+    Ok(())
+}
+
+pub fn setresgid(rgid: gid_t, egid: gid_t, sgid: gid_t) -> Result<(), SyscallError> {
+    unsafe {
+        toResult(binding::psys_setresgid(rgid, egid, sgid) as i64)?;
+    };
+    Ok(())
+}
+
+pub fn setgroups(list: &[gid_t]) -> Result<(), SyscallError> {
+    unsafe {
+        toResult(binding::psys_setgroups(list.len() as u64, list.as_ptr()) as i64)?;
     };
     Ok(())
 }
 
-pub fn setresgid(rgid: gid_t, egid: gid_t, sgid: gid_t) -> Result<(), SyscallError> {
-    unsafe {
-        toResult(binding::psys_setresgid(rgid, egid, sgid) as i64)?;
-    };
+pub fn getpid() -> pid_t {
+    unsafe {
+        binding::psys_getpid()
+    }
+}
+
+/// Returns the thread id of the calling thread, which can be passed to
+/// `sched_setaffinity` and `sched_setscheduler` to only affect this thread.
+///
+/// Unlike `getpid`, which returns the thread group id shared by all threads
+/// of the process, it differs for every thread, except for the main thread,
+/// whose tid is the same as the pid.
+///
+/// The child of `avfork` is single-threaded, thus `gettid` and `getpid`
+/// return the same value in it.
+///
+/// This function is async-signal-safe.
+pub fn gettid() -> pid_t {
+    unsafe {
+        binding::psys_gettid()
+    }
+}
+
+pub fn getuid() -> uid_t {
+    unsafe {
+        binding::psys_getuid()
+    }
+}
+
+/// Move process `pid` into process group `pgid`.
+///
+///  * `pid` - if 0, then the calling process is used.
+///  * `pgid` - if 0, then `pid` is used, making it the leader of a new process
+///    group.
+///
+/// Check manpage for setpgid for more documentation.
+pub fn setpgid(pid: pid_t, pgid: pid_t) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_setpgid(pid, pgid) } as i64)?;
+    Ok(())
+}
+
+/// Create a new session with the calling process as its leader, which has no
+/// controlling terminal.
+///
+/// Returns the id of the new session, which is the pid of the calling process.
+///
+/// Check manpage for setsid for more documentation.
+pub fn setsid() -> Result<pid_t, SyscallError> {
+    let sid = toResult(unsafe { binding::psys_setsid() } as i64)?;
+    Ok(sid as pid_t)
+}
+
+/// Make `pgrp` the foreground process group of `tty` via
+/// `ioctl(tty, TIOCSPGRP)`, e.g. a job-control shell handing the terminal to
+/// the foreground job.
+///
+/// `tty` must be the controlling terminal of the calling process and `pgrp`
+/// must be in the same session, otherwise `ENOTTY` or `EPERM` is returned.
+///
+/// If the calling process is in a background process group of `tty`, then it
+/// receives `SIGTTOU` and is stopped, unless it ignores or blocks `SIGTTOU`,
+/// which is why a shell usually blocks `SIGTTOU` around this call.
+///
+/// Check manpage for tcsetpgrp and ioctl_tty for more documentation.
+pub fn tcsetpgrp(tty: &Fd, pgrp: pid_t) -> Result<(), SyscallError> {
+    toResult(unsafe {
+        let arg = &pgrp as *const pid_t as u64;
+        binding::psys_ioctl(tty.get_fd(), libc::TIOCSPGRP as _, arg)
+    } as i64)?;
     Ok(())
 }
 
-pub fn setgroups(list: &[gid_t]) -> Result<(), SyscallError> {
-    unsafe {
-        toResult(binding::psys_setgroups(list.len() as u64, list.as_ptr()) as i64)?;
-    };
+/// Get the foreground process group of `tty` via `ioctl(tty, TIOCGPGRP)`.
+///
+/// `ENOTTY` is returned if `tty` is not the controlling terminal of the
+/// calling process.
+///
+/// Check manpage for tcgetpgrp and ioctl_tty for more documentation.
+pub fn tcgetpgrp(tty: &Fd) -> Result<pid_t, SyscallError> {
+    let mut pgrp: pid_t = 0;
+    toResult(unsafe {
+        let arg = &mut pgrp as *mut pid_t as u64;
+        binding::psys_ioctl(tty.get_fd(), libc::TIOCGPGRP as _, arg)
+    } as i64)?;
+    Ok(pgrp)
+}
+
+/// Set the "dumpable" attribute of the calling process, which determines whether
+/// core dumps are produced and whether the process can be `ptrace`-attached
+/// by a process with the same uid.
+///
+/// A process that changes its credentials via `setresuid`, `setresgid` and etc,
+/// or executes a set-user-ID/set-group-ID binary, starts non-dumpable.
+///
+/// Note that while the process is non-dumpable, entries in `/proc/self` are owned
+/// by root instead of the ruid and rgid of the process, which might make
+/// `read_proc_status_field` fail after privileges are dropped.
+/// Re-enabling it changes the ownership back.
+///
+/// Check manpage for prctl for more documentation.
+pub fn set_dumpable(dumpable: bool) -> Result<(), SyscallError> {
+    binding::prctl(libc::PR_SET_DUMPABLE, dumpable as u64, 0, 0, 0)?;
     Ok(())
 }
 
-pub fn getpid() -> pid_t {
-    unsafe {
-        binding::psys_getpid()
+/// Check `set_dumpable` for more documentation.
+pub fn get_dumpable() -> Result<bool, SyscallError> {
+    Ok(binding::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0)? != 0)
+}
+
+/// Set the timer slack of the calling thread to `ns` nanoseconds, which is
+/// how much the expiration of timers, e.g. `nanosleep` and `poll` timeouts,
+/// might be delayed so that wakeups can be coalesced.
+///
+/// Setting 0 resets it to the default timer slack, which is the one
+/// inherited on creation, usually 50µs.
+///
+/// Reducing it makes timers more precise at the cost of more wakeups, thus
+/// higher power consumption.
+///
+/// It is inherited by children and preserved across `execve`, so it can be
+/// called in the avfork callback.
+///
+/// Check manpage for prctl for more documentation.
+pub fn set_timerslack(ns: u64) -> Result<(), SyscallError> {
+    binding::prctl(libc::PR_SET_TIMERSLACK, ns, 0, 0, 0)?;
+    Ok(())
+}
+
+/// Returns the timer slack of the calling thread in nanoseconds.
+///
+/// Check `set_timerslack` for more documentation.
+pub fn get_timerslack() -> Result<u64, SyscallError> {
+    // The value might not fit in c_int returned by binding::prctl
+    toResult(unsafe { binding::psys_prctl(libc::PR_GET_TIMERSLACK, 0, 0, 0, 0) } as i64)
+}
+
+/// Look up `field` in `/proc/self/status` and copy its value, with the leading
+/// blanks stripped, into `buf`.
+///
+/// Only syscalls and a fixed-size buffer on stack is used, so it is safe to
+/// be called inside the callback of `avfork`, e.g. to check `NoNewPrivs` or
+/// `CapEff` before exec.
+///
+///  * `field` - name of the field without the trailing colon, e.g. `Seccomp`
+///
+/// Returns number of bytes written to `buf`.
+///
+/// # Errors
+///
+///  - `ENOENT` if `field` is not found in `/proc/self/status`.
+///  - `ERANGE` if `buf` is too small to hold the value.
+///  - Any error returned by `openat` or `read`.
+pub fn read_proc_status_field(field: &CStr, buf: &mut [u8]) -> Result<usize, SyscallError> {
+    #[derive(Copy, Clone)]
+    enum State {
+        /// Matched `field[..n]` at the start of the line
+        Matching(usize),
+        /// The line does not match
+        SkipLine,
+        /// Matched `field` and the colon, skipping the blanks
+        SkipBlank,
+        /// Copied n bytes of value into `buf`
+        Copying(usize),
+    }
+
+    let field = field.to_bytes();
+    let file = FdBox::openat(
+        AT_FDCWD,
+        cstr!("/proc/self/status"),
+        AccessMode::O_RDONLY,
+        FdFlags::O_CLOEXEC
+    )?;
+
+    let mut chunk = [0 as u8; 256];
+    let mut state = State::Matching(0);
+
+    loop {
+        let cnt = crate::autorestart!({
+            file.read(&mut chunk)
+        })?;
+        if cnt == 0 {
+            break;
+        }
+
+        for byte in chunk[..cnt].iter().copied() {
+            state = match state {
+                State::Matching(n) if n < field.len() && byte == field[n] => {
+                    State::Matching(n + 1)
+                },
+                State::Matching(n) if n == field.len() && byte == b':' => State::SkipBlank,
+                State::Matching(_) | State::SkipLine => {
+                    if byte == b'\n' {
+                        State::Matching(0)
+                    } else {
+                        State::SkipLine
+                    }
+                },
+
+                State::SkipBlank if byte == b' ' || byte == b'\t' => State::SkipBlank,
+                State::SkipBlank | State::Copying(_) => {
+                    let copied = match state {
+                        State::Copying(copied) => copied,
+                        _ => 0,
+                    };
+
+                    if byte == b'\n' {
+                        return Ok(copied);
+                    }
+                    if copied == buf.len() {
+                        return Err(SyscallError::new(libc::ERANGE as u32));
+                    }
+
+                    buf[copied] = byte;
+                    State::Copying(copied + 1)
+                },
+            };
+        }
     }
+
+    // The last line might not be terminated by '\n'
+    match state {
+        State::SkipBlank => Ok(0),
+        State::Copying(copied) => Ok(copied),
+        _ => Err(SyscallError::new(libc::ENOENT as u32)),
+    }
+}
+
+/// Obtain a fd referring to process `pid`, which is `O_CLOEXEC` and can be
+/// polled for readability, which indicates that the process has terminated.
+///
+/// Unlike pid, a pidfd cannot be recycled, thus it is not prone to pid reuse.
+///
+/// Requires linux 5.3.
+///
+/// # Errors
+///
+///  - `ESRCH` if `pid` does not exist or has already been reaped.
+///
+/// Check manpage for pidfd_open for more documentation.
+pub fn pidfd_open(pid: pid_t) -> Result<FdBox, SyscallError> {
+    let fd = toResult(unsafe { binding::psys_pidfd_open(pid, 0) } as i64)?;
+    Ok(unsafe { FdBox::from_raw(fd as c_int) })
 }
 
 pub fn sched_setparam(pid: pid_t, param: &libc::sched_param) -> Result<(), SyscallError> {
@@ -496,6 +2326,23 @@ pub enum SchedPolicy {
     SCHED_FIFO(libc::sched_param),
     /// a round-robin policy.
     SCHED_RR(libc::sched_param),
+
+    /// An earliest-deadline-first policy, which has higher priority than all
+    /// the other policies: the process is guaranteed to get `runtime_ns` of
+    /// CPU time within `deadline_ns` of the start of every period of
+    /// `period_ns`.
+    ///
+    /// The kernel only admits it if `runtime_ns <= deadline_ns <= period_ns`
+    /// and the total bandwidth of all `SCHED_DEADLINE` processes still fits
+    /// in the CPUs, otherwise `EINVAL` or `EBUSY` is returned respectively.
+    /// `period_ns` can be 0, in which case it is the same as `deadline_ns`.
+    ///
+    /// Setting it requires `CAP_SYS_NICE`.
+    SCHED_DEADLINE {
+        runtime_ns: u64,
+        deadline_ns: u64,
+        period_ns: u64,
+    },
 }
 impl std::fmt::Debug for SchedPolicy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -508,20 +2355,68 @@ impl std::fmt::Debug for SchedPolicy {
                 write!(f, "SCHED_FIFO({})", param.sched_priority),
             SchedPolicy::SCHED_RR(param) =>
                 write!(f, "SCHED_RR({})", param.sched_priority),
+
+            SchedPolicy::SCHED_DEADLINE { runtime_ns, deadline_ns, period_ns } =>
+                f.debug_struct("SCHED_DEADLINE")
+                    .field("runtime_ns", runtime_ns)
+                    .field("deadline_ns", deadline_ns)
+                    .field("period_ns", period_ns)
+                    .finish(),
         }
     }
 }
 
+/// Not exported by libc.
+const SCHED_DEADLINE: c_int = 6;
+
+/// `struct sched_attr` used by `sched_setattr` and `sched_getattr`, whose
+/// first version is 48 bytes.
+#[repr(C)]
+#[derive(Default)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+fn sched_getattr(pid: pid_t) -> Result<SchedAttr, SyscallError> {
+    let mut attr = SchedAttr::default();
+    let size = std::mem::size_of::<SchedAttr>() as u32;
+
+    toResult(unsafe {
+        binding::psys_sched_getattr(pid, &mut attr as *mut SchedAttr as *mut c_void, size, 0)
+    } as i64)?;
+
+    Ok(attr)
+}
+
+fn sched_setattr(pid: pid_t, attr: &SchedAttr) -> Result<(), SyscallError> {
+    toResult(unsafe {
+        binding::psys_sched_setattr(pid, attr as *const SchedAttr as *const c_void, 0)
+    } as i64)?;
+
+    Ok(())
+}
+
+/// `SchedPolicy::SCHED_DEADLINE` is retrieved via `sched_getattr`.
+///
+/// `SCHED_RESET_ON_FORK` is ignored.
+///
 /// # Error
 ///
-/// If unexpected scheulder policy is returned from kernel, then this function
-/// will terminate the process with a friendly error message.
+/// If unexpected scheduler policy is returned from kernel, e.g. one added by
+/// a newer kernel, then `EINVAL` is returned.
 pub fn sched_getscheduler(pid: pid_t) -> Result<SchedPolicy, SyscallError> {
     let result = unsafe {
         toResult(binding::psys_sched_getscheduler(pid) as i64 )? as i32
     };
 
-    Ok(match result {
+    Ok(match result & !libc::SCHED_RESET_ON_FORK {
         libc::SCHED_OTHER => SchedPolicy::SCHED_OTHER,
         libc::SCHED_BATCH => SchedPolicy::SCHED_BATCH,
         libc::SCHED_IDLE => SchedPolicy::SCHED_IDLE,
@@ -529,12 +2424,21 @@ pub fn sched_getscheduler(pid: pid_t) -> Result<SchedPolicy, SyscallError> {
         libc::SCHED_FIFO => SchedPolicy::SCHED_FIFO(sched_getparam(pid)?),
         libc::SCHED_RR => SchedPolicy::SCHED_RR(sched_getparam(pid)?),
 
-        _ => {
-            crate::errx!(1, "Unexpected scheduler policy in sched_getscheduler")
-        }
+        SCHED_DEADLINE => {
+            let attr = sched_getattr(pid)?;
+            SchedPolicy::SCHED_DEADLINE {
+                runtime_ns: attr.sched_runtime,
+                deadline_ns: attr.sched_deadline,
+                period_ns: attr.sched_period,
+            }
+        },
+
+        _ => return Err(SyscallError::new(libc::EINVAL as u32)),
     })
 }
 
+/// `SchedPolicy::SCHED_DEADLINE` is set via `sched_setattr`, since it does
+/// not fit in `sched_param`.
 pub fn sched_setscheduler(pid: pid_t, policy: &SchedPolicy) -> Result<(), SyscallError> {
     let nullptr: *const libc::sched_param = std::ptr::null();
 
@@ -555,12 +2459,23 @@ pub fn sched_setscheduler(pid: pid_t, policy: &SchedPolicy) -> Result<(), Syscal
 
         SchedPolicy::SCHED_FIFO(param) => setter(libc::SCHED_FIFO, param as *const _),
         SchedPolicy::SCHED_RR(param) => setter(libc::SCHED_RR, param as *const _),
+
+        SchedPolicy::SCHED_DEADLINE { runtime_ns, deadline_ns, period_ns } => {
+            sched_setattr(pid, &SchedAttr {
+                size: std::mem::size_of::<SchedAttr>() as u32,
+                sched_policy: SCHED_DEADLINE as u32,
+                sched_runtime: *runtime_ns,
+                sched_deadline: *deadline_ns,
+                sched_period: *period_ns,
+                ..SchedAttr::default()
+            })
+        },
     }
 }
 
 // Here it relies on the compiler to check that i32 == c_int
 #[repr(i32)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PrlimitResource {
     /// The maximum size of process's virtual memory (address space)
     /// Specified in bytes, but **rounded down to the system page size**
@@ -607,6 +2522,27 @@ pub enum PrlimitResource {
     /// Upon limit, SIGSEGV is sent.
     RLIMIT_STACK = libc::RLIMIT_STACK as i32,
 }
+impl PrlimitResource {
+    /// All the variants of `PrlimitResource`.
+    pub const ALL: [PrlimitResource; 16] = [
+        PrlimitResource::RLIMIT_AS,
+        PrlimitResource::RLIMIT_CORE,
+        PrlimitResource::RLIMIT_CPU,
+        PrlimitResource::RLIMIT_DATA,
+        PrlimitResource::RLIMIT_FSIZE,
+        PrlimitResource::RLIMIT_LOCKS,
+        PrlimitResource::RLIMIT_MEMLOCK,
+        PrlimitResource::RLIMIT_MSGQUEUE,
+        PrlimitResource::RLIMIT_NICE,
+        PrlimitResource::RLIMIT_NOFILE,
+        PrlimitResource::RLIMIT_NPROC,
+        PrlimitResource::RLIMIT_RSS,
+        PrlimitResource::RLIMIT_RTPRIO,
+        PrlimitResource::RLIMIT_RTTIME,
+        PrlimitResource::RLIMIT_SIGPENDING,
+        PrlimitResource::RLIMIT_STACK,
+    ];
+}
 
 ///  * `new_limit` - If `Some(limit) = new_limit`, then the `limit` will be set to the
 ///    new limit for the `resource`.
@@ -641,63 +2577,491 @@ pub fn prlimit(resource: PrlimitResource, new_limit: Option<&binding::rlimit64>)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum PriorityWhichAndWho {
-    PRIO_PROCESS(pid_t),
-    PRIO_PGRP(pid_t),
-    PRIO_USER(uid_t)
+#[derive(Copy, Clone, Debug)]
+pub enum PriorityWhichAndWho {
+    PRIO_PROCESS(pid_t),
+    PRIO_PGRP(pid_t),
+    PRIO_USER(uid_t)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Priority {
+    prio: c_int
+}
+impl Priority {
+    /// * `prio` - should be in range -20..20
+    pub const fn new(prio: c_int) -> Option<Priority> {
+        if prio >= -20 && prio <= 19 {
+            Some(Priority { prio })
+        } else {
+            None
+        }
+    }
+
+    pub const fn get_prio(&self) -> c_int {
+        self.prio
+    }
+}
+
+pub fn getpriority(which_and_who: PriorityWhichAndWho) -> Result<Priority, SyscallError> {
+    let getpriority_impl = |which, who| -> Result<Priority, SyscallError> {
+        let knice = toResult(unsafe { binding::psys_getpriority(which, who) as i64 })?;
+        Ok(Priority { prio: (20 - knice) as c_int })
+    };
+
+    use PriorityWhichAndWho::*;
+
+    match which_and_who {
+        PRIO_PROCESS(pid) => getpriority_impl(libc::PRIO_PROCESS as i32, pid as c_long),
+        PRIO_PGRP(pgid) => getpriority_impl(libc::PRIO_PGRP as i32, pgid as c_long),
+        PRIO_USER(uid) => getpriority_impl(libc::PRIO_USER as i32, uid as c_long),
+    }
+}
+
+pub fn setpriority(which_and_who: PriorityWhichAndWho, prio: Priority)
+    -> Result<(), SyscallError>
+{
+    let setpriority_impl = |which, who| -> Result<(), SyscallError> {
+        let knice = 20 - prio.get_prio();
+        toResult(unsafe { binding::psys_setpriority(which, who, knice) as i64 })?;
+        Ok(())
+    };
+
+    use PriorityWhichAndWho::*;
+
+    match which_and_who {
+        PRIO_PROCESS(pid) => setpriority_impl(libc::PRIO_PROCESS as i32, pid as c_long),
+        PRIO_PGRP(pgid) => setpriority_impl(libc::PRIO_PGRP as i32, pgid as c_long),
+        PRIO_USER(uid) => setpriority_impl(libc::PRIO_USER as i32, uid as c_long),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Signal {
+    sig: c_int
+}
+impl Signal {
+    pub const SIGHUP: Signal = Signal { sig: libc::SIGHUP };
+    pub const SIGINT: Signal = Signal { sig: libc::SIGINT };
+    pub const SIGQUIT: Signal = Signal { sig: libc::SIGQUIT };
+    pub const SIGILL: Signal = Signal { sig: libc::SIGILL };
+    pub const SIGTRAP: Signal = Signal { sig: libc::SIGTRAP };
+    pub const SIGABRT: Signal = Signal { sig: libc::SIGABRT };
+    pub const SIGBUS: Signal = Signal { sig: libc::SIGBUS };
+    pub const SIGFPE: Signal = Signal { sig: libc::SIGFPE };
+    pub const SIGKILL: Signal = Signal { sig: libc::SIGKILL };
+    pub const SIGUSR1: Signal = Signal { sig: libc::SIGUSR1 };
+    pub const SIGSEGV: Signal = Signal { sig: libc::SIGSEGV };
+    pub const SIGUSR2: Signal = Signal { sig: libc::SIGUSR2 };
+    pub const SIGPIPE: Signal = Signal { sig: libc::SIGPIPE };
+    pub const SIGALRM: Signal = Signal { sig: libc::SIGALRM };
+    pub const SIGTERM: Signal = Signal { sig: libc::SIGTERM };
+    pub const SIGCHLD: Signal = Signal { sig: libc::SIGCHLD };
+    pub const SIGCONT: Signal = Signal { sig: libc::SIGCONT };
+    pub const SIGSTOP: Signal = Signal { sig: libc::SIGSTOP };
+    pub const SIGTSTP: Signal = Signal { sig: libc::SIGTSTP };
+    pub const SIGTTIN: Signal = Signal { sig: libc::SIGTTIN };
+    pub const SIGTTOU: Signal = Signal { sig: libc::SIGTTOU };
+    pub const SIGURG: Signal = Signal { sig: libc::SIGURG };
+    pub const SIGXCPU: Signal = Signal { sig: libc::SIGXCPU };
+    pub const SIGXFSZ: Signal = Signal { sig: libc::SIGXFSZ };
+    pub const SIGVTALRM: Signal = Signal { sig: libc::SIGVTALRM };
+    pub const SIGPROF: Signal = Signal { sig: libc::SIGPROF };
+    pub const SIGWINCH: Signal = Signal { sig: libc::SIGWINCH };
+    pub const SIGIO: Signal = Signal { sig: libc::SIGIO };
+    pub const SIGPWR: Signal = Signal { sig: libc::SIGPWR };
+    pub const SIGSYS: Signal = Signal { sig: libc::SIGSYS };
+
+    /// * `sig` - should be in range 1..=64
+    pub const fn new(sig: c_int) -> Option<Signal> {
+        if sig >= 1 && sig <= 64 {
+            Some(Signal { sig })
+        } else {
+            None
+        }
+    }
+
+    pub const fn get_signo(&self) -> c_int {
+        self.sig
+    }
+}
+
+/// Send `sig` to `pid`.
+///
+///  * `pid` - if negative, then `sig` is sent to every process in the process
+///    group `-pid`.
+///
+/// Check manpage for kill for more documentation.
+pub fn kill(pid: pid_t, sig: Signal) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_kill(pid, sig.get_signo()) } as i64)?;
+    Ok(())
+}
+
+/// Send `sig` to thread `tid` in thread group `tgid`, e.g.
+/// `tgkill(getpid(), gettid(), sig)` is the async-signal-safe version of
+/// `raise(sig)`.
+///
+/// Checking `tgid` guards against sending the signal to another process if
+/// the thread has exited and its tid is reused.
+///
+/// Check manpage for tgkill for more documentation.
+pub fn tgkill(tgid: pid_t, tid: pid_t, sig: Signal) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_tgkill(tgid, tid, sig.get_signo()) } as i64)?;
+    Ok(())
+}
+
+/// Layout of `siginfo_t` for signals sent via `sigqueue`.
+///
+/// The union of fields following `si_code` is aligned to pointer, same as
+/// `RtSigFields`, thus it matches the kernel on both 32-bit and 64-bit.
+#[repr(C)]
+struct RtSigInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    rt: RtSigFields,
+}
+#[repr(C)]
+struct RtSigFields {
+    si_pid: pid_t,
+    si_uid: uid_t,
+    si_value: SigVal,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+union SigVal {
+    sival_int: c_int,
+    sival_ptr: *mut c_void,
+}
+
+/// Queue `sig` along with `value` to `pid`, which can be retrieved via
+/// `SigInfo::get_value` from `sigtimedwait`.
+///
+/// Unlike standard signals, multiple instances of a real-time signal, i.e.
+/// `SIGRTMIN..=SIGRTMAX`, are queued instead of being merged.
+///
+/// Check manpage for sigqueue and rt_sigqueueinfo for more documentation.
+pub fn sigqueue(pid: pid_t, sig: Signal, value: c_int) -> Result<(), SyscallError> {
+    let mut info = std::mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+
+    unsafe {
+        (info.as_mut_ptr() as *mut RtSigInfo).write(RtSigInfo {
+            si_signo: sig.get_signo(),
+            si_errno: 0,
+            si_code: libc::SI_QUEUE,
+            rt: RtSigFields {
+                si_pid: getpid(),
+                si_uid: getuid(),
+                si_value: SigVal { sival_int: value },
+            },
+        });
+    }
+
+    toResult(unsafe {
+        binding::psys_rt_sigqueueinfo(pid, sig.get_signo(), info.as_mut_ptr() as *mut c_void)
+    } as i64)?;
+    Ok(())
+}
+
+/// Send `sig` to the process referred to by `pidfd`.
+///
+/// Unlike `kill`, it is not prone to pid reuse.
+///
+/// Requires linux 5.1.
+///
+/// Check manpage for pidfd_send_signal for more documentation.
+pub fn pidfd_send_signal(pidfd: &Fd, sig: Signal) -> Result<(), SyscallError> {
+    toResult(unsafe {
+        binding::psys_pidfd_send_signal(pidfd.get_fd(), sig.get_signo(), std::ptr::null_mut(), 0)
+    } as i64)?;
+    Ok(())
+}
+
+/// Duplicate fd `target_fd` of the process referred to by `pidfd` into the
+/// calling process, which is `O_CLOEXEC`, like receiving it via
+/// `SCM_RIGHTS` but without the cooperation of the process.
+///
+/// The new fd shares the open file description with `target_fd`, e.g. the
+/// file offset and status flags.
+///
+/// Requires `PTRACE_MODE_ATTACH_REALCREDS` permission over the process, which
+/// usually means the same uid or `CAP_SYS_PTRACE`, otherwise `EPERM` is
+/// returned.
+///
+/// Requires linux 5.6.
+///
+/// # Errors
+///
+///  - `EBADF` if `target_fd` is not open in the process, which is racy since
+///    the process might close or replace it at any time unless it is stopped.
+///  - `ESRCH` if the process has terminated.
+///
+/// Check manpage for pidfd_getfd for more documentation.
+pub fn pidfd_getfd(pidfd: &Fd, target_fd: c_int) -> Result<FdBox, SyscallError> {
+    let fd = toResult(unsafe {
+        binding::psys_pidfd_getfd(pidfd.get_fd(), target_fd, 0)
+    } as i64)?;
+    Ok(unsafe { FdBox::from_raw(fd as c_int) })
+}
+
+bitflags! {
+    pub struct EpollEvents: u32 {
+        const EPOLLIN = libc::EPOLLIN as u32;
+        const EPOLLPRI = libc::EPOLLPRI as u32;
+        const EPOLLOUT = libc::EPOLLOUT as u32;
+        const EPOLLERR = libc::EPOLLERR as u32;
+        const EPOLLHUP = libc::EPOLLHUP as u32;
+        const EPOLLRDHUP = libc::EPOLLRDHUP as u32;
+        const EPOLLET = libc::EPOLLET as u32;
+        const EPOLLONESHOT = libc::EPOLLONESHOT as u32;
+    }
+}
+
+/// `struct epoll_event` filled by `EpollReactor::wait`.
+#[repr(transparent)]
+#[derive(Copy, Clone)]
+pub struct EpollEvent {
+    event: libc::epoll_event,
+}
+impl Default for EpollEvent {
+    fn default() -> Self {
+        EpollEvent::new(EpollEvents::empty(), 0)
+    }
+}
+impl EpollEvent {
+    fn new(events: EpollEvents, token: u64) -> EpollEvent {
+        EpollEvent {
+            event: libc::epoll_event { events: events.bits, u64: token },
+        }
+    }
+
+    pub fn get_events(&self) -> EpollEvents {
+        EpollEvents::from_bits_truncate(self.event.events)
+    }
+
+    /// The token passed to `EpollReactor::add` or `EpollReactor::modify`.
+    pub fn get_token(&self) -> u64 {
+        self.event.u64
+    }
+}
+impl std::fmt::Debug for EpollEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EpollEvent")
+            .field("events", &self.get_events())
+            .field("token", &self.get_token())
+            .finish()
+    }
+}
+
+/// A bare epoll instance, which can be used to wait on signalfd, pidfds and
+/// any other fds without any async runtime.
+///
+/// **All APIs of this struct are safe to be used inside avfork callback.**
+#[derive(Debug)]
+pub struct EpollReactor {
+    fd: FdBox,
+}
+impl EpollReactor {
+    /// Create an epoll instance via `epoll_create1(EPOLL_CLOEXEC)`.
+    pub fn new() -> Result<EpollReactor, SyscallError> {
+        let fd = toResult(unsafe {
+            binding::psys_epoll_create1(libc::EPOLL_CLOEXEC)
+        } as i64)?;
+        Ok(EpollReactor { fd: unsafe { FdBox::from_raw(fd as c_int) } })
+    }
+
+    pub fn get_fd(&self) -> Fd {
+        *self.fd
+    }
+
+    fn ctl(&self, op: c_int, fd: &Fd, event: Option<EpollEvent>) -> Result<(), SyscallError> {
+        let mut event = event;
+        let event_ptr: *mut c_void = match &mut event {
+            Some(event) => event as *mut EpollEvent as *mut c_void,
+            None => std::ptr::null_mut(),
+        };
+
+        toResult(unsafe {
+            binding::psys_epoll_ctl(self.fd.get_fd(), op, fd.get_fd(), event_ptr)
+        } as i64)?;
+        Ok(())
+    }
+
+    /// Register `fd`, whose readiness is reported along with `token` by
+    /// `wait`.
+    ///
+    /// `EEXIST` is returned if `fd` is already registered.
+    pub fn add(&self, fd: &Fd, events: EpollEvents, token: u64) -> Result<(), SyscallError> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, Some(EpollEvent::new(events, token)))
+    }
+
+    /// Change the events and token of `fd`, which must be registered.
+    pub fn modify(&self, fd: &Fd, events: EpollEvents, token: u64) -> Result<(), SyscallError> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, Some(EpollEvent::new(events, token)))
+    }
+
+    /// Deregister `fd`.
+    ///
+    /// Closing `fd` deregisters it automatically only if it is the last fd
+    /// referring to the open file description.
+    pub fn del(&self, fd: &Fd) -> Result<(), SyscallError> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, None)
+    }
+
+    /// Wait for any of the registered fds to become ready.
+    ///
+    ///  * `events_out` - must not be empty, otherwise `EINVAL` is returned.
+    ///  * `timeout` - If `None`, then wait indefinitely.
+    ///    It is rounded up to milliseconds and restarted from scratch if
+    ///    interrupted by a signal.
+    ///
+    /// Returns the number of events stored in `events_out`, which is 0 if
+    /// timed out.
+    pub fn wait(&self, events_out: &mut [EpollEvent], timeout: Option<Duration>)
+        -> Result<usize, SyscallError>
+    {
+        let timeout = match timeout {
+            None => -1,
+            Some(timeout) => {
+                let ms = timeout.as_millis() + (timeout.subsec_nanos() % 1_000_000 != 0) as u128;
+                ms.min(c_int::MAX as u128) as c_int
+            },
+        };
+        let maxevents = events_out.len().min(c_int::MAX as usize) as c_int;
+
+        let cnt = autorestart(|| {
+            toResult(unsafe {
+                binding::psys_epoll_pwait(
+                    self.fd.get_fd(),
+                    events_out.as_mut_ptr() as *mut c_void,
+                    maxevents,
+                    timeout,
+                    std::ptr::null(),
+                    0
+                )
+            } as i64)
+        })?;
+
+        Ok(cnt as usize)
+    }
+}
+
+// Here it relies on the compiler to check that i32 == c_int
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MembarrierCmd {
+    /// Wait until all running threads of all processes have passed through a
+    /// state where all memory accesses to user-space addresses match program
+    /// order.
+    ///
+    /// It is slow, taking at least a scheduler tick, but requires no
+    /// registration.
+    Global = 1 << 0,
+    /// Same as `Global`, but only for the threads of the calling process,
+    /// which is done via IPIs, thus much faster.
+    ///
+    /// The process must have registered via `RegisterPrivateExpedited`,
+    /// otherwise `EPERM` is returned.
+    PrivateExpedited = 1 << 3,
+    /// Register the intent of the process to use `PrivateExpedited`.
+    RegisterPrivateExpedited = 1 << 4,
+}
+
+bitflags! {
+    /// Commands supported by the kernel, returned by `membarrier_query`.
+    pub struct MembarrierSupport: c_int {
+        const GLOBAL = 1 << 0;
+        const GLOBAL_EXPEDITED = 1 << 1;
+        const REGISTER_GLOBAL_EXPEDITED = 1 << 2;
+        const PRIVATE_EXPEDITED = 1 << 3;
+        const REGISTER_PRIVATE_EXPEDITED = 1 << 4;
+        const PRIVATE_EXPEDITED_SYNC_CORE = 1 << 5;
+        const REGISTER_PRIVATE_EXPEDITED_SYNC_CORE = 1 << 6;
+        const PRIVATE_EXPEDITED_RSEQ = 1 << 7;
+        const REGISTER_PRIVATE_EXPEDITED_RSEQ = 1 << 8;
+    }
+}
+
+/// Issue a memory barrier on a set of threads, so that memory shared via
+/// `mmap(MAP_SHARED)` can be accessed without fences on the fast path.
+///
+/// Use `membarrier_query` to check whether `cmd` is supported, otherwise
+/// `EINVAL` is returned.
+///
+/// Check manpage for membarrier for more documentation.
+pub fn membarrier(cmd: MembarrierCmd) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_membarrier(cmd as c_int, 0, 0) } as i64)?;
+    Ok(())
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct Priority {
-    prio: c_int
+/// Returns the commands supported by the kernel via `MEMBARRIER_CMD_QUERY`.
+///
+/// Check manpage for membarrier for more documentation.
+pub fn membarrier_query() -> Result<MembarrierSupport, SyscallError> {
+    let ret = toResult(unsafe { binding::psys_membarrier(0, 0, 0) } as i64)?;
+    Ok(MembarrierSupport::from_bits_truncate(ret as c_int))
 }
-impl Priority {
-    /// * `prio` - should be in range -20..20
-    pub const fn new(prio: c_int) -> Option<Priority> {
-        if prio >= -20 && prio <= 19 {
-            Some(Priority { prio })
-        } else {
-            None
-        }
-    }
 
-    pub const fn get_prio(&self) -> c_int {
-        self.prio
+bitflags! {
+    pub struct GetrandomFlags: libc::c_uint {
+        /// Return `EAGAIN` instead of blocking if the entropy pool is not
+        /// initialized yet.
+        const GRND_NONBLOCK = libc::GRND_NONBLOCK;
+        /// Read from the blocking pool of `/dev/random` instead.
+        const GRND_RANDOM = libc::GRND_RANDOM;
     }
 }
 
-pub fn getpriority(which_and_who: PriorityWhichAndWho) -> Result<Priority, SyscallError> {
-    let getpriority_impl = |which, who| -> Result<Priority, SyscallError> {
-        let knice = toResult(unsafe { binding::psys_getpriority(which, who) as i64 })?;
-        Ok(Priority { prio: (20 - knice) as c_int })
-    };
-
-    use PriorityWhichAndWho::*;
+/// Fill `buf` with random bytes from the kernel.
+///
+/// Returns number of bytes filled, which is always `buf.len()` if it is no
+/// more than 256 bytes and no signal interrupts it.
+///
+/// Check manpage for getrandom for more documentation.
+pub fn getrandom(buf: &mut [u8], flags: GetrandomFlags) -> Result<usize, SyscallError> {
+    let buf_ptr = buf.as_mut_ptr() as *mut c_void;
+    Ok(toResult(unsafe {
+        binding::psys_getrandom(buf_ptr, buf.len() as u64, flags.bits)
+    } as i64)? as usize)
+}
 
-    match which_and_who {
-        PRIO_PROCESS(pid) => getpriority_impl(libc::PRIO_PROCESS as i32, pid as c_long),
-        PRIO_PGRP(pgid) => getpriority_impl(libc::PRIO_PGRP as i32, pgid as c_long),
-        PRIO_USER(uid) => getpriority_impl(libc::PRIO_USER as i32, uid as c_long),
+bitflags! {
+    pub struct MlockallFlags: c_int {
+        /// Lock all pages currently mapped.
+        const MCL_CURRENT = libc::MCL_CURRENT;
+        /// Lock all pages mapped in the future, e.g. heap and stack growth.
+        const MCL_FUTURE = libc::MCL_FUTURE;
+        /// Used together with `MCL_CURRENT` and/or `MCL_FUTURE`, only lock
+        /// the pages once they are faulted in.
+        const MCL_ONFAULT = libc::MCL_ONFAULT;
     }
 }
 
-pub fn setpriority(which_and_who: PriorityWhichAndWho, prio: Priority)
-    -> Result<(), SyscallError>
-{
-    let setpriority_impl = |which, who| -> Result<(), SyscallError> {
-        let knice = 20 - prio.get_prio();
-        toResult(unsafe { binding::psys_setpriority(which, who, knice) as i64 })?;
-        Ok(())
-    };
-
-    use PriorityWhichAndWho::*;
+/// Lock the address space of the calling process into RAM, so that accessing
+/// it never triggers major page faults.
+///
+/// It requires `CAP_IPC_LOCK`, otherwise the total size locked must be within
+/// `RLIMIT_MEMLOCK`, or `ENOMEM`/`EPERM` is returned.
+///
+/// Never call it in the callback of `avfork`: the child shares the address
+/// space of the parent via `CLONE_VM`, so it would lock the whole parent into
+/// RAM, and the locks outlive the `execve` of the child.
+/// Since all the locks, including `MCL_FUTURE`, are removed by `execve`, a
+/// real-time program, e.g. spawned with `SchedPolicy::SCHED_FIFO`, has to call
+/// `mlockall` itself.
+///
+/// Check manpage for mlockall for more documentation.
+pub fn mlockall(flags: MlockallFlags) -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_mlockall(flags.bits) } as i64)?;
+    Ok(())
+}
 
-    match which_and_who {
-        PRIO_PROCESS(pid) => setpriority_impl(libc::PRIO_PROCESS as i32, pid as c_long),
-        PRIO_PGRP(pgid) => setpriority_impl(libc::PRIO_PGRP as i32, pgid as c_long),
-        PRIO_USER(uid) => setpriority_impl(libc::PRIO_USER as i32, uid as c_long),
-    }
+/// Unlock all pages locked by `mlockall` or `mlock`.
+///
+/// Check manpage for munlockall for more documentation.
+pub fn munlockall() -> Result<(), SyscallError> {
+    toResult(unsafe { binding::psys_munlockall() } as i64)?;
+    Ok(())
 }
 
 pub fn sigemptyset() -> sigset_t {
@@ -718,6 +3082,298 @@ pub fn sigfillset() -> sigset_t {
     }
 }
 
+pub fn sigaddset(set: &mut sigset_t, sig: Signal) {
+    unsafe {
+        binding::pure_sigaddset(set as *mut sigset_t as *mut c_void, sig.get_signo());
+    }
+}
+
+pub fn sigdelset(set: &mut sigset_t, sig: Signal) {
+    unsafe {
+        binding::pure_sigdelset(set as *mut sigset_t as *mut c_void, sig.get_signo());
+    }
+}
+
+pub fn sigismember(set: &sigset_t, sig: Signal) -> bool {
+    unsafe {
+        binding::pure_sigismember(set as *const sigset_t as *const c_void, sig.get_signo()) != 0
+    }
+}
+
+/// `struct sigaction` used by the kernel, which differs from the one in libc.
+///
+/// Its layout is arch-specific: `sa_restorer` only exists on archs defining
+/// `SA_RESTORER`, and archs like mips reorder the fields and use a larger
+/// `sigset_t`, so it is only defined for the archs checked below.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x",
+))]
+#[repr(C)]
+struct KernelSigaction {
+    sa_handler: libc::sighandler_t,
+    sa_flags: libc::c_ulong,
+    sa_restorer: libc::sighandler_t,
+    sa_mask: u64,
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x",
+))]
+impl KernelSigaction {
+    fn new(sa_handler: libc::sighandler_t) -> Self {
+        Self {
+            sa_handler,
+            sa_flags: 0,
+            sa_restorer: 0,
+            sa_mask: 0,
+        }
+    }
+
+    /// The kernel requires `SA_RESTORER` on x86_64 to deliver the signal,
+    /// thus `sa_restorer` is set to `exit_from_handler`.
+    fn with_siginfo(handler: SigInfoHandler) -> Self {
+        Self {
+            sa_handler: handler as libc::sighandler_t,
+            sa_flags: SIGINFO_FLAGS | SA_RESTORER,
+            sa_restorer: exit_from_handler as libc::sighandler_t,
+            sa_mask: 0,
+        }
+    }
+}
+
+/// Not exposed by libc for every arch, but the same on all archs defining it.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x",
+))]
+const SA_RESTORER: libc::c_ulong = 0x04000000;
+
+/// Used as `sa_restorer` of the handlers installed via `sigaction_on_altstack`,
+/// which must never return.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x",
+))]
+extern "C" fn exit_from_handler() {
+    exit(1)
+}
+
+#[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
+#[repr(C)]
+struct KernelSigaction {
+    sa_handler: libc::sighandler_t,
+    sa_flags: libc::c_ulong,
+    sa_mask: u64,
+}
+
+#[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
+impl KernelSigaction {
+    fn new(sa_handler: libc::sighandler_t) -> Self {
+        Self {
+            sa_handler,
+            sa_flags: 0,
+            sa_mask: 0,
+        }
+    }
+
+    fn with_siginfo(handler: SigInfoHandler) -> Self {
+        Self {
+            sa_handler: handler as libc::sighandler_t,
+            sa_flags: SIGINFO_FLAGS,
+            sa_mask: 0,
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x",
+    target_arch = "riscv64",
+    target_arch = "loongarch64",
+)))]
+compile_error!("the layout of the kernel struct sigaction is unknown on this arch");
+
+/// Reset the handler of every signal that is caught to `SIG_DFL`, while keeping
+/// signals that are ignored ignored, just like `execve`.
+///
+/// This is required in a child sharing the address space with its parent,
+/// otherwise the handlers of the parent might run in the child once signals
+/// are unmasked.
+pub fn reset_signal_handlers() -> Result<(), SyscallError> {
+    let dfl = KernelSigaction::new(libc::SIG_DFL);
+
+    for sig in 1..=64 {
+        if sig == libc::SIGKILL || sig == libc::SIGSTOP {
+            continue;
+        }
+
+        let mut old = std::mem::MaybeUninit::<KernelSigaction>::uninit();
+        let ret = unsafe {
+            binding::psys_rt_sigaction(
+                sig,
+                std::ptr::null(),
+                old.as_mut_ptr() as *mut c_void,
+                std::mem::size_of::<u64>() as u64
+            )
+        };
+        // Signals reserved by the kernel or libc return EINVAL
+        if toResult(ret as i64).is_err() {
+            continue;
+        }
+
+        let handler = unsafe { old.assume_init() }.sa_handler;
+        if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+            continue;
+        }
+
+        toResult(unsafe {
+            binding::psys_rt_sigaction(
+                sig,
+                to_void_ptr(&dfl),
+                std::ptr::null_mut(),
+                std::mem::size_of::<u64>() as u64
+            )
+        } as i64)?;
+    }
+
+    Ok(())
+}
+
+/// Set the disposition of `sig` to `SIG_IGN`, which is preserved across
+/// `execve`.
+///
+/// The child created by `avfork` does not share signal dispositions with its
+/// parent, so calling it in the callback does not affect the parent.
+///
+/// `EINVAL` is returned for `SIGKILL` and `SIGSTOP`.
+///
+/// Check manpage for sigaction for more documentation.
+pub fn signal_ignore(sig: Signal) -> Result<(), SyscallError> {
+    set_signal_disposition(sig, libc::SIG_IGN)
+}
+
+/// Set the disposition of `sig` to `SIG_DFL`, e.g. to undo `signal_ignore`.
+///
+/// Same as `signal_ignore`, calling it in the avfork callback does not affect
+/// the parent.
+///
+/// `EINVAL` is returned for `SIGKILL` and `SIGSTOP`.
+///
+/// Check manpage for sigaction for more documentation.
+pub fn signal_default(sig: Signal) -> Result<(), SyscallError> {
+    set_signal_disposition(sig, libc::SIG_DFL)
+}
+
+fn set_signal_disposition(sig: Signal, handler: libc::sighandler_t) -> Result<(), SyscallError> {
+    let act = KernelSigaction::new(handler);
+
+    toResult(unsafe {
+        binding::psys_rt_sigaction(
+            sig.get_signo(),
+            to_void_ptr(&act),
+            std::ptr::null_mut(),
+            std::mem::size_of::<u64>() as u64
+        )
+    } as i64)?;
+
+    Ok(())
+}
+
+/// Handler installed via `sigaction_on_altstack`, which receives the
+/// `siginfo_t` of the signal.
+pub type SigInfoHandler = extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void);
+
+/// `SA_RESETHAND` so that the same signal raised again in or after the handler
+/// takes the default action.
+const SIGINFO_FLAGS: libc::c_ulong =
+    (libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESETHAND) as libc::c_ulong;
+
+/// Install `handler` for `sig`, which runs on the alternate signal stack set
+/// via `set_sigaltstack`, so that it can handle `SIGSEGV` caused by stack
+/// overflow.
+///
+/// The disposition is reset to `SIG_DFL` once `handler` is invoked.
+///
+/// `handler` must never return: it has to terminate the process instead, e.g.
+/// by raising `sig` again after unblocking it.
+///
+/// Check manpage for sigaction for more documentation.
+pub fn sigaction_on_altstack(sig: Signal, handler: SigInfoHandler)
+    -> Result<(), SyscallError>
+{
+    let act = KernelSigaction::with_siginfo(handler);
+
+    toResult(unsafe {
+        binding::psys_rt_sigaction(
+            sig.get_signo(),
+            to_void_ptr(&act),
+            std::ptr::null_mut(),
+            std::mem::size_of::<u64>() as u64
+        )
+    } as i64)?;
+
+    Ok(())
+}
+
+/// Use `stack` as the alternate signal stack of the calling thread.
+///
+/// Check manpage for sigaltstack for more documentation.
+///
+/// # Safety
+///
+/// `stack` must be alive until the thread calls `execve` or exits, or the
+/// alternate signal stack is replaced.
+pub unsafe fn set_sigaltstack(stack: &mut [u8]) -> Result<(), SyscallError> {
+    let ss = libc::stack_t {
+        ss_sp: stack.as_mut_ptr() as *mut c_void,
+        ss_flags: 0,
+        ss_size: stack.len(),
+    };
+
+    toResult(binding::psys_sigaltstack(to_void_ptr(&ss), std::ptr::null_mut()) as i64)?;
+
+    Ok(())
+}
+
+/// Returns the alternate signal stack of the calling thread set via
+/// `set_sigaltstack`.
+pub fn get_sigaltstack() -> Result<libc::stack_t, SyscallError> {
+    let mut ss = std::mem::MaybeUninit::<libc::stack_t>::uninit();
+
+    toResult(unsafe {
+        binding::psys_sigaltstack(std::ptr::null(), ss.as_mut_ptr() as *mut c_void)
+    } as i64)?;
+
+    Ok(unsafe { ss.assume_init() })
+}
+
 // Here it relies on the compiler to check that i32 == c_int
 #[repr(i32)]
 #[derive(Copy, Clone, Debug)]
@@ -754,6 +3410,89 @@ pub fn sigprocmask(how: SigprocmaskHow, new_set: Option<&sigset_t>)
     Ok(unsafe { old_set.assume_init() })
 }
 
+#[derive(Copy, Clone)]
+pub struct SigInfo {
+    info: libc::siginfo_t,
+}
+impl SigInfo {
+    /// The signal number
+    pub fn get_signo(&self) -> c_int {
+        self.info.si_signo
+    }
+
+    /// pid of the sender, or of the child for `SIGCHLD`
+    pub fn get_pid(&self) -> pid_t {
+        unsafe { self.info.si_pid() }
+    }
+
+    /// ruid of the sender, or of the child for `SIGCHLD`
+    pub fn get_uid(&self) -> uid_t {
+        unsafe { self.info.si_uid() }
+    }
+
+    /// For `SIGCHLD`, the exit status or the signal that changes the state of the
+    /// child depending on `si_code`.
+    pub fn get_status(&self) -> c_int {
+        unsafe { self.info.si_status() }
+    }
+
+    /// For signals sent via `sigqueue`, the value queued along with the signal.
+    pub fn get_value(&self) -> c_int {
+        let info = &self.info as *const libc::siginfo_t as *const RtSigInfo;
+        unsafe { (*info).rt.si_value.sival_int }
+    }
+
+    /// How the signal is generated, e.g. `SI_QUEUE` for `sigqueue`.
+    pub fn get_code(&self) -> c_int {
+        self.info.si_code
+    }
+}
+impl std::fmt::Debug for SigInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{signo = {}, pid = {}, uid = {}, status = {}}}",
+                  self.get_signo(), self.get_pid(), self.get_uid(), self.get_status())
+    }
+}
+
+/// Synchronously wait for one of the signals in `set` to become pending and
+/// consume it.
+///
+/// Signals in `set` should be blocked via `sigprocmask` before calling this
+/// function, otherwise they might be delivered to signal handler instead.
+///
+///  * `timeout` - If `None`, then wait indefinitely.
+///
+/// # Errors
+///
+///  - `EAGAIN` if none of the signals in `set` becomes pending within `timeout`.
+///  - `EINTR` if interrupted by a signal handler for a signal not in `set`.
+///
+/// Check manpage for sigtimedwait for more documentation.
+pub fn sigtimedwait(set: &sigset_t, timeout: Option<Duration>)
+    -> Result<SigInfo, SyscallError>
+{
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout_ptr: *const c_void = match &timeout {
+        Some(timeout) => to_void_ptr(timeout),
+        None => std::ptr::null()
+    };
+    let mut info = std::mem::MaybeUninit::<libc::siginfo_t>::uninit();
+
+    let ret = unsafe {
+        binding::psys_rt_sigtimedwait(
+            to_void_ptr(set),
+            info.as_mut_ptr() as *mut c_void,
+            timeout_ptr
+        )
+    };
+    toResult(ret as i64)?;
+
+    Ok(SigInfo { info: unsafe { info.assume_init() } })
+}
+
 pub fn exit(status: c_int) -> ! {
     unsafe {
         binding::psys_exit(status);
@@ -847,6 +3586,26 @@ pub fn execveat(
     }
 }
 
+/// Execute the program referred to by `fd` via `execveat(fd, "", ..., AT_EMPTY_PATH)`.
+///
+/// Combined with a sealed memfd, it enables executing a program that has no
+/// path on the filesystem at all.
+///
+/// The file referred to by `fd` must be executable: for memfd, it must not be
+/// created with `MFD_NOEXEC_SEAL`, and the fd must be opened for reading.
+///
+/// If `execveat` needs to be emulated via `/proc/self/fd`, then a `/proc`
+/// that is not mounted or mounted with `noexec` would cause it to fail.
+///
+/// If `fd` is `O_CLOEXEC` and the program is a script, then it fails with
+/// `ENOENT`, since the interpreter can no longer access the script after exec.
+pub fn fexecve(fd: &Fd, argv: &CStrArray, envp: &CStrArray) -> SyscallError {
+    // execveat accepts any fd as long as AT_EMPTY_PATH is passed.
+    let fd = unsafe { FdPath::from_raw(fd.get_fd()) };
+
+    execveat(fd, cstr!(""), argv, envp, ExecveAtFlags::AT_EMPTY_PATH)
+}
+
 /// linux/limits.h say PATH_MAX is 4096, but it seems that the filesystem on linux
 /// does not actually hardcoded this limit
 /// 
@@ -893,15 +3652,85 @@ impl<'a> Filename<'a> {
     }
 }
 
+/// Entry of a directory returned by `Dirents`.
+#[derive(Copy, Clone, Debug)]
+pub struct Dirent<'a> {
+    ino: u64,
+    d_type: u8,
+    name: &'a CStr,
+}
+impl<'a> Dirent<'a> {
+    pub fn get_ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// One of `libc::DT_*`, `DT_UNKNOWN` if the filesystem does not support it.
+    pub fn get_type(&self) -> u8 {
+        self.d_type
+    }
+
+    pub fn get_name(&self) -> &'a CStr {
+        self.name
+    }
+}
+
+/// Iterator over the `struct linux_dirent64` filled by `Fd::getdents`.
+///
+/// **This iterator does not allocate, thus is safe to be used inside avfork
+/// callback.**
+#[derive(Copy, Clone, Debug)]
+pub struct Dirents<'a> {
+    buf: &'a [u8],
+}
+impl<'a> Dirents<'a> {
+    /// Offset of d_name in `struct linux_dirent64`
+    const NAME_OFFSET: usize = 19;
+
+    /// * `buf` - the part of the buffer filled by `Fd::getdents`.
+    pub fn new(buf: &'a [u8]) -> Dirents<'a> {
+        Dirents { buf }
+    }
+}
+impl<'a> Iterator for Dirents<'a> {
+    type Item = Dirent<'a>;
+
+    fn next(&mut self) -> Option<Dirent<'a>> {
+        let buf = self.buf;
+        if buf.len() < Dirents::NAME_OFFSET {
+            return None;
+        }
+
+        let reclen = u16::from_ne_bytes([buf[16], buf[17]]) as usize;
+        if reclen <= Dirents::NAME_OFFSET || reclen > buf.len() {
+            return None;
+        }
+
+        let name = &buf[Dirents::NAME_OFFSET..reclen];
+        let name_len = name.iter().position(|byte| *byte == 0)?;
+
+        let mut ino = [0 as u8; 8];
+        ino.copy_from_slice(&buf[..8]);
+
+        self.buf = &buf[reclen..];
+
+        Some(Dirent {
+            ino: u64::from_ne_bytes(ino),
+            d_type: buf[18],
+            // name[..=name_len] contains exactly one nul byte at its end
+            name: unsafe { CStr::from_bytes_with_nul_unchecked(&name[..=name_len]) },
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct ExecvelCandidate<'a> {
     filename: Filename<'a>,
     paths: &'a [&'a str]
 }
 impl<'a> ExecvelCandidate<'a> {
-    /// * `paths` - must not be empty and neither should each element in it be empty,
-    ///   and len of each element plus len of filename plus 1 must be less than 
-    ///   `PATH_MAX`.
+    /// * `paths` - must not be empty and neither should each element in it be empty
+    ///   or contain any nul byte, and len of each element plus len of filename
+    ///   plus 1 must be less than `PATH_MAX`.
     pub fn new(filename: Filename<'a>, paths: &'a [&'a str])
         -> Option<ExecvelCandidate<'a>>
     {
@@ -916,7 +3745,7 @@ impl<'a> ExecvelCandidate<'a> {
 
             // The additional two bytes is for the slash and the null bytes
             let size = filename_sz + path.len() + 2;
-            if path.is_empty() || size > PATH_MAX {
+            if path.is_empty() || path.contains(&0) || size > PATH_MAX {
                 return None;
             }
         }
@@ -924,12 +3753,107 @@ impl<'a> ExecvelCandidate<'a> {
         Some(ExecvelCandidate { filename, paths })
     }
 
-    pub fn get_filename(&'a self) -> &'a Filename<'a> {
-        &self.filename
+    pub fn get_filename(&'a self) -> &'a Filename<'a> {
+        &self.filename
+    }
+
+    pub fn get_paths(&self) -> &'a [&'a str] {
+        self.paths
+    }
+
+    /// Search for the executable the same way `execvel` does, but check each
+    /// candidate with `faccessat(X_OK)` instead of executing it and return
+    /// the first match, so that the search can be done once and the result
+    /// passed to `execve` in subsequent spawns.
+    ///
+    /// The precedence of errors is the same as `execvel`: if no executable
+    /// is found, `EACCES` is returned if any of the candidates is denied,
+    /// otherwise `ENOENT`.
+    ///
+    /// The returned path is absolute only if the matching element of `paths`
+    /// is.
+    ///
+    /// Since `X_OK` is always granted to root as long as any of the execute
+    /// bits is set, and a directory passes the check as well, `execve` on the
+    /// returned path can still fail.
+    ///
+    /// This function allocates, thus it must not be called in the callback
+    /// of `avfork`.
+    pub fn resolve(&self) -> Result<CString, SyscallError> {
+        let filename = self.get_filename().to_bytes();
+
+        let mut errors = SearchErrors::default();
+
+        for path in self.get_paths().iter() {
+            let path = path.as_bytes();
+
+            let mut constructed_path = Vec::with_capacity(path.len() + 1 + filename.len() + 1);
+            constructed_path.extend_from_slice(path);
+            constructed_path.push(b'/');
+            constructed_path.extend_from_slice(filename);
+
+            let constructed_path = match CString::new(constructed_path) {
+                Ok(constructed_path) => constructed_path,
+                // Entries with nul byte are rejected by `new`
+                Err(_) => continue,
+            };
+
+            let ret = unsafe {
+                binding::psys_faccessat(
+                    AT_FDCWD.get_fd(), constructed_path.as_ptr(), AccessCheck::X_OK.bits
+                )
+            };
+            match toResult(ret as i64) {
+                Ok(_) => return Ok(constructed_path),
+                Err(err) => errors.record(err)?,
+            };
+        }
+
+        Err(errors.into_error())
+    }
+}
+
+/// Errors of trying each candidate when searching for an executable, shared
+/// by `execvel`, `fexecvel` and `ExecvelCandidate::resolve`.
+#[derive(Default)]
+struct SearchErrors {
+    got_eaccess: bool,
+}
+impl SearchErrors {
+    /// Returns `Err(err)` if the search should stop with `err`, otherwise the
+    /// next candidate should be tried.
+    fn record(&mut self, err: SyscallError) -> Result<(), SyscallError> {
+        match err.get_errno() as i32 {
+            libc::EACCES => {
+                // Record that we got a 'Permission denied' error.  If we end
+                // up finding no executable we can use, we want to diagnose
+                // that we did find one but were denied access.
+                self.got_eaccess = true;
+                Ok(())
+            },
+            // Those errors indicate the file is missing or not executable
+            // by us, in which case we want to just try the next path
+            // directory.
+            libc::ENOENT  => Ok(()),
+            libc::ESTALE  => Ok(()),
+            libc::ENOTDIR => Ok(()),
+            // Some strange filesystems like AFS return even
+            // stranger error numbers.  They cannot reasonably mean
+            // anything else so ignore those, too.
+            libc::ENODEV    => Ok(()),
+            libc::ETIMEDOUT => Ok(()),
+
+            _ => Err(err),
+        }
     }
 
-    pub fn get_paths(&self) -> &'a [&'a str] {
-        self.paths
+    /// The error to return if no executable is found.
+    fn into_error(self) -> SyscallError {
+        if self.got_eaccess {
+            SyscallError::new(libc::EACCES as u32)
+        } else {
+            SyscallError::new(libc::ENOENT as u32)
+        }
     }
 }
 
@@ -977,7 +3901,7 @@ pub fn execvel(
     let filename_sz = filename.len();
     let filename = filename.as_ptr();
 
-    let mut got_eaccess = false;
+    let mut errors = SearchErrors::default();
 
     for path in candidate.get_paths().iter() {
         let path = path.as_bytes();
@@ -997,35 +3921,12 @@ pub fn execvel(
             Err(err) => err
         };
 
-        match err.get_errno() as i32 {
-            libc::EACCES => {
-                // Record that we got a 'Permission denied' error.  If we end
-                // up finding no executable we can use, we want to diagnose
-                // that we did find one but were denied access.
-                got_eaccess = true;
-                continue;
-            },
-            // Those errors indicate the file is missing or not executable
-            // by us, in which case we want to just try the next path
-            // directory.
-            libc::ENOENT  => continue,
-            libc::ESTALE  => continue,
-            libc::ENOTDIR => continue,
-            // Some strange filesystems like AFS return even
-            // stranger error numbers.  They cannot reasonably mean
-            // anything else so ignore those, too.
-            libc::ENODEV    => continue,
-            libc::ETIMEDOUT => continue,
-    
-            _ => return err,
-        };
+        if let Err(err) = errors.record(err) {
+            return err;
+        }
     }
 
-    if got_eaccess {
-        SyscallError::new(libc::EACCES as u32)
-    } else {
-        SyscallError::new(libc::ENOENT as u32)
-    }
+    errors.into_error()
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -1062,40 +3963,17 @@ pub fn fexecvel(
 {
     let filename = candidate.get_filename().filename;
 
-    let mut got_eaccess = false;
+    let mut errors = SearchErrors::default();
 
     for pathfd in candidate.get_paths().iter() {
         let err = execveat(*pathfd, filename, argv, envp, ExecveAtFlags::NONE);
 
-        match err.get_errno() as i32 {
-            libc::EACCES => {
-                // Record that we got a 'Permission denied' error.  If we end
-                // up finding no executable we can use, we want to diagnose
-                // that we did find one but were denied access.
-                got_eaccess = true;
-                continue;
-            },
-            // Those errors indicate the file is missing or not executable
-            // by us, in which case we want to just try the next path
-            // directory.
-            libc::ENOENT  => continue,
-            libc::ESTALE  => continue,
-            libc::ENOTDIR => continue,
-            // Some strange filesystems like AFS return even
-            // stranger error numbers.  They cannot reasonably mean
-            // anything else so ignore those, too.
-            libc::ENODEV    => continue,
-            libc::ETIMEDOUT => continue,
-    
-            _ => return err,
-        };
+        if let Err(err) = errors.record(err) {
+            return err;
+        }
     }
 
-    if got_eaccess {
-        SyscallError::new(libc::EACCES as u32)
-    } else {
-        SyscallError::new(libc::ENOENT as u32)
-    }
+    errors.into_error()
 }
 
 #[cfg(test)]
@@ -1181,6 +4059,82 @@ mod tests {
         run_program(cstr!("env"), &argv2);
     }
 
+    #[test]
+    fn test_tgkill() {
+        assert_eq!(run(|| {
+            signal_ignore(Signal::SIGUSR1).unwrap();
+            tgkill(getpid(), gettid(), Signal::SIGUSR1).unwrap();
+        }), 0);
+
+        let err = tgkill(getpid(), pid_t::MAX, Signal::SIGUSR1).unwrap_err();
+        assert_eq!(err.get_errno(), libc::ESRCH);
+    }
+
+    #[test]
+    fn test_pidfd_getfd() {
+        let pidfd = pidfd_open(getpid()).unwrap();
+        let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        let fd = pidfd_getfd(&pidfd, write_end.get_fd()).unwrap();
+        assert_ne!(fd.get_fd(), write_end.get_fd());
+
+        fd.write_all(b"Hello").unwrap();
+        let mut buf = [0 as u8; 5];
+        assert_eq!(read_end.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"Hello");
+
+        let err = pidfd_getfd(&pidfd, c_int::MAX).unwrap_err();
+        assert_eq!(err.get_errno(), libc::EBADF);
+    }
+
+    #[test]
+    fn test_memfd_seals() {
+        use std::io::SeekFrom;
+
+        let flags = MemfdFlags::MFD_CLOEXEC | MemfdFlags::MFD_ALLOW_SEALING;
+        let memfd = FdBox::memfd_create(cstr!("test_memfd_seals"), flags).unwrap();
+
+        memfd.write_all(b"Hello").unwrap();
+        memfd.add_seals(SealFlags::F_SEAL_WRITE | SealFlags::F_SEAL_SHRINK).unwrap();
+
+        assert_eq!(memfd.lseek(SeekFrom::Start(0)).unwrap(), 0);
+
+        let mut buf = [0 as u8; 6];
+        assert_eq!(memfd.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"Hello");
+
+        assert_eq!(memfd.write(b"!").unwrap_err().get_errno(), libc::EPERM);
+    }
+
+    #[test]
+    fn test_gettid() {
+        let pid = getpid();
+        let tid = std::thread::spawn(gettid).join().unwrap();
+
+        assert_ne!(tid, pid);
+        assert_eq!(run(|| {
+            if gettid() != getpid() {
+                exit(1);
+            }
+        }), 0);
+    }
+
+    #[test]
+    fn test_execvel_resolve() {
+        let paths = ["/nonexistent", "/bin"];
+
+        let filename = Filename::new(cstr!("ls")).unwrap();
+        let candidate = ExecvelCandidate::new(filename, &paths).unwrap();
+        assert_eq!(candidate.resolve().unwrap().as_bytes(), b"/bin/ls");
+
+        let filename = Filename::new(cstr!("avfork-nonexistent")).unwrap();
+        let candidate = ExecvelCandidate::new(filename, &paths).unwrap();
+        assert_eq!(candidate.resolve().unwrap_err().get_errno(), libc::ENOENT);
+
+        let paths = ["/b\0in"];
+        assert!(ExecvelCandidate::new(filename, &paths).is_none());
+    }
+
     #[test]
     fn test_fexecvel() {
         let pathBoxs = [
@@ -1208,4 +4162,593 @@ mod tests {
         const argv2: &'static CStrArray = &CStrArray!("env");
         run_program(cstr!("env"), argv2);
     }
+
+    #[test]
+    fn test_dup() {
+        let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        let dupped = read_end.dup(true).unwrap();
+        drop(read_end);
+
+        assert_eq!(write_end.write(b"dup").unwrap(), 3);
+
+        let mut buf = [0 as u8; 3];
+        assert_eq!(dupped.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"dup");
+    }
+
+    #[test]
+    fn test_read_proc_status_field() {
+        let mut buf = [0 as u8; 32];
+
+        let cnt = read_proc_status_field(cstr!("Pid"), &mut buf).unwrap();
+        let pid: pid_t = std::str::from_utf8(&buf[..cnt]).unwrap().parse().unwrap();
+        assert_eq!(pid, getpid());
+
+        let result = read_proc_status_field(cstr!("NoSuchField"), &mut buf);
+        assert_matches!(result, Err(err) if err.get_errno() == libc::ENOENT);
+
+        let result = read_proc_status_field(cstr!("Pid"), &mut buf[..0]);
+        assert_matches!(result, Err(err) if err.get_errno() == libc::ERANGE);
+    }
+
+    #[test]
+    fn test_copy_all_to() {
+        let (src_read, src_write) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        let (dst_read, dst_write) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        src_write.write_all(b"Hello, world!").unwrap();
+        drop(src_write);
+
+        assert_eq!(src_read.copy_all_to(&dst_write).unwrap(), 13);
+
+        let mut buf = [0 as u8; 13];
+        assert_eq!(dst_read.read(&mut buf).unwrap(), 13);
+        assert_eq!(&buf, b"Hello, world!");
+    }
+
+    #[test]
+    fn test_tee() {
+        let (read_end1, write_end1) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        let (read_end2, write_end2) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        write_end1.write_all(b"data").unwrap();
+
+        assert_eq!(read_end1.tee(&write_end2, 4, SpliceFlags::empty()).unwrap(), 4);
+
+        let mut buf = [0 as u8; 4];
+        assert_eq!(read_end2.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"data");
+
+        // The data is not consumed from the first pipe
+        let mut buf = [0 as u8; 4];
+        assert_eq!(read_end1.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"data");
+    }
+
+    #[test]
+    fn test_getdents() {
+        let flags = FdFlags::O_DIRECTORY | FdFlags::O_CLOEXEC;
+        let dir = FdBox::openat(AT_FDCWD, cstr!("/proc/self/fd"), AccessMode::O_RDONLY, flags)
+            .unwrap();
+        assert!(dir.is_cloexec().unwrap());
+
+        let fd_name = dir.get_fd().to_string();
+
+        let mut buf = [0 as u8; 1024];
+        let mut found = false;
+        loop {
+            let cnt = dir.getdents(&mut buf).unwrap();
+            if cnt == 0 {
+                break;
+            }
+            for dirent in Dirents::new(&buf[..cnt]) {
+                if dirent.get_name().to_bytes() == fd_name.as_bytes() {
+                    found = true;
+                }
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn test_link_tmpfile() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let file = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        file.write_all(b"hello").unwrap();
+
+        let name = format!("avfork-test-link-tmpfile-{}", std::process::id());
+        let name = CString::new(name).unwrap();
+        file.link_tmpfile(*tmp, &name).unwrap();
+        assert_eq!(file.link_tmpfile(*tmp, &name).unwrap_err().get_errno(), libc::EEXIST);
+        drop(file);
+
+        let path = format!("/tmp/{}", name.to_str().unwrap());
+        let content = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_flock() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let fd1 = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        fd1.flock(FlockOp::Exclusive).unwrap();
+
+        // Shares the open file description with fd1
+        let fd2 = fd1.dup(true).unwrap();
+        fd2.flock(FlockOp::ExclusiveNonBlocking).unwrap();
+
+        // Opens a new open file description of the same file
+        let path = format!("/proc/self/fd/{}\0", fd1.get_fd());
+        let path = CStr::from_bytes_with_nul(path.as_bytes()).unwrap();
+        let fd3 = FdBox::openat(AT_FDCWD, path, AccessMode::O_RDWR, FdFlags::O_CLOEXEC)
+            .unwrap();
+
+        let err = fd3.flock(FlockOp::SharedNonBlocking).unwrap_err();
+        assert_eq!(err.get_errno(), libc::EWOULDBLOCK);
+
+        fd1.flock(FlockOp::Unlock).unwrap();
+        fd3.flock(FlockOp::SharedNonBlocking).unwrap();
+    }
+
+    #[test]
+    fn test_ofd_lock() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let fd1 = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        fd1.ofd_lock(0..10, LockType::Write, false).unwrap();
+
+        let path = format!("/proc/self/fd/{}\0", fd1.get_fd());
+        let path = CStr::from_bytes_with_nul(path.as_bytes()).unwrap();
+        let fd2 = FdBox::openat(AT_FDCWD, path, AccessMode::O_RDWR, FdFlags::O_CLOEXEC)
+            .unwrap();
+
+        let err = fd2.ofd_lock(5..15, LockType::Read, true).unwrap_err();
+        assert_eq!(err.get_errno(), libc::EAGAIN);
+        fd2.ofd_lock(10..u64::MAX, LockType::Write, true).unwrap();
+
+        fd1.ofd_unlock(5..10).unwrap();
+        fd2.ofd_lock(5..10, LockType::Read, true).unwrap();
+
+        assert_eq!(fd1.ofd_unlock(1..1).unwrap_err().get_errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_openat_nosymlink() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let name = format!("avfork-test-nosymlink-{}\0", std::process::id());
+        let name = CStr::from_bytes_with_nul(name.as_bytes()).unwrap();
+
+        let path = format!("/tmp/{}", name.to_str().unwrap());
+        std::os::unix::fs::symlink("/", &path).unwrap();
+
+        let flags = FdFlags::O_CLOEXEC;
+        let result = FdBox::openat_nosymlink(*tmp, name, AccessMode::O_RDONLY, flags);
+        std::fs::remove_file(&path).unwrap();
+        assert_matches!(result, Err(OpenError::SymlinkRejected));
+
+        let result = FdBox::openat_nosymlink(*tmp, name, AccessMode::O_RDONLY, flags);
+        assert_matches!(result, Err(OpenError::Syscall(err)) if err.get_errno() == libc::ENOENT);
+
+        let flags = FdFlags::O_DIRECTORY | FdFlags::O_CLOEXEC;
+        FdBox::openat_nosymlink(AT_FDCWD, cstr!("/tmp"), AccessMode::O_RDONLY, flags).unwrap();
+    }
+
+    #[test]
+    fn test_dev_null() {
+        let null_r = dev_null(false).unwrap();
+        let null_w = dev_null(true).unwrap();
+
+        // Cached
+        assert_eq!(dev_null(false).unwrap().get_fd(), null_r.get_fd());
+        assert_eq!(dev_null(true).unwrap().get_fd(), null_w.get_fd());
+        assert!(null_r.is_cloexec().unwrap());
+
+        let mut buf = [0 as u8; 1];
+        assert_eq!(null_r.read(&mut buf).unwrap(), 0);
+        assert_eq!(null_w.write(b"data").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_redirect_stdio() {
+        let (out_r, out_w) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        let (err_r, err_w) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        assert_eq!(run(|| {
+            let result = out_w.dup3(1, FdFlags::empty())
+                .and_then(|fd| {
+                    std::mem::forget(fd);
+                    err_w.dup3(2, FdFlags::empty())
+                })
+                .and_then(|fd| {
+                    std::mem::forget(fd);
+                    redirect_stdio(Some(&STDIN), Some(&STDERR), Some(&STDOUT))
+                })
+                .and_then(|_| STDOUT.write(b"1"))
+                .and_then(|_| STDERR.write(b"2"));
+
+            if result.is_err() {
+                exit(1);
+            }
+        }), 0);
+
+        drop(out_w);
+        drop(err_w);
+
+        let mut buf = [0 as u8; 2];
+        assert_eq!(out_r.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'2');
+        assert_eq!(err_r.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'1');
+    }
+
+    #[test]
+    fn test_timerslack() {
+        // Each test runs in its own thread, and timer slack is per-thread.
+        set_timerslack(1000).unwrap();
+        assert_eq!(get_timerslack().unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_fstat_type() {
+        let (read_end, _write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        assert_eq!(read_end.fstat_type().unwrap(), FileType::Fifo);
+
+        let flags = FdFlags::O_DIRECTORY | FdFlags::O_CLOEXEC;
+        let dir = FdBox::openat(AT_FDCWD, cstr!("/"), AccessMode::O_RDONLY, flags).unwrap();
+        assert_eq!(dir.fstat_type().unwrap(), FileType::Directory);
+
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let file = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        assert_eq!(file.fstat_type().unwrap(), FileType::Regular);
+    }
+
+    #[test]
+    fn test_readahead() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let file = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        assert_eq!(file.write(&[1; 4096]).unwrap(), 4096);
+        file.readahead(0, 4096).unwrap();
+
+        let (read_end, _write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        assert!(read_end.readahead(0, 4096).is_err());
+    }
+
+    #[test]
+    fn test_append_all() {
+        const LINE_SZ: usize = 64;
+        const LINES: usize = 1000;
+
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let flags = FdFlags::O_CLOEXEC | FdFlags::O_APPEND;
+        let mode = Mode::S_IRUSR | Mode::S_IWUSR;
+        let file = FdBox::creatat(*tmp, cstr!("."), true, flags, true, false, mode).unwrap();
+
+        let fd = *file;
+        let append = move |byte| {
+            let mut line = [byte; LINE_SZ];
+            line[LINE_SZ - 1] = b'\n';
+
+            for _ in 0..LINES {
+                if let Err(err) = fd.append_all(&line) {
+                    errx!(1, "append_all failed: {}", err);
+                }
+            }
+        };
+
+        let handle = std::thread::spawn(move || run(|| append(b'a')));
+        assert_eq!(run(|| append(b'b')), 0);
+        assert_eq!(handle.join().unwrap(), 0);
+
+        let mut content = Vec::new();
+        let mut reader = *file;
+        reader.lseek(std::io::SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut content).unwrap();
+
+        assert_eq!(content.len(), 2 * LINES * LINE_SZ);
+        for line in content.chunks(LINE_SZ) {
+            assert_eq!(line[LINE_SZ - 1], b'\n');
+            assert!(line[..LINE_SZ - 1].iter().all(|byte| *byte == line[0]));
+        }
+    }
+
+    #[test]
+    fn test_sync_file_range() {
+        let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+            .unwrap();
+        let file = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        assert_eq!(file.write(&[1; 4096]).unwrap(), 4096);
+
+        file.sync_file_range(0, 0, SyncRangeFlags::SYNC_FILE_RANGE_WRITE).unwrap();
+        file.sync_file_range(0, 4096, SyncRangeFlags::all()).unwrap();
+    }
+
+    #[test]
+    fn test_sched_getscheduler_reset_on_fork() {
+        assert_eq!(run(|| {
+            let param = libc::sched_param { sched_priority: 0 };
+            let policy = libc::SCHED_OTHER | libc::SCHED_RESET_ON_FORK;
+            if unsafe { libc::sched_setscheduler(0, policy, &param) } != 0 {
+                errx!(1, "sched_setscheduler failed");
+            }
+
+            match sched_getscheduler(0) {
+                Ok(SchedPolicy::SCHED_OTHER) => (),
+                Ok(_) => errx!(1, "Unexpected scheduler policy"),
+                Err(err) => errx!(1, "sched_getscheduler failed: {}", err),
+            }
+        }), 0);
+    }
+
+    #[test]
+    fn test_sched_deadline_infeasible() {
+        // runtime > deadline is rejected with EINVAL, or EPERM if the caller
+        // does not have CAP_SYS_NICE.
+        let policy = SchedPolicy::SCHED_DEADLINE {
+            runtime_ns: 20_000_000,
+            deadline_ns: 10_000_000,
+            period_ns: 30_000_000,
+        };
+        let errno = sched_setscheduler(0, &policy).unwrap_err().get_errno();
+        assert!(errno == libc::EINVAL || errno == libc::EPERM);
+    }
+
+    #[test]
+    fn test_winsize_not_tty() {
+        let (read_end, _write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        let errno = read_end.get_winsize().unwrap_err().get_errno();
+        assert_eq!(errno, libc::ENOTTY);
+
+        let winsize = WinSize { rows: 24, cols: 80, ..WinSize::default() };
+        let errno = read_end.set_winsize(&winsize).unwrap_err().get_errno();
+        assert_eq!(errno, libc::ENOTTY);
+    }
+
+    #[test]
+    fn test_tcgetpgrp_not_tty() {
+        let (read_end, _write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        let errno = tcgetpgrp(&read_end).unwrap_err().get_errno();
+        assert_eq!(errno, libc::ENOTTY);
+
+        let errno = tcsetpgrp(&read_end, getpid()).unwrap_err().get_errno();
+        assert_eq!(errno, libc::ENOTTY);
+    }
+
+    #[test]
+    fn test_statx() {
+        let stx = statx(AT_FDCWD, cstr!("/"), ExecveAtFlags::NONE, StatxMask::STATX_BASIC_STATS)
+            .unwrap();
+
+        let mode = stx.get_mode().unwrap() as u32;
+        assert_eq!(mode & libc::S_IFMT, libc::S_IFDIR);
+        assert!(stx.get_ino().is_some());
+    }
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = AlignedBuf::new(4096).unwrap();
+
+        assert_eq!(buf.len(), 4096);
+        assert_eq!(buf.as_ptr() as usize % get_pagesz(), 0);
+        assert!(buf.iter().all(|byte| *byte == 0));
+
+        buf[0] = 1;
+        assert_eq!(buf[0], 1);
+    }
+
+    #[test]
+    fn test_socketpair() {
+        let (sock1, sock2) = FdBox::socketpair(
+            SockDomain::AF_UNIX, SockType::SOCK_SEQPACKET, true, false
+        ).unwrap();
+
+        assert_eq!(sock1.send(b"ping", MsgFlags::MSG_NOSIGNAL).unwrap(), 4);
+
+        let mut buf = [0 as u8; 4];
+        assert_eq!(sock2.recv(&mut buf, MsgFlags::NONE).unwrap(), 4);
+        assert_eq!(&buf, b"ping");
+
+        drop(sock2);
+
+        let result = sock1.send(b"ping", MsgFlags::MSG_NOSIGNAL);
+        assert_matches!(result, Err(err) if err.get_errno() == libc::EPIPE);
+    }
+
+    #[test]
+    fn test_read_nonblocking() {
+        let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        read_end.set_nonblocking(true).unwrap();
+
+        let mut buf = [0 as u8; 4];
+        assert_matches!(read_end.read_nonblocking(&mut buf), Ok(None));
+
+        write_end.write_all(b"data").unwrap();
+        assert_matches!(read_end.read_nonblocking(&mut buf), Ok(Some(4)));
+
+        drop(write_end);
+        assert_matches!(read_end.read_nonblocking(&mut buf), Ok(Some(0)));
+    }
+
+    #[test]
+    fn test_faccessat2() {
+        let mode = AccessCheck::F_OK;
+        let flags = FaccessFlags::AT_EACCESS;
+
+        match faccessat2(AT_FDCWD, cstr!("/"), mode, flags) {
+            Ok(accessible) => assert!(accessible),
+            Err(err) => assert_eq!(err.get_errno(), libc::ENOSYS),
+        }
+
+        let result = faccessat2(AT_FDCWD, cstr!("/nonexistent-avfork"), mode, flags);
+        assert_matches!(
+            result,
+            Err(err) if err.get_errno() == libc::ENOENT || err.get_errno() == libc::ENOSYS
+        );
+    }
+
+    #[test]
+    fn test_sigtimedwait_timeout() {
+        let result = sigtimedwait(&sigemptyset(), Some(Duration::from_millis(1)));
+        assert_matches!(result, Err(err) if err.get_errno() == libc::EAGAIN);
+    }
+
+    #[test]
+    fn test_epoll_reactor() {
+        let reactor = EpollReactor::new().unwrap();
+        let (read_end, write_end) = FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+
+        reactor.add(&read_end, EpollEvents::EPOLLIN, 233).unwrap();
+
+        let mut events = [EpollEvent::default(); 4];
+        assert_eq!(reactor.wait(&mut events, Some(Duration::from_millis(1))).unwrap(), 0);
+
+        write_end.write_all(b"a").unwrap();
+        assert_eq!(reactor.wait(&mut events, None).unwrap(), 1);
+        assert_eq!(events[0].get_token(), 233);
+        assert!(events[0].get_events().contains(EpollEvents::EPOLLIN));
+
+        reactor.modify(&read_end, EpollEvents::EPOLLIN, 1).unwrap();
+        assert_eq!(reactor.wait(&mut events, None).unwrap(), 1);
+        assert_eq!(events[0].get_token(), 1);
+
+        reactor.del(&read_end).unwrap();
+        assert_eq!(reactor.wait(&mut events, Some(Duration::from_millis(1))).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_membarrier() {
+        let support = match membarrier_query() {
+            Ok(support) => support,
+            Err(err) if err.get_errno() == libc::ENOSYS => return,
+            Err(err) => panic!("{}", err),
+        };
+
+        if support.contains(MembarrierSupport::REGISTER_PRIVATE_EXPEDITED) {
+            membarrier(MembarrierCmd::RegisterPrivateExpedited).unwrap();
+            membarrier(MembarrierCmd::PrivateExpedited).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_wait_for_path() {
+        let interval = Duration::from_millis(1);
+
+        wait_for_path(AT_FDCWD, cstr!("/tmp"), Duration::from_millis(10), interval).unwrap();
+
+        let name = cstr!("/nonexistent-avfork");
+        let errno = wait_for_path(AT_FDCWD, name, Duration::from_millis(10), interval)
+            .unwrap_err()
+            .get_errno();
+        assert_eq!(errno, libc::ETIMEDOUT);
+
+        // Waiting forever does not overflow.
+        wait_for_path(AT_FDCWD, cstr!("/tmp"), Duration::MAX, interval).unwrap();
+
+        let errno = wait_for_path(AT_FDCWD, cstr!("/tmp"), Duration::MAX, Duration::ZERO)
+            .unwrap_err()
+            .get_errno();
+        assert_eq!(errno, libc::EINVAL);
+    }
+
+    /// The signal mask is changed, so it is run in a forked process to not
+    /// affect the harness.
+    #[test]
+    fn test_sigqueue() {
+        assert_eq!(run(|| {
+            let sig = Signal::new(libc::SIGRTMIN()).unwrap();
+
+            let mut set = sigemptyset();
+            sigaddset(&mut set, sig);
+            sigprocmask(SigprocmaskHow::SIG_BLOCK, Some(&set)).unwrap();
+
+            sigqueue(getpid(), sig, 42).unwrap();
+            sigqueue(getpid(), sig, 42).unwrap();
+
+            // Both instances of the real-time signal are queued.
+            for _ in 0..2 {
+                let info = sigtimedwait(&set, Some(Duration::ZERO)).unwrap();
+                assert_eq!(info.get_signo(), sig.get_signo());
+                assert_eq!(info.get_code(), libc::SI_QUEUE);
+                assert_eq!(info.get_value(), 42);
+                assert_eq!(info.get_pid(), getpid());
+            }
+
+            let errno = sigtimedwait(&set, Some(Duration::ZERO)).unwrap_err().get_errno();
+            assert_eq!(errno, libc::EAGAIN);
+        }), 0);
+    }
+
+    #[test]
+    fn test_signal_ignore() {
+        let errno = signal_ignore(Signal::SIGKILL).unwrap_err().get_errno();
+        assert_eq!(errno, libc::EINVAL);
+
+        let errno = signal_default(Signal::SIGSTOP).unwrap_err().get_errno();
+        assert_eq!(errno, libc::EINVAL);
+    }
+
+    /// SIGXFSZ is ignored for the whole process and its children, so it is run
+    /// in a forked process to not affect the harness and other tests.
+    #[test]
+    fn test_write_all_catch_fsize() {
+        assert_eq!(run(|| {
+            let mut limit = prlimit(PrlimitResource::RLIMIT_FSIZE, None).unwrap();
+            limit.rlim_cur = 1024;
+            prlimit(PrlimitResource::RLIMIT_FSIZE, Some(&limit)).unwrap();
+
+            let tmp = FdPathBox::openat(AT_FDCWD, cstr!("/tmp"), FdPathMode::directory, true)
+                .unwrap();
+            let file = FdBox::tmpfile(*tmp, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+
+            let errno = file.write_all_catch_fsize(&[1; 4096]).unwrap_err().get_errno();
+            assert_eq!(errno, libc::EFBIG);
+
+            let mut buf = [0 as u8; 4096];
+            file.lseek(std::io::SeekFrom::Start(0)).unwrap();
+            assert_eq!(file.read(&mut buf).unwrap(), 1024);
+            assert!(buf[..1024].iter().all(|byte| *byte == 1));
+        }), 0);
+    }
+
+    #[test]
+    fn test_getrandom() {
+        let mut buf = [0 as u8; 32];
+        assert_eq!(getrandom(&mut buf, GetrandomFlags::empty()).unwrap(), buf.len());
+        assert!(buf.iter().any(|byte| *byte != 0));
+    }
+
+    #[test]
+    fn test_mlockall() {
+        let errno = mlockall(MlockallFlags::empty()).unwrap_err().get_errno();
+        assert_eq!(errno, libc::EINVAL);
+
+        munlockall().unwrap();
+    }
+
+    #[test]
+    fn test_sigset() {
+        let mut set = sigemptyset();
+        assert!(!sigismember(&set, Signal::SIGTERM));
+
+        sigaddset(&mut set, Signal::SIGTERM);
+        assert!(sigismember(&set, Signal::SIGTERM));
+        assert!(!sigismember(&set, Signal::SIGINT));
+
+        sigdelset(&mut set, Signal::SIGTERM);
+        assert!(!sigismember(&set, Signal::SIGTERM));
+
+        let mut set = sigfillset();
+        sigdelset(&mut set, Signal::SIGINT);
+        assert!(!sigismember(&set, Signal::SIGINT));
+        assert!(sigismember(&set, Signal::SIGTERM));
+    }
 }
@@ -78,12 +78,23 @@ macro_rules! expect {
 pub mod tests {
     use crate::utility::*;
     use crate::syscall;
+    use crate::syscall::{FdBox, FdFlags, AccessMode, Dirents, AT_FDCWD};
 
+    use std::collections::BTreeSet;
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// Run `f` in a forked process and return its exit status, which is 0
+    /// if `f` returns and 1 if it panics.
+    ///
+    /// Panics if the forked process is killed by a signal.
     pub fn run<F: FnOnce()>(f: F) -> c_int {
         let pid = unsafe { libc::fork() };
 
         if pid == 0 {
-            f();
+            // Never unwind into the test harness of the forked process.
+            if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+                syscall::exit(1);
+            }
 
             syscall::exit(0);
         } else {
@@ -93,10 +104,101 @@ pub mod tests {
                 assert_eq!(pid, libc::waitpid(pid, &mut status as *mut _, 0));
             };
 
+            assert!(
+                libc::WIFEXITED(status),
+                "forked process is killed by signal {}", libc::WTERMSIG(status)
+            );
             libc::WEXITSTATUS(status)
         }
     }
 
+    /// Returns the fds currently open, excluding the fd of `/proc/self/fd`
+    /// opened to list them.
+    pub fn open_fds() -> BTreeSet<c_int> {
+        let flags = FdFlags::O_DIRECTORY | FdFlags::O_CLOEXEC;
+        let dir = FdBox::openat(AT_FDCWD, cstr!("/proc/self/fd"), AccessMode::O_RDONLY, flags)
+            .unwrap();
+
+        let mut fds = BTreeSet::new();
+        let mut buf = [0 as u8; 1024];
+        loop {
+            let cnt = dir.getdents(&mut buf).unwrap();
+            if cnt == 0 {
+                break;
+            }
+
+            for dirent in Dirents::new(&buf[..cnt]) {
+                let name = dirent.get_name().to_str().unwrap();
+                if let Ok(fd) = name.parse::<c_int>() {
+                    fds.insert(fd);
+                }
+            }
+        }
+
+        fds.remove(&dir.get_fd());
+        fds
+    }
+
+    /// Assert that `f` does not leave any fd open.
+    ///
+    /// Same as `assert_no_fd_leak_except`, `f` is run in a forked process.
+    pub fn assert_no_fd_leak<F: FnOnce()>(f: F) {
+        assert_no_fd_leak_except(f, |_| Vec::new())
+    }
+
+    /// Assert that `f` does not leave any fd open, except for those owned
+    /// by its return value, e.g. pipe ends of a `Child`, which are returned
+    /// by `returned_fds`.
+    ///
+    /// Since other tests running concurrently also open fds, `f` is run in a
+    /// forked process, which only has the calling thread.
+    /// Fds opened on first use and cached for the rest of the process, e.g.
+    /// `dev_null`, must be opened before calling this function.
+    pub fn assert_no_fd_leak_except<T, F, G>(f: F, returned_fds: G)
+        where F: FnOnce() -> T, G: FnOnce(&T) -> Vec<c_int>
+    {
+        assert_eq!(run(|| check_no_fd_leak(f, returned_fds)), 0);
+    }
+
+    /// Exit with 1 if `f` panics or leaves any fd open except for
+    /// `returned_fds`.
+    fn check_no_fd_leak<T, F, G>(f: F, returned_fds: G)
+        where F: FnOnce() -> T, G: FnOnce(&T) -> Vec<c_int>
+    {
+        let before = open_fds();
+        let val = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(val) => val,
+            Err(_) => crate::errx!(1, "f panicked"),
+        };
+        let mut after = open_fds();
+
+        for fd in returned_fds(&val) {
+            after.remove(&fd);
+        }
+
+        let leaked: Vec<_> = after.difference(&before).collect();
+        if !leaked.is_empty() {
+            crate::errx!(1, "fds {:?} are leaked", leaked);
+        }
+    }
+
+    #[test]
+    fn test_assert_no_fd_leak() {
+        assert_no_fd_leak(|| {
+            FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap();
+        });
+
+        assert_no_fd_leak_except(
+            || FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap(),
+            |(read_end, write_end)| vec![read_end.get_fd(), write_end.get_fd()]
+        );
+
+        let leak = || std::mem::forget(FdBox::pipe2(FdFlags::O_CLOEXEC).unwrap());
+        assert_eq!(run(|| check_no_fd_leak(leak, |_| Vec::new())), 1);
+
+        assert_eq!(run(|| check_no_fd_leak(|| panic!("Expected panic"), |_| Vec::new())), 1);
+    }
+
     #[test]
     fn test_errx() {
         assert_eq!(run(|| crate::errx!(0, "Hello, world from test_errx!")), 0);